@@ -0,0 +1,114 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    connectors::ConnectorError,
+    db::{ImportMode, LibraryBackup},
+    prefs::PrefData,
+    updates::now_millis,
+};
+
+/// Bumped whenever the layout of `Backup` changes in a way older versions
+/// can't read by ignoring the new fields.
+const FORMAT_VERSION: u32 = 1;
+const FILE_NAME: &str = "backup.json";
+
+/// Upgrades the raw JSON of a backup at version `i + 1` to `i + 2`. Only ever
+/// append to this list.
+const MIGRATIONS: &[fn(&mut Value)] = &[];
+
+/// A library backup, stored as JSON in a zip archive so it stays small and
+/// can still be inspected by hand.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    /// Milliseconds since the epoch.
+    pub created_at: u64,
+    #[serde(default)]
+    pub library: LibraryBackup,
+    /// Kept as JSON so the settings' own migrations can run on import.
+    #[serde(default)]
+    pub settings: Value,
+}
+
+impl Backup {
+    pub fn new(library: LibraryBackup, settings: &PrefData) -> serde_json::Result<Self> {
+        Ok(Backup {
+            version: FORMAT_VERSION,
+            created_at: now_millis(),
+            library,
+            settings: serde_json::to_value(settings)?,
+        })
+    }
+
+    pub fn settings(&self) -> serde_json::Result<PrefData> {
+        PrefData::from_value(self.settings.clone())
+    }
+}
+
+pub fn write(path: &Path, backup: &Backup) -> Result<(), ConnectorError> {
+    let part = path.with_extension("part");
+    let mut zip = ZipWriter::new(File::create(&part)?);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(FILE_NAME, deflated)?;
+    serde_json::to_writer(&mut zip, backup)?;
+    zip.finish()?.flush()?;
+    Ok(fs::rename(part, path)?)
+}
+
+/// Reads a backup made by any version. Ones from newer versions are read as
+/// far as this version understands them.
+pub fn read(path: &Path) -> Result<Backup, ConnectorError> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let mut json = String::new();
+    zip.by_name(FILE_NAME)?.read_to_string(&mut json)?;
+    let mut data: Value = serde_json::from_str(&json)?;
+
+    let version = data.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+    if version == 0 {
+        return Err(ConnectorError::parse("not a Bunni backup"));
+    }
+    if version > FORMAT_VERSION {
+        println!("backup is from a newer version ({version}), reading what's understood");
+    }
+    for migrate in MIGRATIONS.iter().skip(version as usize - 1) {
+        migrate(&mut data);
+    }
+    Ok(serde_json::from_value(data)?)
+}
+
+/// Applies the settings from a backup. The local library is machine
+/// specific, so it's only taken over if that folder exists here.
+pub fn restore_settings(current: &mut PrefData, backup: PrefData, mode: ImportMode) {
+    let local_library = backup.local_library.filter(|path| path.is_dir());
+    match mode {
+        ImportMode::Replace => {
+            if local_library.is_some() {
+                current.local_library = local_library;
+            }
+            current.languages = backup.languages;
+            current.mangadex_follow = backup.mangadex_follow;
+            current.manga_cache_ttl = backup.manga_cache_ttl;
+            current.image_cache_size = backup.image_cache_size;
+            current.connector_settings = backup.connector_settings;
+        }
+        ImportMode::Merge => {
+            if current.local_library.is_none() {
+                current.local_library = local_library;
+            }
+            if current.languages.is_empty() {
+                current.languages = backup.languages;
+            }
+            for (id, settings) in backup.connector_settings {
+                current.connector_settings.entry(id).or_insert(settings);
+            }
+        }
+    }
+}
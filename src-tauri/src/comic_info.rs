@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+use crate::connectors::{Chapter, Format, SearchItem};
+
+pub const FILE_NAME: &str = "ComicInfo.xml";
+
+/// The subset of the ComicRack `ComicInfo.xml` schema that Bunni reads and
+/// writes. Komga, Tachiyomi and YACReader all understand these fields.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename = "ComicInfo", rename_all = "PascalCase")]
+pub struct ComicInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_count: Option<u32>,
+    #[serde(rename = "LanguageISO", skip_serializing_if = "Option::is_none")]
+    pub language_iso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manga: Option<String>,
+}
+
+impl ComicInfo {
+    pub fn new(manga: &SearchItem, format: Format) -> Self {
+        ComicInfo {
+            series: Some(manga.title.clone()),
+            summary: Some(manga.description.clone()).filter(|d| !d.is_empty()),
+            manga: Some(
+                match format {
+                    Format::Normal => "YesAndRightToLeft",
+                    Format::Long => "Yes",
+                }
+                .to_string(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    pub fn chapter(manga: &SearchItem, chapter: &Chapter, format: Format) -> Self {
+        ComicInfo {
+            title: Some(chapter.name.clone()),
+            number: Some(chapter.number.to_string()),
+            volume: chapter.volume.clone(),
+            language_iso: chapter.language.clone(),
+            ..Self::new(manga, format)
+        }
+    }
+
+    pub fn to_xml(&self) -> Result<String, quick_xml::DeError> {
+        Ok(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n{}",
+            quick_xml::se::to_string(self)?
+        ))
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Self, quick_xml::DeError> {
+        quick_xml::de::from_str(xml)
+    }
+}
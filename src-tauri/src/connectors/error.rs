@@ -0,0 +1,149 @@
+use std::{
+    fmt::Display,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{header::HeaderMap, Response, StatusCode};
+use serde::Serialize;
+use specta::Type;
+
+#[derive(Debug, Serialize, Type, Clone)]
+pub enum ConnectorError {
+    /// The request never got a response.
+    Network(String),
+    /// The server answered with an unexpected status code.
+    Status {
+        status: u16,
+        url: String,
+    },
+    /// The response didn't look like we expected, usually because the site
+    /// changed its layout or API.
+    Parse(String),
+    NotFound(String),
+    /// Seconds to wait before trying again, when the server says.
+    RateLimited {
+        retry_after: Option<u32>,
+    },
+    /// The connector doesn't support this operation.
+    Unsupported(String),
+    Io(String),
+    /// Reading or writing the library database failed.
+    Database(String),
+    /// Logging in failed, or the request needs an account that isn't
+    /// logged in.
+    Auth(String),
+    /// A value from the frontend was rejected, like a setting of the wrong
+    /// kind.
+    Invalid(String),
+}
+
+impl Display for ConnectorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectorError::Network(e) => write!(f, "network error: {e}"),
+            ConnectorError::Status { status, url } => write!(f, "{url} returned {status}"),
+            ConnectorError::Parse(e) => write!(f, "unexpected response: {e}"),
+            ConnectorError::NotFound(what) => write!(f, "{what} not found"),
+            ConnectorError::RateLimited {
+                retry_after: Some(secs),
+            } => write!(f, "rate limited, retry in {secs}s"),
+            ConnectorError::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            ConnectorError::Unsupported(what) => write!(f, "{what} is not supported"),
+            ConnectorError::Io(e) => write!(f, "io error: {e}"),
+            ConnectorError::Database(e) => write!(f, "database error: {e}"),
+            ConnectorError::Auth(e) => write!(f, "authentication failed: {e}"),
+            ConnectorError::Invalid(what) => write!(f, "invalid {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectorError {}
+
+impl ConnectorError {
+    pub fn parse(what: impl Display) -> Self {
+        ConnectorError::Parse(what.to_string())
+    }
+}
+
+impl From<reqwest::Error> for ConnectorError {
+    fn from(value: reqwest::Error) -> Self {
+        match value.status() {
+            Some(StatusCode::TOO_MANY_REQUESTS) => {
+                ConnectorError::RateLimited { retry_after: None }
+            }
+            Some(StatusCode::NOT_FOUND) => {
+                ConnectorError::NotFound(value.url().map(|u| u.to_string()).unwrap_or_default())
+            }
+            Some(status) => ConnectorError::Status {
+                status: status.as_u16(),
+                url: value.url().map(|u| u.to_string()).unwrap_or_default(),
+            },
+            None if value.is_decode() => ConnectorError::Parse(value.to_string()),
+            None => ConnectorError::Network(value.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectorError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::NotFound => ConnectorError::NotFound(value.to_string()),
+            io::ErrorKind::InvalidData => ConnectorError::Parse(value.to_string()),
+            _ => ConnectorError::Io(value.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for ConnectorError {
+    fn from(value: rusqlite::Error) -> Self {
+        ConnectorError::Database(value.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ConnectorError {
+    fn from(value: serde_json::Error) -> Self {
+        match value.io_error_kind() {
+            Some(_) => ConnectorError::Io(value.to_string()),
+            None => ConnectorError::Parse(value.to_string()),
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for ConnectorError {
+    fn from(value: zip::result::ZipError) -> Self {
+        match value {
+            zip::result::ZipError::Io(e) => e.into(),
+            e => ConnectorError::Parse(e.to_string()),
+        }
+    }
+}
+
+/// Seconds until the server is willing to take requests again. Accepts
+/// `Retry-After` in seconds, and MangaDex's `X-RateLimit-Retry-After`,
+/// which is a unix timestamp.
+pub(super) fn retry_after(headers: &HeaderMap) -> Option<u32> {
+    let header = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+    if let Some(secs) = header("retry-after") {
+        return Some(secs as u32);
+    }
+    let at = header("x-ratelimit-retry-after")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(at.saturating_sub(now) as u32)
+}
+
+/// Turns non-success responses into the matching error, keeping the
+/// rate limit headers that `error_for_status` would throw away.
+pub fn check_status(res: Response) -> Result<Response, ConnectorError> {
+    match res.status() {
+        status if status.is_success() => Ok(res),
+        StatusCode::TOO_MANY_REQUESTS => Err(ConnectorError::RateLimited {
+            retry_after: retry_after(res.headers()),
+        }),
+        StatusCode::NOT_FOUND => Err(ConnectorError::NotFound(res.url().to_string())),
+        status => Err(ConnectorError::Status {
+            status: status.as_u16(),
+            url: res.url().to_string(),
+        }),
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy, PartialEq, Eq)]
+pub enum PublicationStatus {
+    Ongoing,
+    Completed,
+    Hiatus,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy, PartialEq, Eq)]
+pub enum Demographic {
+    Shounen,
+    Shoujo,
+    Seinen,
+    Josei,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy, PartialEq, Eq)]
+pub enum ContentRating {
+    Safe,
+    Suggestive,
+    Erotica,
+    Pornographic,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Relevance,
+    LatestUpload,
+    Popular,
+    Rating,
+    Title,
+    NewlyAdded,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct Tag {
+    /// Whatever the connector uses to identify the tag, not meant for display.
+    pub id: String,
+    pub name: String,
+    /// "genre", "theme", "format"... when the source groups its tags.
+    pub group: Option<String>,
+}
+
+/// Narrows down a search. Empty lists mean "don't filter on this". Filters a
+/// connector doesn't advertise in its `FilterCapabilities` are ignored.
+#[derive(Serialize, Deserialize, Type, Clone, Default)]
+#[serde(default)]
+pub struct SearchFilters {
+    /// Tag IDs from `FilterCapabilities::tags`.
+    pub tags: Vec<String>,
+    pub status: Vec<PublicationStatus>,
+    pub demographic: Vec<Demographic>,
+    pub content_rating: Vec<ContentRating>,
+    /// ISO 639-1 codes of languages that need to have a translation.
+    pub languages: Vec<String>,
+    pub sort: Option<SortOrder>,
+}
+
+/// The filters a connector understands, for building the filter UI.
+#[derive(Serialize, Deserialize, Type, Clone, Default)]
+pub struct FilterCapabilities {
+    pub tags: Vec<Tag>,
+    /// How many tags can be combined, `None` for no limit.
+    pub max_tags: Option<u32>,
+    pub status: Vec<PublicationStatus>,
+    pub demographic: Vec<Demographic>,
+    pub content_rating: Vec<ContentRating>,
+    pub languages: Vec<String>,
+    pub sort: Vec<SortOrder>,
+    /// Whether filters still apply when there's a search query. Some sites
+    /// only filter their browse listing.
+    pub filters_with_query: bool,
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::{IntoUrl, RequestBuilder, Response, StatusCode};
+use tokio::time::sleep;
+
+use super::error::retry_after;
+
+const USER_AGENT: &str = "Bunni/0.0.1";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// For the whole request, body included, so it has to fit a large page.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Attempts after the first one fails with 429 or 5xx.
+const MAX_RETRIES: u32 = 3;
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+/// Servers asking to wait longer than this get their error passed on
+/// instead of a request that seems to hang.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// Requests per second and burst for hosts without a limit of their own.
+const DEFAULT_RATE: f64 = 10.0;
+const DEFAULT_BURST: f64 = 10.0;
+
+/// A token bucket. Tokens can go negative, which is how many requests are
+/// already waiting for one.
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Bucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    /// Changes the rate and burst, keeping the requests already waiting.
+    fn set_limit(&mut self, rate: f64, burst: f64) {
+        self.refill();
+        self.rate = rate;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    /// Takes a token, returning how long to wait before it's really there.
+    fn take(&mut self) -> Duration {
+        self.refill();
+        self.tokens -= 1.0;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Makes requests wait at least `wait` from now.
+    fn pause(&mut self, wait: Duration) {
+        self.refill();
+        self.tokens = self.tokens.min(-wait.as_secs_f64() * self.rate);
+    }
+}
+
+/// The HTTP client every connector, tracker and account shares. Requests
+/// take turns per host, time out, and are retried with exponential backoff
+/// on 429 and 5xx, waiting as long as `Retry-After` asks.
+///
+/// Build requests with `get` and friends and hand them to `send`, as
+/// sending them directly skips all of that.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        HttpClient {
+            client: reqwest::Client::builder()
+                .user_agent(USER_AGENT)
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(TIMEOUT)
+                .build()
+                .expect("failed to build HTTP client"),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Limits `host` to `rate` requests per second, with bursts of up to
+    /// `burst`. Setting it again keeps any wait a 429 asked for.
+    pub fn limit(&self, host: &str, rate: f64, burst: u32) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .entry(host.to_string())
+            .and_modify(|bucket| bucket.set_limit(rate, burst as f64))
+            .or_insert_with(|| Bucket::new(rate, burst as f64));
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn patch(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.patch(url)
+    }
+
+    pub fn delete(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.delete(url)
+    }
+
+    fn bucket<T>(&self, host: &str, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(host.to_string())
+            .or_insert_with(|| Bucket::new(DEFAULT_RATE, DEFAULT_BURST));
+        f(bucket)
+    }
+
+    /// Sends `request` once its host has a token. The response of the last
+    /// attempt is returned whatever its status, for `check_status`.
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut request = request.build()?;
+        let host = request.url().host_str().unwrap_or_default().to_string();
+        let mut attempt = 0;
+        loop {
+            // only streamed bodies can't be cloned, and nothing sends those
+            let retry = request.try_clone().filter(|_| attempt < MAX_RETRIES);
+            sleep(self.bucket(&host, Bucket::take)).await;
+            let res = self.client.execute(request).await?;
+
+            let status = res.status();
+            let retry = match retry {
+                Some(retry) if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() => {
+                    retry
+                }
+                _ => return Ok(res),
+            };
+            let wait = retry_after(res.headers())
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or_else(|| FIRST_BACKOFF * 2u32.pow(attempt));
+            if wait > MAX_WAIT {
+                return Ok(res);
+            }
+            println!("{} returned {status}, retrying in {wait:?}", res.url());
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                // everything else going to the host has to wait too
+                self.bucket(&host, |bucket| bucket.pause(wait));
+            } else {
+                sleep(wait).await;
+            }
+            request = retry;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compares waits, allowing for the time the test itself takes.
+    fn assert_wait(wait: Duration, secs: f64) {
+        assert!((wait.as_secs_f64() - secs).abs() < 0.05, "waited {wait:?}, not {secs}s");
+    }
+
+    #[test]
+    fn take_waits_once_the_burst_is_used() {
+        let mut bucket = Bucket::new(2.0, 2.0);
+        assert_eq!(bucket.take(), Duration::ZERO);
+        assert_eq!(bucket.take(), Duration::ZERO);
+        assert_wait(bucket.take(), 0.5);
+        assert_wait(bucket.take(), 1.0);
+
+        // a second later the two waiting requests went out
+        bucket.updated -= Duration::from_secs(1);
+        assert_wait(bucket.take(), 0.5);
+
+        // refilling stops at the burst
+        bucket.updated -= Duration::from_secs(60);
+        assert_eq!(bucket.take(), Duration::ZERO);
+        assert_eq!(bucket.take(), Duration::ZERO);
+        assert_wait(bucket.take(), 0.5);
+    }
+
+    #[test]
+    fn pause_holds_requests_back() {
+        let mut bucket = Bucket::new(2.0, 2.0);
+        bucket.pause(Duration::from_secs(3));
+        assert_wait(bucket.take(), 3.5);
+
+        // a shorter pause doesn't cut the wait
+        bucket.pause(Duration::from_secs(1));
+        assert_wait(bucket.take(), 4.0);
+    }
+
+    #[test]
+    fn limit_keeps_pause() {
+        let client = HttpClient::new();
+        client.limit("example.com", 2.0, 2);
+        client.bucket("example.com", |bucket| bucket.pause(Duration::from_secs(3)));
+        client.limit("example.com", 4.0, 4);
+        assert_wait(client.bucket("example.com", Bucket::take), 1.75);
+    }
+}
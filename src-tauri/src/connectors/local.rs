@@ -0,0 +1,568 @@
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{self, Read},
+    iter::Peekable,
+    path::{Component, Path, PathBuf},
+    str::Chars,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use zip::ZipArchive;
+
+use super::{
+    Chapter, ChapterImages, Connector, ConnectorError, Format, Image, Manga, Page, SearchFilters,
+    SearchItem,
+};
+use crate::{
+    comic_info::{self, ComicInfo},
+    downloads::content_type,
+    protocol,
+};
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "gif", "avif"];
+const ARCHIVE_EXTENSIONS: [&str; 2] = ["cbz", "zip"];
+// RAR archives, which there's no reader for
+const UNSUPPORTED_EXTENSIONS: [&str; 2] = ["cbr", "rar"];
+
+// IDs end up in frontend routes, so they can't contain '/'. Path parts are
+// joined with a pipe, escaped like in URLs where a name has one.
+const ID_SEPARATOR: &str = "|";
+
+const PAGE_SIZE: usize = 50;
+
+/// The folder the local connector reads from. Shared with the settings
+/// commands so it can be changed without restarting.
+#[derive(Clone)]
+pub struct LocalLibrary(Arc<RwLock<PathBuf>>);
+
+impl LocalLibrary {
+    pub fn new(root: PathBuf) -> Self {
+        Self(Arc::new(RwLock::new(root)))
+    }
+
+    pub fn get(&self) -> PathBuf {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, root: PathBuf) {
+        *self.0.write().unwrap() = root;
+    }
+}
+
+/// Reads manga that are already on disk. Every folder or archive at the top
+/// of the library is a series, and its chapters are either subfolders of
+/// images or CBZ/ZIP archives. IDs are paths relative to the library root.
+pub struct LocalConnector {
+    library: LocalLibrary,
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| extensions.contains(&e.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn is_image(path: &Path) -> bool {
+    has_extension(path, &IMAGE_EXTENSIONS)
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.is_file() && has_extension(path, &ARCHIVE_EXTENSIONS)
+}
+
+/// Whether `path` is an archive that can't be read.
+fn is_unsupported(path: &Path) -> bool {
+    path.is_file() && has_extension(path, &UNSUPPORTED_EXTENSIONS)
+}
+
+/// Drops the archives that can't be read from a scan, logging them so a
+/// series that's missing chapters isn't a mystery.
+fn skip_unsupported(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let (unsupported, paths): (Vec<_>, Vec<_>) = paths.into_iter().partition(|p| is_unsupported(p));
+    for path in unsupported {
+        println!("skipping {}: RAR archives are not supported", path.display());
+    }
+    paths
+}
+
+fn escape_id_part(part: &str) -> String {
+    part.replace('%', "%25").replace(ID_SEPARATOR, "%7C")
+}
+
+fn unescape_id_part(part: &str) -> String {
+    part.replace("%7C", ID_SEPARATOR).replace("%25", "%")
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> u64 {
+    let mut n = 0u64;
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        n = n.saturating_mul(10).saturating_add(d as u64);
+        chars.next();
+    }
+    n
+}
+
+/// Orders names the way a person would, so "2" comes before "10".
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, _) => return Ordering::Less,
+            (_, None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                match take_number(&mut a).cmp(&take_number(&mut b)) {
+                    Ordering::Equal => continue,
+                    ord => return ord,
+                }
+            }
+            (Some(x), Some(y)) => {
+                match x.to_lowercase().cmp(y.to_lowercase()) {
+                    Ordering::Equal => (),
+                    ord => return ord,
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn stem(path: &Path) -> String {
+    match path.is_dir() {
+        true => file_name(path),
+        false => path
+            .file_stem()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| !file_name(p).starts_with('.'))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| natural_cmp(&file_name(a), &file_name(b)));
+    Ok(entries)
+}
+
+/// First number in a chapter name, e.g. 12.5 for "Chapter 012.5 - Title".
+fn parse_number(name: &str) -> Option<f32> {
+    let start = name.find(|c: char| c.is_ascii_digit())?;
+    let rest = &name[start..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    rest[..end].trim_end_matches('.').parse().ok()
+}
+
+fn read_archive(path: &Path) -> io::Result<ZipArchive<File>> {
+    ZipArchive::new(File::open(path)?).map_err(io::Error::from)
+}
+
+fn archive_pages(archive: &mut ZipArchive<File>) -> Vec<String> {
+    let mut names = archive
+        .file_names()
+        .filter(|n| is_image(Path::new(n)))
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>();
+    names.sort_by(|a, b| natural_cmp(a, b));
+    names
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> io::Result<Vec<u8>> {
+    let mut entry = archive.by_name(name)?;
+    let mut bytes = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads the page at `index` of a chapter folder or archive.
+fn read_page(chapter: &Path, index: usize) -> io::Result<Image> {
+    let missing = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no page {index} in {}", chapter.display()),
+        )
+    };
+    if is_archive(chapter) {
+        let mut archive = read_archive(chapter)?;
+        let name = archive_pages(&mut archive)
+            .into_iter()
+            .nth(index)
+            .ok_or_else(missing)?;
+        return Ok(Image {
+            content_type: content_type(Path::new(&name)).to_string(),
+            bytes: read_entry(&mut archive, &name)?,
+        });
+    }
+
+    let path = page_paths(chapter)?.into_iter().nth(index).ok_or_else(missing)?;
+    Ok(Image {
+        content_type: content_type(&path).to_string(),
+        bytes: fs::read(path)?,
+    })
+}
+
+fn page_count(chapter: &Path) -> io::Result<usize> {
+    if is_archive(chapter) {
+        return Ok(archive_pages(&mut read_archive(chapter)?).len());
+    }
+    Ok(page_paths(chapter)?.len())
+}
+
+fn page_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(sorted_entries(dir)?
+        .into_iter()
+        .filter(|p| p.is_file() && is_image(p))
+        .collect())
+}
+
+fn read_comic_info(path: &Path) -> Option<ComicInfo> {
+    let xml = if is_archive(path) {
+        let mut archive = read_archive(path).ok()?;
+        String::from_utf8(read_entry(&mut archive, comic_info::FILE_NAME).ok()?).ok()?
+    } else {
+        fs::read_to_string(path.join(comic_info::FILE_NAME)).ok()?
+    };
+    ComicInfo::from_xml(&xml).ok()
+}
+
+fn has_images(dir: &Path) -> bool {
+    sorted_entries(dir)
+        .map(|entries| entries.iter().any(|p| p.is_file() && is_image(p)))
+        .unwrap_or(false)
+}
+
+/// Chapters of a series: its subfolders and archives, or the series itself
+/// when it's a single archive or a folder of loose images.
+fn chapter_paths(series: &Path) -> io::Result<Vec<PathBuf>> {
+    if is_archive(series) {
+        return Ok(vec![series.to_path_buf()]);
+    }
+    let chapters = skip_unsupported(sorted_entries(series)?)
+        .into_iter()
+        .filter(|p| is_archive(p) || (p.is_dir() && has_images(p)))
+        .collect::<Vec<_>>();
+    if chapters.is_empty() && has_images(series) {
+        return Ok(vec![series.to_path_buf()]);
+    }
+    Ok(chapters)
+}
+
+impl LocalConnector {
+    pub fn new(library: LocalLibrary) -> Self {
+        LocalConnector { library }
+    }
+
+    /// Turns an ID back into a path, refusing anything that would escape
+    /// the library folder.
+    fn resolve(&self, id: &str) -> io::Result<PathBuf> {
+        let mut path = self.library.get();
+        for part in id.split(ID_SEPARATOR) {
+            let part = unescape_id_part(part);
+            let mut components = Path::new(&part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(c)), None) => path.push(c),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid id {id}"),
+                    ))
+                }
+            }
+        }
+        match path.exists() {
+            true => Ok(path),
+            false => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{id} not found"),
+            )),
+        }
+    }
+
+    fn id(&self, path: &Path) -> String {
+        path.strip_prefix(self.library.get())
+            .unwrap_or(path)
+            .components()
+            .map(|c| escape_id_part(&c.as_os_str().to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(ID_SEPARATOR)
+    }
+
+    fn series_paths(&self) -> io::Result<Vec<PathBuf>> {
+        Ok(skip_unsupported(sorted_entries(&self.library.get())?)
+            .into_iter()
+            .filter(|p| is_archive(p) || p.is_dir())
+            .collect())
+    }
+
+    /// A `bunni://` URL for the series' cover image, or the first page when
+    /// there's none. Both are only read once the webview asks.
+    fn cover(&self, series: &Path, chapters: &[PathBuf]) -> String {
+        let cover = sorted_entries(series)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|p| p.is_file() && is_image(p) && stem(p).eq_ignore_ascii_case("cover"));
+        let url = match cover {
+            Some(path) => self.id(&path),
+            None => match chapters.first() {
+                Some(chapter) if page_count(chapter).map_or(false, |n| n > 0) => {
+                    format!("{}{ID_SEPARATOR}0", self.id(chapter))
+                }
+                _ => return String::new(),
+            },
+        };
+        protocol::image_url(Connector::id(self), &url)
+    }
+
+    fn describe(&self, series: &Path) -> io::Result<SearchItem> {
+        let chapters = chapter_paths(series)?;
+        let info = read_comic_info(series)
+            .or_else(|| chapters.first().and_then(|c| read_comic_info(c)))
+            .unwrap_or_default();
+
+        Ok(SearchItem {
+            id: self.id(series),
+            title: info.series.unwrap_or_else(|| stem(series)),
+            description: info.summary.unwrap_or_default(),
+            cover_url: self.cover(series, &chapters),
+        })
+    }
+
+    fn chapter(&self, path: &Path, index: usize) -> Chapter {
+        let info = read_comic_info(path).unwrap_or_default();
+        let name = stem(path);
+        Chapter {
+            id: self.id(path),
+            number: info
+                .number
+                .as_deref()
+                .and_then(|n| n.parse().ok())
+                .or_else(|| parse_number(&name))
+                .unwrap_or(index as f32),
+            name: info.title.unwrap_or(name),
+            volume: info.volume,
+            language: info.language_iso,
+            scanlation_group: None,
+            read: None,
+        }
+    }
+
+    fn matches(series: &Path, query: &str) -> bool {
+        let query = query.to_lowercase();
+        std::iter::once(series.to_path_buf())
+            .chain(chapter_paths(series).unwrap_or_default())
+            .any(|p| file_name(&p).to_lowercase().contains(&query))
+            || read_comic_info(series)
+                .and_then(|info| info.series)
+                .map(|s| s.to_lowercase().contains(&query))
+                .unwrap_or(false)
+    }
+
+    fn search_library(&self, query: &str, page: u32) -> io::Result<Page<SearchItem>> {
+        let matching = self
+            .series_paths()?
+            .into_iter()
+            .filter(|series| query.is_empty() || Self::matches(series, query))
+            .collect::<Vec<_>>();
+        let start = page as usize * PAGE_SIZE;
+        // only describe the page we return, covers are slow to read
+        let items = matching
+            .iter()
+            .skip(start)
+            .take(PAGE_SIZE)
+            .map(|series| self.describe(series))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Page {
+            has_next: start + PAGE_SIZE < matching.len(),
+            total: Some(matching.len() as u32),
+            items,
+        })
+    }
+
+    fn read_manga(&self, id: &str) -> io::Result<Manga> {
+        let series = self.resolve(id)?;
+        let mut chapters = chapter_paths(&series)?
+            .iter()
+            .enumerate()
+            .map(|(i, path)| self.chapter(path, i + 1))
+            .collect::<Vec<_>>();
+        // newest first, like the online connectors
+        chapters.reverse();
+
+        Ok(Manga {
+            desc: self.describe(&series)?,
+            chapters,
+        })
+    }
+
+    /// Pages are the chapter's ID and their index, read one at a time by
+    /// `read_page_url`.
+    fn read_chapter(&self, id: &str) -> io::Result<ChapterImages> {
+        let chapter = self.resolve(id)?;
+        Ok(ChapterImages {
+            images: (0..page_count(&chapter)?)
+                .map(|i| format!("{id}{ID_SEPARATOR}{i}"))
+                .collect(),
+            format: Format::Normal,
+        })
+    }
+
+    fn read_page_url(&self, url: &str) -> io::Result<Image> {
+        let page = url
+            .rsplit_once(ID_SEPARATOR)
+            .and_then(|(id, index)| Some((id, index.parse::<usize>().ok()?)));
+        if let Some((id, index)) = page {
+            return read_page(&self.resolve(id)?, index);
+        }
+        // a cover image, by its own ID
+        let path = self.resolve(url)?;
+        match path.is_file() && is_image(&path) {
+            true => Ok(Image {
+                content_type: content_type(&path).to_string(),
+                bytes: fs::read(&path)?,
+            }),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid page {url}"),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for LocalConnector {
+    fn id(&self) -> &str {
+        "local"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "Local"
+    }
+
+    fn url(&self) -> String {
+        String::new()
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        _filters: &SearchFilters,
+        _languages: &[String],
+        page: u32,
+    ) -> Result<Page<SearchItem>, ConnectorError> {
+        Ok(self.search_library(query, page)?)
+    }
+
+    async fn fetch_manga(&self, id: &str, _languages: &[String]) -> Result<Manga, ConnectorError> {
+        Ok(self.read_manga(id)?)
+    }
+
+    async fn fetch_chapter_urls(&self, id: &str) -> Result<ChapterImages, ConnectorError> {
+        Ok(self.read_chapter(id)?)
+    }
+
+    async fn fetch_image(&self, url: &str) -> Result<Image, ConnectorError> {
+        Ok(self.read_page_url(url)?)
+    }
+
+    /// Pages are IDs, which `resolve` keeps inside the library.
+    fn serves_image(&self, url: &str) -> bool {
+        !url.contains("://")
+    }
+
+    fn cached_image(&self, url: &str) -> Option<Image> {
+        self.read_page_url(url).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec!["Chapter 10", "chapter 2", "Chapter 1.5", "Chapter 1", "Extra"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            ["Chapter 1", "Chapter 1.5", "chapter 2", "Chapter 10", "Extra"]
+        );
+        assert_eq!(natural_cmp("page007", "page7"), Ordering::Equal);
+        assert_eq!(natural_cmp("vol 1", "vol 1 extra"), Ordering::Less);
+    }
+
+    #[test]
+    fn chapter_numbers() {
+        assert_eq!(parse_number("Chapter 012.5 - Title"), Some(12.5));
+        assert_eq!(parse_number("Vol.2 Ch.7"), Some(2.0));
+        assert_eq!(parse_number("Chapter 3."), Some(3.0));
+        assert_eq!(parse_number("Oneshot"), None);
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        let chapter = root.path().join("A|B 100%").join("Ch %7C 1");
+        fs::create_dir_all(&chapter).unwrap();
+        let connector = LocalConnector::new(LocalLibrary::new(root.path().to_path_buf()));
+
+        let id = connector.id(&chapter);
+        assert_eq!(id.matches(ID_SEPARATOR).count(), 1);
+        assert_eq!(connector.resolve(&id).unwrap(), chapter);
+        assert!(connector.resolve("..|etc").is_err());
+    }
+
+    #[test]
+    fn skips_rar_archives() {
+        let root = tempfile::tempdir().unwrap();
+        let series = root.path().join("Series");
+        fs::create_dir(&series).unwrap();
+        fs::write(series.join("Chapter 1.cbr"), b"Rar!").unwrap();
+        fs::write(series.join("Chapter 2.cbz"), b"").unwrap();
+
+        let chapters = chapter_paths(&series).unwrap();
+        assert_eq!(chapters, [series.join("Chapter 2.cbz")]);
+    }
+
+    #[tokio::test]
+    async fn serves_covers() {
+        let root = tempfile::tempdir().unwrap();
+        for (series, file) in [("Covered", "cover.png"), ("Covered", "Ch 1/1.jpg"), ("Plain", "Ch 1/1.jpg")] {
+            let path = root.path().join(series).join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, format!("{series}/{file}")).unwrap();
+        }
+        fs::create_dir_all(root.path().join("Empty").join("Ch 1")).unwrap();
+        let connector = LocalConnector::new(LocalLibrary::new(root.path().to_path_buf()));
+
+        for (series, file) in [("Covered", "cover.png"), ("Plain", "Ch 1/1.jpg")] {
+            let manga = connector.fetch_manga(series, &[]).await.unwrap();
+            let (connector_id, src) = protocol::parse_image_url(&manga.desc.cover_url).unwrap();
+            assert_eq!(connector_id, "local");
+            assert!(connector.serves_image(&src));
+            let image = connector.fetch_image(&src).await.unwrap();
+            assert_eq!(image.bytes, format!("{series}/{file}").into_bytes());
+        }
+        let empty = connector.fetch_manga("Empty", &[]).await.unwrap();
+        assert_eq!(empty.desc.cover_url, "");
+        assert!(connector.fetch_image("Covered|Ch 1").await.is_err());
+    }
+}
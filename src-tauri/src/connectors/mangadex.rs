@@ -1,22 +1,161 @@
-use std::collections::HashMap;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
 
 use async_trait::async_trait;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
 
-use crate::connectors::{Chapter, ChapterImages, Connector, Format, Manga, SearchItem};
+use crate::{
+    connectors::{
+        check_status,
+        mirrors::{address_settings, Mirrors},
+        Chapter, ChapterImages, Connector, ConnectorConfig, ConnectorError, ContentRating,
+        Demographic, FilterCapabilities, Format, HttpClient, Image, Manga, Page,
+        PublicationStatus, SearchFilters, SearchItem, SettingField, Settings, SortOrder, Tag,
+    },
+    image_cache::ImageCache,
+};
+
+const SEARCH_LIMIT: u32 = 50;
+/// The most chapters the feed endpoint returns per request.
+const FEED_LIMIT: u32 = 500;
+/// MangaDex refuses list requests where offset + limit goes past this.
+const MAX_RESULTS: u32 = 10_000;
+/// The API allows ~5 requests per second, the account shares them.
+const RATE: f64 = 5.0;
+const BURST: u32 = 5;
+/// Covers are on uploads.mangadex.org, pages on MangaDex@Home nodes.
+const IMAGE_HOSTS: &[&str] = &["mangadex.org", "mangadex.network"];
 
-#[derive(Clone)]
 pub struct MangaDex {
-    client: reqwest::Client
+    client: HttpClient,
+    cache: Arc<ImageCache>,
+    mirrors: Mirrors,
+    settings: RwLock<Settings>,
+}
+
+const CONTENT_RATINGS: [ContentRating; 4] = [
+    ContentRating::Safe,
+    ContentRating::Suggestive,
+    ContentRating::Erotica,
+    ContentRating::Pornographic,
+];
+
+fn settings_schema() -> Vec<SettingField> {
+    vec![
+        SettingField::choice(
+            "content_rating",
+            "Content rating",
+            "The most explicit manga shown, unless a search asks for others.",
+            &[
+                ("safe", "Safe"),
+                ("suggestive", "Suggestive"),
+                ("erotica", "Erotica"),
+                ("pornographic", "Pornographic"),
+            ],
+            // what the API shows when not asked
+            "erotica",
+        ),
+        SettingField::bool(
+            "data_saver",
+            "Data saver",
+            "Read compressed pages, which are smaller but blurrier.",
+            false,
+        ),
+        SettingField::string_list(
+            "languages",
+            "Chapter languages",
+            "ISO 639-1 codes to read MangaDex in instead of the preferred languages.",
+        ),
+    ]
+    .into_iter()
+    .chain(address_settings())
+    .collect()
 }
 
 impl MangaDex {
-    pub fn new(client: reqwest::Client) -> Self {
-        MangaDex { client }
+    pub fn new(client: HttpClient, cache: Arc<ImageCache>) -> Self {
+        let mangadex = MangaDex {
+            client,
+            cache,
+            mirrors: Mirrors::new("mangadex", "https://api.mangadex.org"),
+            settings: RwLock::new(Settings::resolve(&settings_schema(), None)),
+        };
+        mangadex.limit_hosts();
+        mangadex
+    }
+
+    /// Applies the API's rate limit to whichever address it's reached at.
+    fn limit_hosts(&self) {
+        for host in self.mirrors.hosts() {
+            self.client.limit(&host, RATE, BURST);
+        }
+    }
+
+    /// Ratings up to the one picked in the settings.
+    fn content_ratings(&self) -> Vec<ContentRating> {
+        let settings = self.settings.read().unwrap();
+        let max = settings.choice("content_rating");
+        let end = CONTENT_RATINGS
+            .iter()
+            .position(|r| content_rating_param(*r) == max)
+            .unwrap_or(CONTENT_RATINGS.len() - 1);
+        CONTENT_RATINGS[..=end].to_vec()
+    }
+
+    /// The languages from the settings, or `preferred` when none are set.
+    fn languages(&self, preferred: &[String]) -> Vec<String> {
+        let settings = self.settings.read().unwrap();
+        match settings.list("languages") {
+            [] => preferred.to_vec(),
+            languages => languages.to_vec(),
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: String) -> Result<T, ConnectorError> {
+        self.mirrors
+            .fetch(&url, |url| async move {
+                let res = check_status(self.client.send(self.client.get(url)).await?)?;
+                Ok(res.json::<T>().await?)
+            })
+            .await
+    }
+
+    async fn get_response<T: DeserializeOwned>(
+        &self,
+        url: String,
+    ) -> Result<ApiResponse<T>, ConnectorError> {
+        self.get_json(url).await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: String) -> Result<T, ConnectorError> {
+        Ok(self.get_response(url).await?.data)
+    }
+
+    fn proxy_cover(&self, item: SearchItem) -> SearchItem {
+        SearchItem {
+            cover_url: self.mirrors.proxy_image(&item.cover_url),
+            ..item
+        }
     }
 }
 
-type LocalizedString = HashMap<String, String>;
+// ordered so "first available" is the same every time
+type LocalizedString = BTreeMap<String, String>;
+
+/// Picks the best translation out of `strings`: the preferred languages in
+/// order, then romanized and original Japanese, then whatever there is.
+fn localized(strings: &[&LocalizedString], languages: &[String]) -> String {
+    languages
+        .iter()
+        .map(String::as_str)
+        .chain(["ja-ro", "ja"])
+        .find_map(|lang| strings.iter().find_map(|s| s.get(lang)))
+        .or_else(|| strings.iter().find_map(|s| s.values().next()))
+        .cloned()
+        .unwrap_or_default()
+}
 
 #[derive(Deserialize)]
 struct CoverArt {
@@ -34,6 +173,8 @@ struct ItemRelationship {
 #[derive(Deserialize)]
 struct ItemAttributes {
     title: LocalizedString,
+    #[serde(rename = "altTitles", default)]
+    alt_titles: Vec<LocalizedString>,
     description: LocalizedString,
 }
 
@@ -44,30 +185,28 @@ struct SearchManga {
     relationships: Vec<ItemRelationship>,
 }
 
-impl From<SearchManga> for SearchItem {
-    fn from(value: SearchManga) -> Self {
+impl SearchManga {
+    fn into_item(self, languages: &[String]) -> SearchItem {
+        let value = self;
         let id = value.id;
         let cover = value
             .relationships
             .into_iter()
             .find(|e| e.item_type == "cover_art")
-            .map(|x| x.attributes.unwrap().file_name)
+            .and_then(|x| x.attributes)
+            .map(|a| a.file_name)
             .unwrap_or_default();
-        let titles = value.attributes.title;
+        let attributes = value.attributes;
+        // the main title is often only romanized, with translations in the
+        // alternative titles
+        let titles = std::iter::once(&attributes.title)
+            .chain(attributes.alt_titles.iter())
+            .collect::<Vec<_>>();
         let cover_url = format!("https://uploads.mangadex.org/covers/{id}/{cover}.256.jpg");
         SearchItem {
             id,
-            title: titles
-                .get("en")
-                .or(titles.get("jp"))
-                .map(|s| s.to_string())
-                .unwrap_or_default(),
-            description: value
-                .attributes
-                .description
-                .get("en")
-                .map(|s| s.to_string())
-                .unwrap_or_default(),
+            title: localized(&titles, languages),
+            description: localized(&[&attributes.description], languages),
             cover_url,
         }
     }
@@ -77,6 +216,8 @@ impl From<SearchManga> for SearchItem {
 struct ChapterAttributes {
     volume: Option<String>,
     chapter: Option<String>,
+    #[serde(rename = "translatedLanguage")]
+    translated_language: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -89,16 +230,20 @@ struct MangaTag {
     attributes: TagAttributes,
 }
 
+/// Attributes of the manga or scanlation group a chapter links to, when
+/// they were asked for with `includes[]`.
 #[derive(Deserialize)]
-struct MangaAttributes {
+struct RelationshipAttributes {
+    #[serde(default)]
     tags: Vec<MangaTag>,
+    name: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct ChapterRelationships {
     #[serde(rename = "type")]
     item_type: String,
-    attributes: Option<MangaAttributes>,
+    attributes: Option<RelationshipAttributes>,
 }
 
 #[derive(Deserialize)]
@@ -110,10 +255,15 @@ struct ChapterData {
 
 impl From<ChapterData> for Chapter {
     fn from(value: ChapterData) -> Self {
+        let scanlation_group = value
+            .relationships
+            .iter()
+            .find(|r| r.item_type == "scanlation_group")
+            .and_then(|r| r.attributes.as_ref()?.name.clone());
         Chapter {
             id: value.id,
             name: {
-                let vol = value.attributes.volume.map(|v| format!("Vol.{v} "));
+                let vol = value.attributes.volume.as_ref().map(|v| format!("Vol.{v} "));
                 let chap = value
                     .attributes
                     .chapter
@@ -127,6 +277,9 @@ impl From<ChapterData> for Chapter {
                 .chapter
                 .and_then(|c| c.parse().ok())
                 .unwrap_or(0f32),
+            volume: value.attributes.volume,
+            language: value.attributes.translated_language,
+            scanlation_group,
             read: None,
         }
     }
@@ -135,6 +288,8 @@ impl From<ChapterData> for Chapter {
 #[derive(Deserialize)]
 struct AtHomeChapter {
     data: Vec<String>,
+    #[serde(rename = "dataSaver")]
+    data_saver: Vec<String>,
     hash: String,
 }
 
@@ -145,83 +300,280 @@ struct AtHomeData {
     chapter: AtHomeChapter,
 }
 
+#[derive(Deserialize)]
+struct TagListAttributes {
+    name: LocalizedString,
+    group: String,
+}
+
+#[derive(Deserialize)]
+struct TagListItem {
+    id: String,
+    attributes: TagListAttributes,
+}
+
+fn status_param(status: PublicationStatus) -> &'static str {
+    match status {
+        PublicationStatus::Ongoing => "ongoing",
+        PublicationStatus::Completed => "completed",
+        PublicationStatus::Hiatus => "hiatus",
+        PublicationStatus::Cancelled => "cancelled",
+    }
+}
+
+fn demographic_param(demographic: Demographic) -> &'static str {
+    match demographic {
+        Demographic::Shounen => "shounen",
+        Demographic::Shoujo => "shoujo",
+        Demographic::Seinen => "seinen",
+        Demographic::Josei => "josei",
+    }
+}
+
+fn content_rating_param(rating: ContentRating) -> &'static str {
+    match rating {
+        ContentRating::Safe => "safe",
+        ContentRating::Suggestive => "suggestive",
+        ContentRating::Erotica => "erotica",
+        ContentRating::Pornographic => "pornographic",
+    }
+}
+
+fn order_param(sort: SortOrder) -> (&'static str, &'static str) {
+    match sort {
+        SortOrder::Relevance => ("order[relevance]", "desc"),
+        SortOrder::LatestUpload => ("order[latestUploadedChapter]", "desc"),
+        SortOrder::Popular => ("order[followedCount]", "desc"),
+        SortOrder::Rating => ("order[rating]", "desc"),
+        SortOrder::Title => ("order[title]", "asc"),
+        SortOrder::NewlyAdded => ("order[createdAt]", "desc"),
+    }
+}
+
 #[derive(Deserialize)]
 struct ApiResponse<T> {
     data: T,
+    /// Only set on list endpoints.
+    total: Option<u32>,
 }
 
 #[async_trait]
 impl Connector for MangaDex {
+    fn id(&self) -> &str {
+        "mangadex"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
     fn name(&self) -> &str {
         "MangaDex"
     }
 
-    fn url(&self) -> &str {
-        "https://api.mangadex.org"
+    fn url(&self) -> String {
+        self.mirrors.base()
+    }
+
+    fn settings_schema(&self) -> Vec<SettingField> {
+        settings_schema()
+    }
+
+    fn apply_settings(&self, settings: &Settings) {
+        self.mirrors.configure(&ConnectorConfig::from_settings(settings));
+        self.limit_hosts();
+        *self.settings.write().unwrap() = settings.clone();
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        languages: &[String],
+        page: u32,
+    ) -> Result<Page<SearchItem>, ConnectorError> {
+        let offset = page * SEARCH_LIMIT;
+        if offset + SEARCH_LIMIT > MAX_RESULTS {
+            return Ok(Page::empty());
+        }
+
+        let mut params = vec![
+            ("includes[]", "cover_art".to_string()),
+            ("limit", SEARCH_LIMIT.to_string()),
+            ("offset", offset.to_string()),
+        ];
+        if !query.is_empty() {
+            params.push(("title", query.to_string()));
+        }
+        params.extend(filters.tags.iter().map(|t| ("includedTags[]", t.clone())));
+        params.extend(
+            filters
+                .status
+                .iter()
+                .map(|s| ("status[]", status_param(*s).to_string())),
+        );
+        params.extend(
+            filters
+                .demographic
+                .iter()
+                .map(|d| ("publicationDemographic[]", demographic_param(*d).to_string())),
+        );
+        let content_ratings = match filters.content_rating.is_empty() {
+            true => self.content_ratings(),
+            false => filters.content_rating.clone(),
+        };
+        params.extend(
+            content_ratings
+                .iter()
+                .map(|r| ("contentRating[]", content_rating_param(*r).to_string())),
+        );
+        params.extend(
+            filters
+                .languages
+                .iter()
+                .map(|l| ("availableTranslatedLanguage[]", l.clone())),
+        );
+        if let Some(sort) = filters.sort {
+            let (key, direction) = order_param(sort);
+            params.push((key, direction.to_string()));
+        }
+        let url = reqwest::Url::parse_with_params(&format!("{}/manga", self.url()), &params)
+            .map_err(ConnectorError::parse)?;
+
+        let results = self
+            .get_response::<Vec<SearchManga>>(url.to_string())
+            .await?;
+
+        let total = results.total.unwrap_or_default();
+        let fetched = offset + results.data.len() as u32;
+        Ok(Page {
+            items: results
+                .data
+                .into_iter()
+                .map(|it| self.proxy_cover(it.into_item(languages)))
+                .collect(),
+            has_next: fetched < total.min(MAX_RESULTS) && fetched + SEARCH_LIMIT <= MAX_RESULTS,
+            total: Some(total),
+        })
     }
 
-    async fn search(&self, query: &str) -> Result<Vec<SearchItem>, reqwest::Error> {
+    async fn filter_capabilities(&self) -> Result<FilterCapabilities, ConnectorError> {
         let url = self.url();
-        let results = self.client.get(format!(
-            "{url}/manga?title={query}&includes[]=cover_art&limit=50"
-        ))
-        .send()
-        .await?
-        .json::<ApiResponse<Vec<SearchManga>>>()
-        .await?;
+        let tags: Vec<TagListItem> = self.get(format!("{url}/manga/tag")).await?;
+        let mut tags = tags
+            .into_iter()
+            .map(|t| Tag {
+                name: t.attributes.name.get("en").cloned().unwrap_or_else(|| t.id.clone()),
+                id: t.id,
+                group: Some(t.attributes.group),
+            })
+            .collect::<Vec<_>>();
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
 
-        Ok(results.data.into_iter().map(|it| it.into()).collect())
+        Ok(FilterCapabilities {
+            tags,
+            max_tags: None,
+            status: vec![
+                PublicationStatus::Ongoing,
+                PublicationStatus::Completed,
+                PublicationStatus::Hiatus,
+                PublicationStatus::Cancelled,
+            ],
+            demographic: vec![
+                Demographic::Shounen,
+                Demographic::Shoujo,
+                Demographic::Seinen,
+                Demographic::Josei,
+            ],
+            content_rating: vec![
+                ContentRating::Safe,
+                ContentRating::Suggestive,
+                ContentRating::Erotica,
+                ContentRating::Pornographic,
+            ],
+            languages: ["en", "ja", "ko", "zh", "es", "es-la", "fr", "de", "it", "pt-br", "ru"]
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            sort: vec![
+                SortOrder::Relevance,
+                SortOrder::LatestUpload,
+                SortOrder::Popular,
+                SortOrder::Rating,
+                SortOrder::Title,
+                SortOrder::NewlyAdded,
+            ],
+            filters_with_query: true,
+        })
     }
 
-    async fn fetch_manga(&self, id: &str) -> Result<Manga, reqwest::Error> {
+    async fn fetch_manga(&self, id: &str, languages: &[String]) -> Result<Manga, ConnectorError> {
+        let languages = &self.languages(languages);
         let url = self.url();
-        let manga = self.client.get(format!("{url}/manga/{id}?includes[]=cover_art"))
-            .send()    
-            .await?
-            .json::<ApiResponse<SearchManga>>()
+        let manga: SearchManga = self
+            .get(format!("{url}/manga/{id}?includes[]=cover_art"))
             .await?;
-        let volumes = self.client.get(format!(
-            "{url}/chapter?limit=100&manga={id}&translatedLanguage[]=en&order[chapter]=desc"
-        ))
-        .send()
-        .await?
-        .json::<ApiResponse<Vec<ChapterData>>>()
-        .await?;
+
+        let feed = format!("{url}/manga/{id}/feed");
+        let mut chapters = Vec::new();
+        loop {
+            let mut params = vec![
+                ("limit", FEED_LIMIT.to_string()),
+                ("offset", chapters.len().to_string()),
+                ("includes[]", "scanlation_group".to_string()),
+                ("order[chapter]", "desc".to_string()),
+            ];
+            params.extend(languages.iter().map(|l| ("translatedLanguage[]", l.clone())));
+            params.extend(
+                self.content_ratings()
+                    .iter()
+                    .map(|r| ("contentRating[]", content_rating_param(*r).to_string())),
+            );
+            let page_url =
+                reqwest::Url::parse_with_params(&feed, &params).map_err(ConnectorError::parse)?;
+            let page = self
+                .get_response::<Vec<ChapterData>>(page_url.to_string())
+                .await?;
+            let done = page.data.is_empty()
+                || chapters.len() + page.data.len() >= page.total.unwrap_or_default() as usize;
+            chapters.extend(page.data.into_iter().map(Chapter::from));
+            // the rest can't be paged to, so it's all we get
+            if done || chapters.len() as u32 + FEED_LIMIT > MAX_RESULTS {
+                break;
+            }
+        }
 
         Ok(Manga {
-            desc: manga.data.into(),
-            chapters: volumes.data.into_iter().map(|c| c.into()).collect(),
+            desc: self.proxy_cover(manga.into_item(languages)),
+            chapters,
         })
     }
 
-    async fn fetch_chapter(&self, id: &str) -> Result<ChapterImages, reqwest::Error> {
+    async fn fetch_chapter_urls(&self, id: &str) -> Result<ChapterImages, ConnectorError> {
         let url = self.url();
-        let chapter = self.client.get(format!("{url}/chapter/{id}?includes[]=manga"))
-            .send()
-            .await?
-            .json::<ApiResponse<ChapterData>>()
-            .await?
-            .data;
-        let at_home = self.client.get(format!("{url}/at-home/server/{id}"))
-            .send()
-            .await?
-            .json::<AtHomeData>()
+        let chapter: ChapterData = self
+            .get(format!("{url}/chapter/{id}?includes[]=manga"))
             .await?;
+        // the at-home endpoint isn't wrapped in `data` like the rest
+        let at_home: AtHomeData = self.get_json(format!("{url}/at-home/server/{id}")).await?;
 
         let tags = chapter
             .relationships
             .into_iter()
             .find(|r| r.item_type == "manga")
-            .unwrap()
-            .attributes
-            .unwrap()
+            .and_then(|r| r.attributes)
+            .ok_or_else(|| ConnectorError::parse(format!("chapter {id} has no manga")))?
             .tags;
-        let base = format!("{}/data/{}", at_home.base_url, at_home.chapter.hash);
+        let data_saver = self.settings.read().unwrap().bool("data_saver");
+        let (path, files) = match data_saver {
+            true => ("data-saver", at_home.chapter.data_saver),
+            false => ("data", at_home.chapter.data),
+        };
+        let base = format!("{}/{path}/{}", at_home.base_url, at_home.chapter.hash);
 
         let is_long = tags
-            .into_iter()
-            .find(|t| t.attributes.name["en"] == "Long Strip")
-            .is_some();
+            .iter()
+            .any(|t| t.attributes.name.get("en").map(|n| n.as_str()) == Some("Long Strip"));
 
         Ok(ChapterImages {
             format: if is_long {
@@ -229,12 +581,22 @@ impl Connector for MangaDex {
             } else {
                 Format::Normal
             },
-            images: at_home
-                .chapter
-                .data
+            images: files
                 .into_iter()
-                .map(|file| format!("{base}/{file}"))
+                .map(|file| self.mirrors.proxy_image(&format!("{base}/{file}")))
                 .collect(),
         })
     }
+
+    async fn fetch_image(&self, url: &str) -> Result<Image, ConnectorError> {
+        self.cache.fetch(&self.client, url, self.client.get(url)).await
+    }
+
+    fn serves_image(&self, url: &str) -> bool {
+        self.mirrors.serves(url, IMAGE_HOSTS)
+    }
+
+    fn cached_image(&self, url: &str) -> Option<Image> {
+        self.cache.cached(url)
+    }
 }
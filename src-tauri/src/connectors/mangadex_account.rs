@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use super::{check_status, env_url, ConnectorError, HttpClient};
+use crate::{prefs::write_private, updates::now_millis};
+
+const API_URL: &str = "https://api.mangadex.org";
+const AUTH_URL: &str = "https://auth.mangadex.org/realms/mangadex/protocol/openid-connect/token";
+
+/// Refresh this long before the access token actually expires.
+const REFRESH_MARGIN: u64 = 60 * 1000;
+/// Most IDs the read marker endpoint takes at once.
+const READ_BATCH: usize = 100;
+const FOLLOWS_LIMIT: u32 = 100;
+
+/// What's needed to stay logged in. MangaDex only hands out tokens to
+/// personal API clients, so the client's credentials are kept too.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    username: String,
+    client_id: String,
+    client_secret: String,
+    access_token: String,
+    refresh_token: String,
+    /// Milliseconds since the epoch.
+    expires_at: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    /// Seconds.
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct Entity {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+    data: Vec<Entity>,
+    total: u32,
+}
+
+#[derive(Deserialize)]
+struct ReadMarkers {
+    data: HashMap<String, Vec<String>>,
+}
+
+/// A logged in MangaDex account, used to keep follows and read markers in
+/// sync with the library. The session is saved next to the settings so it
+/// survives restarts, readable only by the current user.
+///
+/// `BUNNI_MANGADEX_API` and `BUNNI_MANGADEX_AUTH` point it at another
+/// server than MangaDex, like a local mock for testing.
+pub struct MangaDexAccount {
+    client: HttpClient,
+    api_url: String,
+    auth_url: String,
+    path: PathBuf,
+    session: Mutex<Option<Session>>,
+}
+
+impl MangaDexAccount {
+    pub fn new(client: HttpClient, data_dir: PathBuf) -> Self {
+        let path = data_dir.join("mangadex_session.json");
+        let session = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| println!("ignoring unreadable MangaDex session: {e}"))
+                .ok(),
+            Err(_) => None,
+        };
+        MangaDexAccount {
+            client,
+            api_url: env_url("BUNNI_MANGADEX_API", API_URL),
+            auth_url: env_url("BUNNI_MANGADEX_AUTH", AUTH_URL),
+            path,
+            session: Mutex::new(session),
+        }
+    }
+
+    fn save(&self, session: Option<&Session>) -> io::Result<()> {
+        let session = match session {
+            Some(session) => session,
+            None => {
+                return match fs::remove_file(&self.path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                }
+            }
+        };
+
+        write_private(&self.path, session)
+    }
+
+    /// Only a 400 or 401 means the credentials are bad. Anything else, like
+    /// being rate limited, is passed on as is so it doesn't end the session.
+    async fn request_token(&self, form: &[(&str, &str)]) -> Result<TokenResponse, ConnectorError> {
+        let res = self.client.send(self.client.post(&self.auth_url).form(form)).await?;
+        if matches!(res.status(), StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED) {
+            return Err(ConnectorError::Auth(format!(
+                "MangaDex rejected the credentials ({})",
+                res.status()
+            )));
+        }
+        Ok(check_status(res)?.json().await?)
+    }
+
+    /// Logs in with the password grant, replacing any previous session.
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<(), ConnectorError> {
+        let token = self
+            .request_token(&[
+                ("grant_type", "password"),
+                ("username", username),
+                ("password", password),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+            ])
+            .await?;
+        let session = Session {
+            username: username.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_at: now_millis() + token.expires_in * 1000,
+        };
+        self.save(Some(&session))?;
+        *self.session.lock().await = Some(session);
+        Ok(())
+    }
+
+    pub async fn logout(&self) -> Result<(), ConnectorError> {
+        *self.session.lock().await = None;
+        Ok(self.save(None)?)
+    }
+
+    /// The logged in user's name.
+    pub async fn username(&self) -> Option<String> {
+        self.session.lock().await.as_ref().map(|s| s.username.clone())
+    }
+
+    /// A valid access token, refreshing it first if it's about to expire.
+    /// A refresh token that no longer works ends the session.
+    async fn access_token(&self) -> Result<String, ConnectorError> {
+        let mut guard = self.session.lock().await;
+        let session = guard
+            .as_mut()
+            .ok_or_else(|| ConnectorError::Auth("not logged in to MangaDex".to_string()))?;
+        if session.expires_at > now_millis() + REFRESH_MARGIN {
+            return Ok(session.access_token.clone());
+        }
+
+        let token = self
+            .request_token(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", &session.refresh_token),
+                ("client_id", &session.client_id),
+                ("client_secret", &session.client_secret),
+            ])
+            .await;
+        let token = match token {
+            Ok(token) => token,
+            Err(e @ ConnectorError::Auth(_)) => {
+                *guard = None;
+                self.save(None)?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        session.access_token = token.access_token;
+        session.refresh_token = token.refresh_token;
+        session.expires_at = now_millis() + token.expires_in * 1000;
+        self.save(Some(session))?;
+        Ok(session.access_token.clone())
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response, ConnectorError> {
+        let token = self.access_token().await?;
+        check_status(self.client.send(request.bearer_auth(token)).await?)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: reqwest::Url) -> Result<T, ConnectorError> {
+        Ok(self.send(self.client.get(url)).await?.json().await?)
+    }
+
+    pub async fn set_followed(&self, manga_id: &str, followed: bool) -> Result<(), ConnectorError> {
+        let url = format!("{}/manga/{manga_id}/follow", self.api_url);
+        let request = match followed {
+            true => self.client.post(url),
+            false => self.client.delete(url),
+        };
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// IDs of every manga the user follows.
+    pub async fn follows(&self) -> Result<Vec<String>, ConnectorError> {
+        let mut ids = Vec::new();
+        loop {
+            let url = reqwest::Url::parse_with_params(
+                &format!("{}/user/follows/manga", self.api_url),
+                &[
+                    ("limit", FOLLOWS_LIMIT.to_string()),
+                    ("offset", ids.len().to_string()),
+                ],
+            )
+            .map_err(ConnectorError::parse)?;
+            let page: ListResponse = self.get(url).await?;
+            let done = page.data.is_empty() || ids.len() + page.data.len() >= page.total as usize;
+            ids.extend(page.data.into_iter().map(|m| m.id));
+            if done {
+                return Ok(ids);
+            }
+        }
+    }
+
+    pub async fn mark_read(&self, manga_id: &str, chapter_ids: &[String]) -> Result<(), ConnectorError> {
+        let url = format!("{}/manga/{manga_id}/read", self.api_url);
+        let body = json!({ "chapterIdsRead": chapter_ids, "chapterIdsUnread": [] });
+        self.send(self.client.post(url).json(&body)).await?;
+        Ok(())
+    }
+
+    /// The read chapters of each of the given manga.
+    pub async fn read_markers(
+        &self,
+        manga_ids: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, ConnectorError> {
+        let mut markers = HashMap::new();
+        for batch in manga_ids.chunks(READ_BATCH) {
+            let params = batch
+                .iter()
+                .map(|id| ("ids[]", id.as_str()))
+                .chain([("grouped", "true")]);
+            let url = reqwest::Url::parse_with_params(&format!("{}/manga/read", self.api_url), params)
+                .map_err(ConnectorError::parse)?;
+            let res: ReadMarkers = self.get(url).await?;
+            markers.extend(res.data);
+        }
+        Ok(markers)
+    }
+}
+
+#[cfg(test)]
+impl MangaDexAccount {
+    /// An account against a test server at `url`, with the token endpoint at
+    /// `/token`, logged in until `expires_at`. The session is saved in `dir`.
+    pub fn test(url: &str, dir: &std::path::Path, expires_at: u64) -> Self {
+        MangaDexAccount {
+            client: HttpClient::new(),
+            api_url: url.to_string(),
+            auth_url: format!("{url}/token"),
+            path: dir.join("mangadex_session.json"),
+            session: Mutex::new(Some(Session {
+                username: "reader".to_string(),
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                expires_at,
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    fn token(access_token: &str) -> Response {
+        Response::json(json!({
+            "access_token": access_token,
+            "refresh_token": "new-refresh",
+            "expires_in": 900,
+        }))
+    }
+
+    fn follows_page(offset: usize, len: usize, total: usize) -> Response {
+        let data = (offset..offset + len)
+            .map(|i| json!({ "id": format!("manga-{i}") }))
+            .collect::<Vec<_>>();
+        Response::json(json!({ "data": data, "total": total }))
+    }
+
+    #[tokio::test]
+    async fn login_saves_session() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/token" => token("logged-in"),
+            _ => Response::status(404),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let account = MangaDexAccount::test(&server.url, dir.path(), 0);
+        account.logout().await.unwrap();
+
+        account.login("someone", "hunter2", "id", "secret").await.unwrap();
+        assert_eq!(account.username().await.as_deref(), Some("someone"));
+        let saved: Session =
+            serde_json::from_slice(&fs::read(dir.path().join("mangadex_session.json")).unwrap())
+                .unwrap();
+        assert_eq!(saved.access_token, "logged-in");
+        assert_eq!(saved.refresh_token, "new-refresh");
+
+        let req = &server.requests_to("/token")[0];
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.form("grant_type").as_deref(), Some("password"));
+        assert_eq!(req.form("username").as_deref(), Some("someone"));
+        assert_eq!(req.form("client_secret").as_deref(), Some("secret"));
+    }
+
+    #[tokio::test]
+    async fn refreshes_token_near_expiry() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/token" => token("refreshed"),
+            "/user/follows/manga" => follows_page(0, 0, 0),
+            _ => Response::status(404),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        // inside the refresh margin
+        let account = MangaDexAccount::test(&server.url, dir.path(), now_millis() + 1000);
+
+        account.follows().await.unwrap();
+        account.follows().await.unwrap();
+
+        let refreshes = server.requests_to("/token");
+        assert_eq!(refreshes.len(), 1);
+        assert_eq!(refreshes[0].form("grant_type").as_deref(), Some("refresh_token"));
+        assert_eq!(refreshes[0].form("refresh_token").as_deref(), Some("refresh"));
+        for req in server.requests_to("/user/follows/manga") {
+            assert_eq!(req.headers["authorization"], "Bearer refreshed");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_refresh_ends_session() {
+        let server = TestServer::start(|_| Response::status(401)).await;
+        let dir = tempfile::tempdir().unwrap();
+        let account = MangaDexAccount::test(&server.url, dir.path(), 0);
+        account.save(account.session.lock().await.as_ref()).unwrap();
+
+        assert!(matches!(account.follows().await, Err(ConnectorError::Auth(_))));
+        assert_eq!(account.username().await, None);
+        assert!(!dir.path().join("mangadex_session.json").exists());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_refresh_keeps_session() {
+        let server =
+            TestServer::start(|_| Response::status(429).header("Retry-After", "3600")).await;
+        let dir = tempfile::tempdir().unwrap();
+        let account = MangaDexAccount::test(&server.url, dir.path(), 0);
+
+        assert!(matches!(
+            account.follows().await,
+            Err(ConnectorError::RateLimited { .. })
+        ));
+        assert_eq!(account.username().await.as_deref(), Some("reader"));
+    }
+
+    #[tokio::test]
+    async fn pages_through_follows() {
+        let server = TestServer::start(|req| {
+            let offset = req.param("offset").unwrap().parse().unwrap();
+            follows_page(offset, (250 - offset).min(100), 250)
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let account = MangaDexAccount::test(&server.url, dir.path(), now_millis() + HOUR);
+
+        let follows = account.follows().await.unwrap();
+        assert_eq!(follows.len(), 250);
+        assert_eq!(follows[249], "manga-249");
+        let offsets = server
+            .requests()
+            .iter()
+            .map(|r| r.param("offset").unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, ["0", "100", "200"]);
+    }
+
+    #[tokio::test]
+    async fn batches_read_markers() {
+        let server = TestServer::start(|req| {
+            let data = req
+                .params("ids[]")
+                .into_iter()
+                .map(|id| (id.to_string(), vec![format!("{id}-chapter")]))
+                .collect::<HashMap<_, _>>();
+            Response::json(json!({ "data": data }))
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let account = MangaDexAccount::test(&server.url, dir.path(), now_millis() + HOUR);
+
+        let ids = (0..150).map(|i| format!("manga-{i}")).collect::<Vec<_>>();
+        let markers = account.read_markers(&ids).await.unwrap();
+        assert_eq!(markers.len(), 150);
+        assert_eq!(markers["manga-120"], ["manga-120-chapter"]);
+        let batches = server
+            .requests_to("/manga/read")
+            .iter()
+            .map(|r| r.params("ids[]").len())
+            .collect::<Vec<_>>();
+        assert_eq!(batches, [100, 50]);
+    }
+}
@@ -1,16 +1,104 @@
-use super::{Chapter, ChapterImages, Connector, Format, Manga, SearchItem};
+use super::{
+    check_status,
+    mirrors::{address_settings, Mirrors},
+    Chapter, ChapterImages, Connector, ConnectorConfig, ConnectorError, FilterCapabilities, Format,
+    HttpClient, Image, Manga, Page, PublicationStatus, SearchFilters, SearchItem, SettingField,
+    Settings, SortOrder, Tag,
+};
+use std::sync::{Arc, RwLock};
+
 use async_trait::async_trait;
-use base64::{engine::general_purpose, Engine};
-use futures::future::join_all;
 use reqwest::header::HeaderValue;
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Selector};
+
+use crate::image_cache::ImageCache;
 
 const USER_AGENT: &str =
     "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:71.0) Gecko/20100101 Firefox/77.0";
 
-#[derive(Clone)]
+/// `category` values for the manga list, by genre name.
+const GENRES: [(&str, &str); 40] = [
+    ("2", "Action"),
+    ("3", "Adult"),
+    ("4", "Adventure"),
+    ("6", "Comedy"),
+    ("7", "Cooking"),
+    ("9", "Doujinshi"),
+    ("10", "Drama"),
+    ("11", "Ecchi"),
+    ("12", "Fantasy"),
+    ("13", "Gender bender"),
+    ("14", "Harem"),
+    ("15", "Historical"),
+    ("16", "Horror"),
+    ("45", "Isekai"),
+    ("17", "Josei"),
+    ("44", "Manhua"),
+    ("43", "Manhwa"),
+    ("19", "Martial arts"),
+    ("20", "Mature"),
+    ("21", "Mecha"),
+    ("22", "Medical"),
+    ("24", "Mystery"),
+    ("25", "One shot"),
+    ("26", "Psychological"),
+    ("27", "Romance"),
+    ("28", "School life"),
+    ("29", "Sci fi"),
+    ("30", "Seinen"),
+    ("31", "Shoujo"),
+    ("32", "Shoujo ai"),
+    ("33", "Shounen"),
+    ("34", "Shounen ai"),
+    ("35", "Slice of life"),
+    ("36", "Smut"),
+    ("37", "Sports"),
+    ("38", "Supernatural"),
+    ("39", "Tragedy"),
+    ("40", "Webtoons"),
+    ("41", "Yaoi"),
+    ("42", "Yuri"),
+];
+
+/// Images on this host refuse hotlinking, so they go through a proxy even
+/// when none is configured.
+const PROTECTED_IMAGES: &str = "https://convert_image_digi.mgicdn.com";
+const DEFAULT_PROXY: &str = "https://images.weserv.nl/?url=";
+/// The sister sites and CDNs covers and pages are on.
+const IMAGE_HOSTS: &[&str] = &[
+    "mangakakalot.com",
+    "manganato.com",
+    "chapmanganato.com",
+    "chapmanganato.to",
+    "mkklcdnv6temp.com",
+    "mkklcdnv6tempv2.com",
+    "mkklcdnv6tempv3.com",
+    "mkklcdnv6tempv5.com",
+    "2xstorage.com",
+    "mgicdn.com",
+    "images.weserv.nl",
+];
+
 pub struct MangaKakalot {
-    client: reqwest::Client,
+    client: HttpClient,
+    cache: Arc<ImageCache>,
+    mirrors: Mirrors,
+    settings: RwLock<Settings>,
+}
+
+fn settings_schema() -> Vec<SettingField> {
+    let proxy_images = SettingField::choice(
+        "proxy_images",
+        "Proxy images",
+        "Which images go through images.weserv.nl when no image proxy is set.",
+        &[
+            ("protected", "Hotlink protected ones"),
+            ("all", "All of them"),
+            ("none", "None"),
+        ],
+        "protected",
+    );
+    std::iter::once(proxy_images).chain(address_settings()).collect()
 }
 
 fn change_alias(alias: &str) -> String {
@@ -31,164 +119,274 @@ fn change_alias(alias: &str) -> String {
     str
 }
 
+/// Chapter names look like "Vol.2 Chapter 14: Title" when the series has
+/// volumes.
+fn parse_volume(name: &str) -> Option<String> {
+    let rest = name.strip_prefix("Vol.")?;
+    let end = rest.find(' ').unwrap_or(rest.len());
+    Some(rest[..end].to_string()).filter(|v| !v.is_empty())
+}
+
+/// The pager's last link reads "Last(12)".
+fn last_page(doc: &Html) -> Result<Option<u32>, ConnectorError> {
+    let last = selector("a.page_last, a.page-last")?;
+    Ok(doc.select(&last).next().and_then(|el| {
+        let text: String = el.text().collect();
+        text.chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse()
+            .ok()
+    }))
+}
+
+/// Pulls 14.5 out of "Vol.2 Chapter 14.5: Title".
+fn parse_number(name: &str) -> Option<f32> {
+    let start = name.find("Chapter ")? + "Chapter ".len();
+    let rest = &name[start..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    rest[..end].trim_end_matches('.').parse().ok()
+}
+
 impl MangaKakalot {
-    pub fn new(client: reqwest::Client) -> MangaKakalot {
-        MangaKakalot { client }
+    pub fn new(client: HttpClient, cache: Arc<ImageCache>) -> MangaKakalot {
+        MangaKakalot {
+            client,
+            cache,
+            mirrors: Mirrors::new("mangakakalot", "https://mangakakalot.com"),
+            settings: RwLock::new(Settings::resolve(&settings_schema(), None)),
+        }
     }
 
-    fn headers() -> reqwest::header::HeaderMap {
+    fn headers(&self) -> reqwest::header::HeaderMap {
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert(
             reqwest::header::USER_AGENT,
             HeaderValue::from_static(USER_AGENT),
         );
-        headers.insert(
-            reqwest::header::REFERER,
-            HeaderValue::from_static("https://mangakakalot.com/"),
-        );
+        if let Ok(referer) = HeaderValue::from_str(&format!("{}/", self.url())) {
+            headers.insert(reqwest::header::REFERER, referer);
+        }
         headers
     }
 
-    pub async fn get_text(&self, url: impl Into<String>) -> reqwest::Result<String> {
-        self.client
-            .get(url.into())
-            .headers(Self::headers())
-            .send()
-            .await?
-            .text()
+    pub async fn get_text(&self, url: impl Into<String>) -> Result<String, ConnectorError> {
+        self.mirrors
+            .fetch(&url.into(), |url| async move {
+                let request = self.client.get(url).headers(self.headers());
+                let res = self.client.send(request).await?;
+                Ok(check_status(res)?.text().await?)
+            })
             .await
     }
 
-    pub async fn fetch_cover_img(&self, url: String) -> Result<String, reqwest::Error> {
-        let req = self.client.get(url).headers(Self::headers()).send().await?;
+    /// Hotlink protected images go through images.weserv.nl unless the user
+    /// set up their own proxy, which then gets every image.
+    fn proxy_image(&self, src: String) -> String {
+        if self.mirrors.has_proxy() {
+            return self.mirrors.proxy_image(&src);
+        }
+        let proxied = match self.settings.read().unwrap().choice("proxy_images") {
+            "all" => true,
+            "protected" => src.starts_with(PROTECTED_IMAGES),
+            _ => false,
+        };
+        match proxied {
+            true => format!(
+                "{DEFAULT_PROXY}{}",
+                src.split("//").nth(1).unwrap_or_default()
+            ),
+            false => src,
+        }
+    }
+}
 
-        let content_type = req
-            .headers()
-            .get("content-type")
-            .expect("content-type header not found")
-            .to_str()
-            .unwrap()
-            .to_string();
-        let bytes = general_purpose::STANDARD.encode(req.bytes().await?);
+fn selector(selector: &str) -> Result<Selector, ConnectorError> {
+    Selector::parse(selector).map_err(|e| ConnectorError::parse(format!("{selector}: {e}")))
+}
 
-        Ok(format!("data:{content_type};base64,{bytes}"))
-    }
+/// First element under `el` matching `sel`, failing when the page no longer
+/// has it.
+fn select<'a>(el: ElementRef<'a>, sel: &str) -> Result<ElementRef<'a>, ConnectorError> {
+    el.select(&selector(sel)?)
+        .next()
+        .ok_or_else(|| ConnectorError::parse(format!("no element matching {sel}")))
+}
+
+fn attr(el: ElementRef, name: &str) -> Result<String, ConnectorError> {
+    el.attr(name)
+        .map(|a| a.to_string())
+        .ok_or_else(|| ConnectorError::parse(format!("<{}> has no {name}", el.value().name())))
 }
 
 #[async_trait]
 impl Connector for MangaKakalot {
+    fn id(&self) -> &str {
+        "mangakakalot"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
     fn name(&self) -> &str {
         "MangaKakalot"
     }
 
-    fn url(&self) -> &str {
-        "https://mangakakalot.com"
+    fn url(&self) -> String {
+        self.mirrors.base()
     }
 
-    async fn search(&self, query: &str) -> Result<Vec<SearchItem>, reqwest::Error> {
-        let url = if query == "" {
-            format!("{}/manga_list", self.url())
+    fn settings_schema(&self) -> Vec<SettingField> {
+        settings_schema()
+    }
+
+    fn apply_settings(&self, settings: &Settings) {
+        self.mirrors.configure(&ConnectorConfig::from_settings(settings));
+        *self.settings.write().unwrap() = settings.clone();
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        _languages: &[String],
+        page: u32,
+    ) -> Result<Page<SearchItem>, ConnectorError> {
+        // the site counts pages from one
+        let page = page + 1;
+        // the search page has no filters, only the browse listing does
+        let url = if query.is_empty() {
+            let sort = match filters.sort {
+                Some(SortOrder::Popular) => "topview",
+                Some(SortOrder::NewlyAdded) => "newest",
+                _ => "latest",
+            };
+            let category = filters.tags.first().map_or("all", |t| t.as_str());
+            // only one state can be picked, "all" if both are wanted
+            let state = match filters.status[..] {
+                [PublicationStatus::Completed] => "completed",
+                [PublicationStatus::Ongoing] => "ongoing",
+                _ => "all",
+            };
+            format!(
+                "{}/manga_list?type={sort}&category={category}&state={state}&page={page}",
+                self.url()
+            )
         } else {
-            format!("{}/search/story/{}", self.url(), change_alias(query))
+            format!(
+                "{}/search/story/{}?page={page}",
+                self.url(),
+                change_alias(query)
+            )
         };
 
-        let page = self.get_text(url).await?;
-        let doc = Html::parse_document(&page);
-        let mangas =
-            Selector::parse(".panel_story_list .story_item, div.list-truyen-item-wrap").unwrap();
+        let html = self.get_text(url).await?;
+        let doc = Html::parse_document(&html);
+        let mangas = selector(".panel_story_list .story_item, div.list-truyen-item-wrap")?;
         let els = doc.select(&mangas);
 
-        Ok(els
+        let items = els
             .map(|el| {
-                let url = el
-                    .select(&Selector::parse("h3 a").unwrap())
-                    .next()
-                    .expect("no url element");
-                SearchItem {
-                    id: url
-                        .value()
-                        .attr("href")
-                        .unwrap()
-                        .to_string()
-                        .replace("/", " "),
+                let url = select(el, "h3 a")?;
+                Ok(SearchItem {
+                    id: attr(url, "href")?.replace("/", " "),
                     title: url.text().collect(),
                     description: String::from(""),
-                    cover_url: el
-                        .select(&Selector::parse("img").unwrap())
-                        .next()
-                        .expect("no cover element")
-                        .value()
-                        .attr("src")
-                        .unwrap()
-                        .to_string(),
-                }
+                    cover_url: attr(select(el, "img")?, "src")?,
+                })
             })
-            .collect())
+            .collect::<Result<Vec<_>, ConnectorError>>()?;
+
+        Ok(Page {
+            has_next: last_page(&doc)?.map_or(false, |last| page < last),
+            items,
+            // only the page count is known
+            total: None,
+        })
     }
 
-    async fn fetch_manga(&self, id: &str) -> Result<Manga, reqwest::Error> {
+    async fn filter_capabilities(&self) -> Result<FilterCapabilities, ConnectorError> {
+        Ok(FilterCapabilities {
+            tags: GENRES
+                .iter()
+                .map(|(id, name)| Tag {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    group: None,
+                })
+                .collect(),
+            max_tags: Some(1),
+            status: vec![PublicationStatus::Ongoing, PublicationStatus::Completed],
+            sort: vec![
+                SortOrder::LatestUpload,
+                SortOrder::Popular,
+                SortOrder::NewlyAdded,
+            ],
+            filters_with_query: false,
+            ..Default::default()
+        })
+    }
+
+    async fn fetch_manga(&self, id: &str, _languages: &[String]) -> Result<Manga, ConnectorError> {
         let url = id.replace(" ", "/");
         let page = self.get_text(url).await?;
 
         let doc = Html::parse_document(&page);
 
-        let main = Selector::parse("div.manga-info-top, div.panel-story-info").unwrap();
-        let chapters =
-            Selector::parse("div.chapter-list div.row, ul.row-content-chapter li").unwrap();
-
-        let el = doc.select(&main).next().unwrap();
-        let chapters = doc.select(&chapters);
+        let el = select(
+            doc.root_element(),
+            "div.manga-info-top, div.panel-story-info",
+        )?;
+        let chapters = selector("div.chapter-list div.row, ul.row-content-chapter li")?;
+        let chapters = doc.select(&chapters).collect::<Vec<_>>();
+        let count = chapters.len();
 
         Ok(Manga {
             desc: SearchItem {
                 id: id.to_string(),
-                title: el
-                    .select(&Selector::parse("h1, h2").unwrap())
-                    .next()
-                    .unwrap()
-                    .text()
-                    .collect(),
-                description: el
-                    .select(
-                        &Selector::parse("div#noidungm, div#panel-story-info-description").unwrap(),
-                    )
-                    .next()
+                title: select(el, "h1, h2")?.text().collect(),
+                description: select(el, "div#noidungm, div#panel-story-info-description")
                     .map(|e| e.text().collect())
                     .unwrap_or_default(),
-                cover_url: el
-                    .select(
-                        &Selector::parse("div.manga-info-pic img, span.info-image img").unwrap(),
-                    )
-                    .next()
-                    .unwrap()
-                    .attr("src")
-                    .unwrap()
-                    .to_string(),
+                cover_url: attr(
+                    select(el, "div.manga-info-pic img, span.info-image img")?,
+                    "src",
+                )?,
             },
             chapters: chapters
+                .into_iter()
                 .enumerate()
                 .map(|(i, el)| {
-                    let anch = el.select(&Selector::parse("a").unwrap()).next().unwrap();
-                    Chapter {
-                        id: anch.attr("href").unwrap().replace("/", " "),
-                        name: anch.text().collect(),
-                        number: i as f32,
+                    let anch = select(el, "a")?;
+                    let name: String = anch.text().collect();
+                    Ok(Chapter {
+                        id: attr(anch, "href")?.replace("/", " "),
+                        volume: parse_volume(&name),
+                        // the list is newest first, so count down when the
+                        // name has no number
+                        number: parse_number(&name).unwrap_or((count - i) as f32),
+                        name,
+                        // the site only hosts English scans
+                        language: Some("en".to_string()),
+                        scanlation_group: None,
                         read: None,
-                    }
+                    })
                 })
-                .collect(),
+                .collect::<Result<_, ConnectorError>>()?,
         })
     }
 
-    async fn fetch_chapter(&self, id: &str) -> Result<ChapterImages, reqwest::Error> {
+    async fn fetch_chapter_urls(&self, id: &str) -> Result<ChapterImages, ConnectorError> {
         // let url = format!("{}/{}", self.url(), id.replace(" ", "/"));
         let url = id.replace(" ", "/");
 
         let page = self.get_text(url).await?;
         let srcs = {
             let doc = Html::parse_document(&page);
-            let selector =
-                Selector::parse("div#vungdoc img, div.container-chapter-reader img").unwrap();
+            let selector = selector("div#vungdoc img, div.container-chapter-reader img")?;
             let els = doc.select(&selector);
             els.map(|el| el.value().attr("src").unwrap_or_default().to_string())
                 .filter(|src| !src.ends_with("log"))
@@ -196,21 +394,26 @@ impl Connector for MangaKakalot {
         };
 
         Ok(ChapterImages {
-            images: join_all(srcs.into_iter().map(|src| {
-                let modified_url = if src.starts_with("https://convert_image_digi.mgicdn.com") {
-                    format!(
-                        "https://images.weserv.nl/?url={}",
-                        src.split("//").nth(1).unwrap_or_default()
-                    )
-                } else {
-                    src.to_string()
-                };
-                self.fetch_cover_img(modified_url)
-            }))
-            .await
-            .into_iter()
-            .collect::<reqwest::Result<Vec<_>>>()?,
+            images: srcs.into_iter().map(|src| self.proxy_image(src)).collect(),
             format: Format::Normal,
         })
     }
+
+    async fn fetch_image(&self, url: &str) -> Result<Image, ConnectorError> {
+        // cached under the URL asked for, whichever mirror answers
+        self.mirrors
+            .fetch(url, |mirror_url| async move {
+                let request = self.client.get(&mirror_url).headers(self.headers());
+                self.cache.fetch(&self.client, url, request).await
+            })
+            .await
+    }
+
+    fn serves_image(&self, url: &str) -> bool {
+        self.mirrors.serves(url, IMAGE_HOSTS)
+    }
+
+    fn cached_image(&self, url: &str) -> Option<Image> {
+        self.cache.cached(url)
+    }
 }
@@ -0,0 +1,266 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use super::{env_url, ConnectorError, SettingField, Settings};
+
+/// Where a connector sends its requests, from the settings of
+/// `address_settings`.
+#[derive(Clone, Default, PartialEq)]
+pub struct ConnectorConfig {
+    /// Replaces the site's own address, for example with a local server.
+    pub base_url: Option<String>,
+    /// Other addresses of the same site, tried in order when the base URL
+    /// doesn't answer.
+    pub mirrors: Vec<String>,
+    /// Fetches images through this URL, which gets the image's address in
+    /// its `url` query parameter like images.weserv.nl does.
+    pub image_proxy: Option<String>,
+}
+
+impl ConnectorConfig {
+    pub fn from_settings(settings: &Settings) -> Self {
+        ConnectorConfig {
+            base_url: settings.string("base_url").map(str::to_string),
+            mirrors: settings.list("mirrors").to_vec(),
+            image_proxy: settings.string("image_proxy").map(str::to_string),
+        }
+    }
+}
+
+/// The settings of connectors that talk to a site, for pointing them at
+/// other servers.
+pub fn address_settings() -> Vec<SettingField> {
+    vec![
+        SettingField::url(
+            "base_url",
+            "Base URL",
+            "Replaces the site's own address, for example with a local server.",
+        ),
+        SettingField::url_list(
+            "mirrors",
+            "Mirrors",
+            "Other addresses of the site, tried in order when the base URL doesn't answer.",
+        ),
+        SettingField::url(
+            "image_proxy",
+            "Image proxy",
+            "Loads images through this URL, with the image's address in its url parameter.",
+        ),
+    ]
+}
+
+/// Failures another mirror might not have.
+fn is_retryable(e: &ConnectorError) -> bool {
+    match e {
+        ConnectorError::Network(_) => true,
+        ConnectorError::Status { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+/// Whether `url` is on `base`, and not just a longer host name.
+fn strip_base<'a>(url: &'a str, base: &str) -> Option<&'a str> {
+    let rest = url.strip_prefix(base)?;
+    (rest.is_empty() || rest.starts_with(['/', '?'])).then(|| rest)
+}
+
+/// The base URLs of a connector: its own or the configured one, then the
+/// mirrors. Remembers which one answered last and starts from it.
+pub struct Mirrors {
+    /// The site's own address, which IDs holding full URLs point at.
+    default: String,
+    config: RwLock<ConnectorConfig>,
+    current: AtomicUsize,
+}
+
+impl Mirrors {
+    /// `BUNNI_<ID>_URL` replaces `default`, for pointing tests at a mock.
+    pub fn new(connector_id: &str, default: &str) -> Self {
+        let var = format!("BUNNI_{}_URL", connector_id.to_uppercase());
+        Mirrors {
+            default: env_url(&var, default),
+            config: RwLock::new(ConnectorConfig::default()),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts over from the base URL if the addresses changed.
+    pub fn configure(&self, config: &ConnectorConfig) {
+        let mut current = self.config.write().unwrap();
+        if *current != *config {
+            *current = config.clone();
+            self.current.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn bases(&self) -> Vec<String> {
+        let config = self.config.read().unwrap();
+        std::iter::once(config.base_url.as_ref().unwrap_or(&self.default))
+            .chain(&config.mirrors)
+            .map(|url| url.trim_end_matches('/').to_string())
+            .collect()
+    }
+
+    /// Hosts of the site's own and configured addresses.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts = Vec::new();
+        for base in self.bases().iter().chain([&self.default]) {
+            let host = reqwest::Url::parse(base)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string));
+            if let Some(host) = host.filter(|host| !hosts.contains(host)) {
+                hosts.push(host);
+            }
+        }
+        hosts
+    }
+
+    /// The base URL requests go to right now.
+    pub fn base(&self) -> String {
+        let bases = self.bases();
+        let current = self.current.load(Ordering::Relaxed);
+        bases[current % bases.len()].clone()
+    }
+
+    pub fn has_proxy(&self) -> bool {
+        self.config.read().unwrap().image_proxy.is_some()
+    }
+
+    /// `url` through the image proxy, if one is set.
+    pub fn proxy_image(&self, url: &str) -> String {
+        let config = self.config.read().unwrap();
+        let proxied = config.image_proxy.as_ref().and_then(|proxy| {
+            let mut proxied = reqwest::Url::parse(proxy).ok()?;
+            proxied.query_pairs_mut().append_pair("url", url);
+            Some(proxied.to_string())
+        });
+        proxied.unwrap_or_else(|| url.to_string())
+    }
+
+    /// Whether `url` is an http(s) URL on one of the site's addresses, the
+    /// image proxy or `image_hosts` and their subdomains, so `bunni://`
+    /// can't be used to fetch anything else.
+    pub fn serves(&self, url: &str, image_hosts: &[&str]) -> bool {
+        let url = match reqwest::Url::parse(url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => return false,
+        };
+        let config = self.config.read().unwrap();
+        let on_site = self
+            .bases()
+            .iter()
+            .chain([&self.default])
+            .chain(&config.image_proxy)
+            .filter_map(|base| reqwest::Url::parse(base).ok())
+            .any(|base| base.origin() == url.origin());
+        let host = url.host_str().unwrap_or_default();
+        on_site
+            || image_hosts
+                .iter()
+                .any(|h| host == *h || host.strip_suffix(h).map_or(false, |sub| sub.ends_with('.')))
+    }
+
+    /// Runs `request` on `url`, moving on to the next mirror while it fails
+    /// in a way another mirror might not. URLs that aren't on any of the
+    /// site's addresses are only tried as they are.
+    pub async fn fetch<T, F, Fut>(&self, url: &str, request: F) -> Result<T, ConnectorError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<T, ConnectorError>>,
+    {
+        let bases = self.bases();
+        let path = bases
+            .iter()
+            .chain([&self.default])
+            .find_map(|base| strip_base(url, base));
+        let path = match path {
+            Some(path) => path,
+            None => return request(url.to_string()).await,
+        };
+
+        let start = self.current.load(Ordering::Relaxed);
+        let mut error = None;
+        for i in (0..bases.len()).map(|i| (start + i) % bases.len()) {
+            match request(format!("{}{path}", bases[i])).await {
+                Err(e) if is_retryable(&e) => {
+                    println!("{} failed, trying the next mirror: {e}", bases[i]);
+                    error = Some(e);
+                }
+                res => {
+                    self.current.store(i, Ordering::Relaxed);
+                    return res;
+                }
+            }
+        }
+        Err(error.expect("no base URLs"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::SettingValue;
+
+    #[test]
+    fn serves_only_site_urls() {
+        let mirrors = Mirrors::new("test", "https://example.com");
+        mirrors.configure(&ConnectorConfig {
+            base_url: None,
+            mirrors: vec!["http://127.0.0.1:8080/".to_string()],
+            image_proxy: Some("https://proxy.test/?url=".to_string()),
+        });
+        let hosts = ["cdn.test"];
+
+        assert!(mirrors.serves("https://example.com/cover.jpg", &hosts));
+        assert!(mirrors.serves("http://127.0.0.1:8080/page.png", &hosts));
+        assert!(mirrors.serves("https://proxy.test/?url=https%3A%2F%2Fa.test", &hosts));
+        assert!(mirrors.serves("https://cdn.test/1.jpg", &hosts));
+        assert!(mirrors.serves("https://v2.cdn.test/1.jpg", &hosts));
+
+        assert!(!mirrors.serves("https://evilcdn.test/1.jpg", &hosts));
+        assert!(!mirrors.serves("https://example.com.evil.test/", &hosts));
+        assert!(!mirrors.serves("http://127.0.0.1:9000/", &hosts));
+        assert!(!mirrors.serves("http://169.254.169.254/latest/meta-data", &hosts));
+        assert!(!mirrors.serves("file:///etc/passwd", &hosts));
+        assert!(!mirrors.serves("not a url", &hosts));
+    }
+
+    #[test]
+    fn config_from_settings() {
+        let saved = [
+            ("base_url", SettingValue::String("http://localhost:8080".to_string())),
+            ("mirrors", SettingValue::StringList(vec!["ftp://mirror.test".to_string()])),
+            ("image_proxy", SettingValue::String(String::new())),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        let settings = Settings::resolve(&address_settings(), Some(&saved));
+
+        // the mirror isn't http(s), so it's dropped
+        assert!(
+            ConnectorConfig::from_settings(&settings)
+                == ConnectorConfig {
+                    base_url: Some("http://localhost:8080".to_string()),
+                    mirrors: Vec::new(),
+                    image_proxy: None,
+                }
+        );
+    }
+
+    #[test]
+    fn lists_hosts_once() {
+        let mirrors = Mirrors::new("test", "https://example.com");
+        mirrors.configure(&ConnectorConfig {
+            base_url: Some("https://example.com/api".to_string()),
+            mirrors: vec!["http://127.0.0.1:8080".to_string(), "https://mirror.test".to_string()],
+            image_proxy: None,
+        });
+        assert_eq!(mirrors.hosts(), ["example.com", "127.0.0.1", "mirror.test"]);
+    }
+}
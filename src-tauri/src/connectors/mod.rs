@@ -1,15 +1,32 @@
-use std::ops::Index;
+use std::{collections::HashMap, ops::Index, sync::Arc};
 
 use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
 use specta::Type;
 
-use self::{mangadex::MangaDex, mangakakalot::MangaKakalot};
+use self::{local::{LocalConnector, LocalLibrary}, mangadex::MangaDex, mangakakalot::MangaKakalot};
+use crate::{image_cache::ImageCache, protocol};
 
+pub use self::error::{check_status, ConnectorError};
+pub use self::filters::{
+    ContentRating, Demographic, FilterCapabilities, PublicationStatus, SearchFilters, SortOrder,
+    Tag,
+};
+pub use self::http::HttpClient;
+pub use self::mirrors::ConnectorConfig;
+pub use self::settings::{ConnectorSettings, SettingField, SettingValue, Settings};
+
+mod error;
+mod filters;
+mod http;
+pub mod local;
 mod mangadex;
+pub mod mangadex_account;
 mod mangakakalot;
+mod mirrors;
+mod settings;
 
-#[derive(Serialize, Deserialize, Type)]
+#[derive(Serialize, Deserialize, Type, Clone, PartialEq)]
 pub struct SearchItem {
     pub id: String,
     pub title: String,
@@ -17,20 +34,43 @@ pub struct SearchItem {
     pub cover_url: String,
 }
 
-#[derive(Serialize, Deserialize, Type)]
+#[derive(Serialize, Deserialize, Type, Clone, PartialEq)]
 pub struct Chapter {
     pub id: String,
     pub name: String,
     pub number: f32,
+    pub volume: Option<String>,
+    /// ISO 639-1 code, with a region suffix for variants like "pt-br".
+    pub language: Option<String>,
+    pub scanlation_group: Option<String>,
     pub read: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Type)]
+#[derive(Serialize, Deserialize, Type, Clone, PartialEq)]
 pub struct Manga {
     pub desc: SearchItem,
     pub chapters: Vec<Chapter>,
 }
 
+/// One page of results from a paginated endpoint.
+#[derive(Serialize, Deserialize, Type)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub has_next: bool,
+    /// Total number of results, when the source reports it.
+    pub total: Option<u32>,
+}
+
+impl<T> Page<T> {
+    pub fn empty() -> Self {
+        Page {
+            items: Vec::new(),
+            has_next: false,
+            total: Some(0),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Type, Clone, Copy)]
 pub enum Format {
     Normal,
@@ -43,27 +83,125 @@ pub struct ChapterImages {
     pub format: Format
 }
 
+pub struct Image {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
 #[async_trait]
 pub trait Connector: Send + Sync {
+    /// Identifies the connector in everything that's saved, so it has to stay
+    /// the same forever, unlike its position in `Connectors`.
+    fn id(&self) -> &str;
+    /// Bumped when the format of the connector's manga or chapter IDs changes.
+    fn version(&self) -> u32;
     fn name(&self) -> &str;
-    fn url(&self) -> &str;
-    async fn search(&self, query: &str) -> Result<Vec<SearchItem>, reqwest::Error>;
-    async fn fetch_manga(&self, id: &str) -> Result<Manga, reqwest::Error>;
-    async fn fetch_chapter(&self, id: &str) -> Result<ChapterImages, reqwest::Error>;
+    /// The base URL requests go to right now, which changes when a mirror
+    /// takes over.
+    fn url(&self) -> String;
+    /// Searches for manga, `page` counting from zero. `languages` is the
+    /// user's preferred languages, most preferred first, used to pick titles
+    /// and descriptions.
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        languages: &[String],
+        page: u32,
+    ) -> Result<Page<SearchItem>, ConnectorError>;
+    /// Fetches the manga with the chapters translated to any of `languages`.
+    async fn fetch_manga(&self, id: &str, languages: &[String]) -> Result<Manga, ConnectorError>;
+    /// The chapter's pages as the connector's own URLs, which only
+    /// `fetch_image` knows how to load.
+    async fn fetch_chapter_urls(&self, id: &str) -> Result<ChapterImages, ConnectorError>;
+    async fn fetch_image(&self, url: &str) -> Result<Image, ConnectorError>;
+    /// Whether `fetch_image` may load `url` for the webview. Anything not
+    /// on the site or its image hosts is refused.
+    fn serves_image(&self, url: &str) -> bool;
+    /// What `fetch_image` would answer if it can without the network, for
+    /// callers that can't wait on it.
+    fn cached_image(&self, _url: &str) -> Option<Image> {
+        None
+    }
+
+    /// Like `fetch_chapter_urls`, but with `bunni://` URLs the webview can
+    /// load the pages from directly.
+    async fn fetch_chapter(&self, id: &str) -> Result<ChapterImages, ConnectorError> {
+        let mut chapter = self.fetch_chapter_urls(id).await?;
+        for url in &mut chapter.images {
+            *url = protocol::image_url(self.id(), url);
+        }
+        Ok(chapter)
+    }
+
+    /// Which `SearchFilters` this connector understands.
+    async fn filter_capabilities(&self) -> Result<FilterCapabilities, ConnectorError> {
+        Ok(FilterCapabilities::default())
+    }
+
+    /// Options specific to this connector, which the frontend renders from
+    /// the schema alone.
+    fn settings_schema(&self) -> Vec<SettingField> {
+        Vec::new()
+    }
+
+    /// Takes over the user's values for `settings_schema`.
+    fn apply_settings(&self, _settings: &Settings) {}
+}
+
+/// The connectors in the order `Connectors::new` used to create them, back
+/// when data was saved by position.
+const LEGACY_ORDER: &[&str] = &["mangadex", "mangakakalot", "local"];
+
+/// Maps a connector index from before stable IDs to its ID.
+pub fn legacy_id(idx: u32) -> Option<&'static str> {
+    LEGACY_ORDER.get(idx as usize).copied()
+}
+
+/// A base URL from the environment variable `var`, so tests can swap a site
+/// for a local fake. Falls back to `default`.
+pub fn env_url(var: &str, default: &str) -> String {
+    std::env::var(var)
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| default.to_string())
 }
 
 pub struct Connectors(pub Vec<Box<dyn Connector>>);
 
 impl Connectors {
-    pub fn new() -> Self {
-        let client = reqwest::Client::builder()
-            .user_agent("Bunni/0.0.1")
-            .build()
-            .expect("failed to build HTTP client");
-        Self(vec![
-            Box::new(MangaDex::new(client.clone())),
-            Box::new(MangaKakalot::new(client.clone())),
-        ])
+    /// `settings` holds each connector's saved settings by ID.
+    pub fn new(
+        library: LocalLibrary,
+        settings: &HashMap<String, HashMap<String, SettingValue>>,
+        client: HttpClient,
+        cache: Arc<ImageCache>,
+    ) -> Self {
+        let connectors: Vec<Box<dyn Connector>> = vec![
+            Box::new(MangaDex::new(client.clone(), cache.clone())),
+            Box::new(MangaKakalot::new(client.clone(), cache)),
+            Box::new(LocalConnector::new(library)),
+        ];
+        let connectors = Self(connectors);
+        connectors.apply_settings(settings);
+        connectors
+    }
+
+    /// Applies the saved settings by connector ID, defaults for the rest.
+    pub fn apply_settings(&self, settings: &HashMap<String, HashMap<String, SettingValue>>) {
+        for connector in &self.0 {
+            let schema = connector.settings_schema();
+            connector.apply_settings(&Settings::resolve(&schema, settings.get(connector.id())));
+        }
+    }
+
+    /// The index of the connector with this ID.
+    pub fn position(&self, id: &str) -> Option<u32> {
+        self.0.iter().position(|c| c.id() == id).map(|i| i as u32)
+    }
+
+    /// Like indexing, but `None` for an index that's out of range.
+    pub fn get(&self, index: u32) -> Option<&dyn Connector> {
+        self.0.get(index as usize).map(Box::as_ref)
     }
 }
 
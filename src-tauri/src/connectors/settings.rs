@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct SettingOption {
+    pub value: String,
+    pub name: String,
+}
+
+/// What a setting holds, which decides how the frontend shows it.
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub enum SettingKind {
+    Bool,
+    /// One of `options`, by value.
+    Enum { options: Vec<SettingOption> },
+    StringList,
+    /// An http(s) URL, or empty for none.
+    Url,
+    /// http(s) URLs.
+    UrlList,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone, PartialEq)]
+pub enum SettingValue {
+    Bool(bool),
+    Enum(String),
+    StringList(Vec<String>),
+    String(String),
+}
+
+fn is_http_url(url: &str) -> bool {
+    matches!(reqwest::Url::parse(url), Ok(url) if matches!(url.scheme(), "http" | "https"))
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct SettingField {
+    /// Identifies the setting in the saved values.
+    pub key: String,
+    pub name: String,
+    pub description: String,
+    pub kind: SettingKind,
+    pub default: SettingValue,
+}
+
+impl SettingField {
+    pub fn bool(key: &str, name: &str, description: &str, default: bool) -> Self {
+        SettingField {
+            key: key.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            kind: SettingKind::Bool,
+            default: SettingValue::Bool(default),
+        }
+    }
+
+    /// `options` are `(value, name)` pairs.
+    pub fn choice(
+        key: &str,
+        name: &str,
+        description: &str,
+        options: &[(&str, &str)],
+        default: &str,
+    ) -> Self {
+        SettingField {
+            key: key.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            kind: SettingKind::Enum {
+                options: options
+                    .iter()
+                    .map(|(value, name)| SettingOption {
+                        value: value.to_string(),
+                        name: name.to_string(),
+                    })
+                    .collect(),
+            },
+            default: SettingValue::Enum(default.to_string()),
+        }
+    }
+
+    /// Empty by default.
+    pub fn string_list(key: &str, name: &str, description: &str) -> Self {
+        SettingField {
+            key: key.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            kind: SettingKind::StringList,
+            default: SettingValue::StringList(Vec::new()),
+        }
+    }
+
+    /// Empty by default.
+    pub fn url(key: &str, name: &str, description: &str) -> Self {
+        SettingField {
+            key: key.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            kind: SettingKind::Url,
+            default: SettingValue::String(String::new()),
+        }
+    }
+
+    /// Empty by default.
+    pub fn url_list(key: &str, name: &str, description: &str) -> Self {
+        SettingField {
+            key: key.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            kind: SettingKind::UrlList,
+            default: SettingValue::StringList(Vec::new()),
+        }
+    }
+
+    /// Whether `value` is of this field's kind, and one of its options for
+    /// an enum or made of http(s) URLs for a URL.
+    pub fn accepts(&self, value: &SettingValue) -> bool {
+        match (&self.kind, value) {
+            (SettingKind::Bool, SettingValue::Bool(_)) => true,
+            (SettingKind::Enum { options }, SettingValue::Enum(value)) => {
+                options.iter().any(|o| o.value == *value)
+            }
+            (SettingKind::StringList, SettingValue::StringList(_)) => true,
+            (SettingKind::Url, SettingValue::String(url)) => url.is_empty() || is_http_url(url),
+            (SettingKind::UrlList, SettingValue::StringList(urls)) => {
+                urls.iter().all(|url| is_http_url(url))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A connector's settings and their current values, for the settings page.
+#[derive(Serialize, Type)]
+pub struct ConnectorSettings {
+    pub fields: Vec<SettingField>,
+    pub values: HashMap<String, SettingValue>,
+}
+
+/// Values for every field of a schema. Saved values that no longer fit
+/// their field, say after an option was removed, fall back to the default.
+#[derive(Clone, Default)]
+pub struct Settings(HashMap<String, SettingValue>);
+
+impl Settings {
+    pub fn resolve(schema: &[SettingField], saved: Option<&HashMap<String, SettingValue>>) -> Self {
+        Settings(
+            schema
+                .iter()
+                .map(|field| {
+                    let value = saved
+                        .and_then(|saved| saved.get(&field.key))
+                        .filter(|value| field.accepts(value))
+                        .unwrap_or(&field.default);
+                    (field.key.clone(), value.clone())
+                })
+                .collect(),
+        )
+    }
+
+    pub fn values(&self) -> &HashMap<String, SettingValue> {
+        &self.0
+    }
+
+    pub fn bool(&self, key: &str) -> bool {
+        matches!(self.0.get(key), Some(SettingValue::Bool(true)))
+    }
+
+    pub fn choice(&self, key: &str) -> &str {
+        match self.0.get(key) {
+            Some(SettingValue::Enum(value)) => value,
+            _ => "",
+        }
+    }
+
+    pub fn list(&self, key: &str) -> &[String] {
+        match self.0.get(key) {
+            Some(SettingValue::StringList(values)) => values,
+            _ => &[],
+        }
+    }
+
+    /// `None` when the string is empty.
+    pub fn string(&self, key: &str) -> Option<&str> {
+        match self.0.get(key) {
+            Some(SettingValue::String(value)) if !value.is_empty() => Some(value),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::{categories, library, trackers, Database, TrackerLink};
+use crate::{connectors::Format, progress::ChapterProgress, updates::now_millis};
+
+/// How a backup is combined with the library that's already there.
+#[derive(Serialize, Deserialize, Type, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    /// Keeps everything and adds what the backup has on top: the union of
+    /// liked manga, categories, read chapters and tracker links, and
+    /// whichever progress was updated last.
+    Merge,
+    /// Throws away the current library first.
+    Replace,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupManga {
+    pub connector: String,
+    pub manga_id: String,
+    pub added_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupCategory {
+    pub name: String,
+    /// `(connector, manga_id)` pairs in the category's order.
+    #[serde(default)]
+    pub manga: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupView {
+    pub connector: String,
+    pub manga_id: String,
+    pub format: Format,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupChapter {
+    pub connector: String,
+    pub chapter_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupProgress {
+    pub connector: String,
+    pub chapter_id: String,
+    #[serde(flatten)]
+    pub progress: ChapterProgress,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupTrackerLink {
+    pub connector: String,
+    pub manga_id: String,
+    #[serde(flatten)]
+    pub link: TrackerLink,
+}
+
+/// Everything in the database worth moving to another machine, keyed by
+/// connector ID so it doesn't depend on the connectors this build has.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct LibraryBackup {
+    pub liked: Vec<BackupManga>,
+    /// In the user's order.
+    pub categories: Vec<BackupCategory>,
+    pub views: Vec<BackupView>,
+    pub read: Vec<BackupChapter>,
+    pub progress: Vec<BackupProgress>,
+    pub tracker_links: Vec<BackupTrackerLink>,
+}
+
+fn query<T>(
+    conn: &Connection,
+    sql: &str,
+    f: impl FnMut(&rusqlite::Row) -> rusqlite::Result<T>,
+) -> rusqlite::Result<Vec<T>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map([], f)?;
+    rows.collect()
+}
+
+fn clear(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "DELETE FROM category_manga;
+         DELETE FROM categories;
+         DELETE FROM known_chapters;
+         DELETE FROM liked;
+         DELETE FROM views;
+         DELETE FROM read_chapters;
+         DELETE FROM progress;
+         DELETE FROM tracker_links;",
+    )
+}
+
+impl Database {
+    pub fn backup(&self) -> rusqlite::Result<LibraryBackup> {
+        let conn = self.conn.lock().unwrap();
+        let liked = query(
+            &conn,
+            "SELECT connector, manga_id, added_at FROM liked ORDER BY added_at, rowid",
+            |row| {
+                Ok(BackupManga {
+                    connector: row.get(0)?,
+                    manga_id: row.get(1)?,
+                    added_at: row.get(2)?,
+                })
+            },
+        )?;
+
+        let mut categories = query(
+            &conn,
+            "SELECT id, name FROM categories ORDER BY position, id",
+            |row| Ok((row.get::<_, u32>(0)?, row.get(1)?)),
+        )?
+        .into_iter()
+        .map(|(id, name)| (id, BackupCategory { name, manga: Vec::new() }))
+        .collect::<Vec<_>>();
+        for (id, category) in &mut categories {
+            let mut stmt = conn.prepare_cached(
+                "SELECT connector, manga_id FROM category_manga
+                 WHERE category_id = ?1 ORDER BY position, rowid",
+            )?;
+            let rows = stmt.query_map([*id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            category.manga = rows.collect::<rusqlite::Result<_>>()?;
+        }
+
+        let views = query(&conn, "SELECT connector, manga_id, format FROM views", |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+        })?
+        .into_iter()
+        .filter_map(|(connector, manga_id, format)| {
+            Some(BackupView {
+                connector,
+                manga_id,
+                format: library::parse_format(&format)?,
+            })
+        })
+        .collect();
+
+        let read = query(&conn, "SELECT connector, chapter_id FROM read_chapters", |row| {
+            Ok(BackupChapter {
+                connector: row.get(0)?,
+                chapter_id: row.get(1)?,
+            })
+        })?;
+
+        let progress = query(
+            &conn,
+            "SELECT connector, chapter_id, page, total, updated_at, completed FROM progress",
+            |row| {
+                Ok(BackupProgress {
+                    connector: row.get(0)?,
+                    chapter_id: row.get(1)?,
+                    progress: ChapterProgress {
+                        page: row.get(2)?,
+                        total: row.get(3)?,
+                        updated_at: row.get(4)?,
+                        completed: row.get(5)?,
+                    },
+                })
+            },
+        )?;
+
+        let tracker_links = trackers::all_links(&conn)?
+            .into_iter()
+            .map(|(connector, manga_id, link)| BackupTrackerLink {
+                connector,
+                manga_id,
+                link,
+            })
+            .collect();
+
+        Ok(LibraryBackup {
+            liked,
+            categories: categories.into_iter().map(|(_, c)| c).collect(),
+            views,
+            read,
+            progress,
+            tracker_links,
+        })
+    }
+
+    /// Restores a backup in a single transaction, so a failed import leaves
+    /// the library as it was.
+    pub fn restore(&self, backup: &LibraryBackup, mode: ImportMode) -> rusqlite::Result<()> {
+        self.transaction(|tx| {
+            if mode == ImportMode::Replace {
+                clear(tx)?;
+            }
+
+            for manga in &backup.liked {
+                library::insert_liked(tx, &manga.connector, &manga.manga_id, manga.added_at)?;
+            }
+
+            let now = now_millis();
+            for category in &backup.categories {
+                let existing: Option<u32> = tx
+                    .query_row(
+                        "SELECT id FROM categories WHERE name = ?1",
+                        [&category.name],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let id = match existing {
+                    Some(id) => id,
+                    None => categories::insert_category(tx, &category.name)?,
+                };
+                for (connector, manga_id) in &category.manga {
+                    // categories only hold liked manga, this one wasn't liked
+                    // when the backup was made
+                    library::insert_liked(tx, connector, manga_id, now)?;
+                    categories::add_to_category(tx, id, connector, manga_id)?;
+                }
+            }
+
+            for view in &backup.views {
+                match mode {
+                    ImportMode::Merge => {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO views (connector, manga_id, format)
+                             VALUES (?1, ?2, ?3)",
+                            params![view.connector, view.manga_id, library::format_name(view.format)],
+                        )?;
+                    }
+                    ImportMode::Replace => {
+                        library::insert_view(tx, &view.connector, &view.manga_id, view.format)?
+                    }
+                }
+            }
+
+            for chapter in &backup.read {
+                library::insert_read(tx, &chapter.connector, &chapter.chapter_id)?;
+            }
+
+            for p in &backup.progress {
+                let current = library::get_progress(tx, &p.connector, &p.chapter_id)?;
+                if current.map_or(true, |c| c.updated_at < p.progress.updated_at) {
+                    library::upsert_progress(tx, &p.connector, &p.chapter_id, &p.progress)?;
+                }
+            }
+
+            for l in &backup.tracker_links {
+                let replace = mode == ImportMode::Replace;
+                trackers::insert_link(tx, &l.connector, &l.manga_id, &l.link, replace)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manga(manga_id: &str, added_at: u64) -> BackupManga {
+        BackupManga {
+            connector: "test".to_string(),
+            manga_id: manga_id.to_string(),
+            added_at,
+        }
+    }
+
+    fn progress(chapter_id: &str, page: u32, updated_at: u64) -> BackupProgress {
+        BackupProgress {
+            connector: "test".to_string(),
+            chapter_id: chapter_id.to_string(),
+            progress: ChapterProgress {
+                page,
+                total: 20,
+                updated_at,
+                completed: false,
+            },
+        }
+    }
+
+    fn backup() -> LibraryBackup {
+        LibraryBackup {
+            liked: vec![manga("b", 5)],
+            categories: vec![BackupCategory {
+                name: "Reading".to_string(),
+                manga: vec![
+                    ("test".to_string(), "b".to_string()),
+                    ("test".to_string(), "c".to_string()),
+                ],
+            }],
+            read: vec![BackupChapter {
+                connector: "test".to_string(),
+                chapter_id: "b1".to_string(),
+            }],
+            progress: vec![progress("a1", 9, 1), progress("b1", 4, 1)],
+            ..Default::default()
+        }
+    }
+
+    /// A library with "a" liked, read and further along in "a1" than the
+    /// backup.
+    fn library() -> Database {
+        let db = Database::in_memory(&["test"]);
+        db.toggle_liked(0, "a").unwrap();
+        db.mark_read(0, "a", "a0").unwrap();
+        db.set_progress(0, "a", "a1", 2, 20).unwrap();
+        db
+    }
+
+    fn liked(db: &Database) -> Vec<String> {
+        db.liked().unwrap().into_iter().map(|m| m.manga_id).collect()
+    }
+
+    fn added_at(db: &Database, manga_id: &str) -> u64 {
+        db.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT added_at FROM liked WHERE manga_id = ?1",
+                [manga_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn merge_keeps_library() {
+        let db = library();
+        let before = now_millis();
+        db.restore(&backup(), ImportMode::Merge).unwrap();
+
+        let mut ids = liked(&db);
+        ids.sort();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(added_at(&db, "b"), 5);
+        assert!(added_at(&db, "c") >= before);
+
+        let read = ["a0", "b1"].map(String::from);
+        assert_eq!(db.read_chapters(0, &read).unwrap().len(), 2);
+        // ours was updated after the backup's
+        assert_eq!(db.progress(0, "a1").unwrap().unwrap().page, 2);
+        assert_eq!(db.progress(0, "b1").unwrap().unwrap().page, 4);
+
+        let reading = db.categories().unwrap().into_iter().find(|c| c.name == "Reading");
+        assert_eq!(reading.unwrap().manga_count, 2);
+    }
+
+    #[test]
+    fn replace_drops_library() {
+        let db = library();
+        db.restore(&backup(), ImportMode::Replace).unwrap();
+
+        let mut ids = liked(&db);
+        ids.sort();
+        assert_eq!(ids, ["b", "c"]);
+        let read = ["a0", "b1"].map(String::from);
+        assert_eq!(db.read_chapters(0, &read).unwrap().len(), 1);
+        assert_eq!(db.progress(0, "a1").unwrap().unwrap().page, 9);
+    }
+}
@@ -0,0 +1,199 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::Database;
+use crate::prefs::StoredManga;
+
+/// A user defined group of liked manga, like "Reading" or "Dropped". A manga
+/// can be in any number of them.
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct Category {
+    pub id: u32,
+    pub name: String,
+    pub manga_count: u32,
+}
+
+fn next_position(conn: &Connection, category_id: u32) -> rusqlite::Result<u32> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(position) + 1, 0) FROM category_manga WHERE category_id = ?1",
+        [category_id],
+        |row| row.get(0),
+    )
+}
+
+pub(super) fn insert_category(conn: &Connection, name: &str) -> rusqlite::Result<u32> {
+    conn.execute(
+        "INSERT INTO categories (name, position)
+         VALUES (?1, (SELECT COALESCE(MAX(position) + 1, 0) FROM categories))",
+        [name],
+    )?;
+    Ok(conn.last_insert_rowid() as u32)
+}
+
+/// Adds the manga to the end of the category, if it isn't in it already.
+pub(super) fn add_to_category(
+    conn: &Connection,
+    category_id: u32,
+    connector: &str,
+    manga_id: &str,
+) -> rusqlite::Result<()> {
+    let position = next_position(conn, category_id)?;
+    conn.execute(
+        "INSERT OR IGNORE INTO category_manga (category_id, connector, manga_id, position)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![category_id, connector, manga_id, position],
+    )?;
+    Ok(())
+}
+
+impl Database {
+    /// All categories in the user's order.
+    pub fn categories(&self) -> rusqlite::Result<Vec<Category>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.name, COUNT(m.manga_id) FROM categories c
+             LEFT JOIN category_manga m ON m.category_id = c.id
+             GROUP BY c.id ORDER BY c.position, c.id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Category {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                manga_count: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Adds a category after the existing ones. Names have to be unique.
+    pub fn create_category(&self, name: &str) -> rusqlite::Result<Category> {
+        let id = insert_category(&self.conn.lock().unwrap(), name)?;
+        Ok(Category {
+            id,
+            name: name.to_string(),
+            manga_count: 0,
+        })
+    }
+
+    /// Returns whether there was a category with that ID.
+    pub fn rename_category(&self, id: u32, name: &str) -> rusqlite::Result<bool> {
+        let renamed = self.conn.lock().unwrap().execute(
+            "UPDATE categories SET name = ?2 WHERE id = ?1",
+            params![id, name],
+        )?;
+        Ok(renamed > 0)
+    }
+
+    /// Deletes the category. Its manga stay liked.
+    pub fn delete_category(&self, id: u32) -> rusqlite::Result<bool> {
+        let deleted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM categories WHERE id = ?1", [id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Puts the categories in the order of `ids`. Categories left out keep
+    /// their relative order after the given ones.
+    pub fn reorder_categories(&self, ids: &[u32]) -> rusqlite::Result<()> {
+        self.transaction(|tx| {
+            let count = ids.len() as u32;
+            tx.execute("UPDATE categories SET position = position + ?1", [count])?;
+            let mut stmt = tx.prepare("UPDATE categories SET position = ?2 WHERE id = ?1")?;
+            for (position, id) in ids.iter().enumerate() {
+                stmt.execute(params![id, position as u32])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// IDs of the categories the manga is in.
+    pub fn manga_categories(&self, connector_idx: u32, manga_id: &str) -> rusqlite::Result<Vec<u32>> {
+        let connector = self.connector_id(connector_idx);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT m.category_id FROM category_manga m
+             JOIN categories c ON c.id = m.category_id
+             WHERE m.connector = ?1 AND m.manga_id = ?2 ORDER BY c.position",
+        )?;
+        let rows = stmt.query_map(params![connector, manga_id], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Puts a liked manga in exactly the given categories. It's added to the
+    /// end of the ones it wasn't in before.
+    pub fn set_manga_categories(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        category_ids: &[u32],
+    ) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        self.transaction(|tx| {
+            let current = {
+                let mut stmt = tx.prepare(
+                    "SELECT category_id FROM category_manga WHERE connector = ?1 AND manga_id = ?2",
+                )?;
+                let rows = stmt.query_map(params![connector, manga_id], |row| row.get(0))?;
+                rows.collect::<rusqlite::Result<Vec<u32>>>()?
+            };
+            for id in current.iter().filter(|id| !category_ids.contains(id)) {
+                tx.execute(
+                    "DELETE FROM category_manga
+                     WHERE category_id = ?1 AND connector = ?2 AND manga_id = ?3",
+                    params![id, connector, manga_id],
+                )?;
+            }
+            for id in category_ids.iter().filter(|id| !current.contains(id)) {
+                add_to_category(tx, *id, connector, manga_id)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The manga in a category in the user's order.
+    pub fn category_manga(&self, category_id: u32) -> rusqlite::Result<Vec<StoredManga>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT connector, manga_id FROM category_manga
+             WHERE category_id = ?1 ORDER BY position, rowid",
+        )?;
+        let rows = stmt.query_map([category_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?))
+        })?;
+        let mut manga = Vec::new();
+        for row in rows {
+            let (connector, manga_id) = row?;
+            if let Some(connector_idx) = self.connector_idx(&connector) {
+                manga.push(StoredManga {
+                    connector_idx,
+                    manga_id,
+                });
+            }
+        }
+        Ok(manga)
+    }
+
+    /// Puts the category's manga in the given order. Manga left out keep
+    /// their relative order after the given ones.
+    pub fn reorder_category(&self, category_id: u32, manga: &[StoredManga]) -> rusqlite::Result<()> {
+        self.transaction(|tx| {
+            let count = manga.len() as u32;
+            tx.execute(
+                "UPDATE category_manga SET position = position + ?2 WHERE category_id = ?1",
+                params![category_id, count],
+            )?;
+            let mut stmt = tx.prepare(
+                "UPDATE category_manga SET position = ?4
+                 WHERE category_id = ?1 AND connector = ?2 AND manga_id = ?3",
+            )?;
+            for (position, m) in manga.iter().enumerate() {
+                let connector = self.connector_id(m.connector_idx);
+                stmt.execute(params![category_id, connector, m.manga_id, position as u32])?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,166 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::Database;
+use crate::updates::now_millis;
+
+/// An open chapter within this long of the last one counts as the same
+/// reading session.
+const SESSION_GAP: u64 = 30 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct HistoryEntry {
+    pub id: u32,
+    pub connector_idx: u32,
+    pub manga_id: String,
+    pub chapter_id: String,
+    /// Last page seen in this session.
+    pub page: u32,
+    /// Milliseconds since the epoch.
+    pub opened_at: u64,
+    /// Milliseconds between opening the chapter and the last page turn.
+    pub duration: u64,
+}
+
+impl HistoryEntry {
+    fn from_row(row: &Row, connector_idx: u32) -> rusqlite::Result<Self> {
+        Ok(HistoryEntry {
+            id: row.get(0)?,
+            connector_idx,
+            manga_id: row.get(2)?,
+            chapter_id: row.get(3)?,
+            page: row.get(4)?,
+            opened_at: row.get(5)?,
+            duration: row.get(6)?,
+        })
+    }
+}
+
+pub(super) fn insert(
+    conn: &Connection,
+    connector: &str,
+    manga_id: &str,
+    chapter_id: &str,
+    page: u32,
+    opened_at: u64,
+    duration: u64,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO history (connector, manga_id, chapter_id, page, opened_at, duration)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![connector, manga_id, chapter_id, page, opened_at, duration],
+    )?;
+    Ok(())
+}
+
+/// Updates the chapter's current session, or starts one if the chapter
+/// wasn't opened recently.
+pub(super) fn touch(
+    conn: &Connection,
+    connector: &str,
+    manga_id: &str,
+    chapter_id: &str,
+    page: u32,
+) -> rusqlite::Result<()> {
+    let now = now_millis();
+    let current: Option<(u32, u64)> = conn
+        .query_row(
+            "SELECT id, opened_at FROM history
+             WHERE connector = ?1 AND chapter_id = ?2 AND opened_at + duration > ?3
+             ORDER BY opened_at DESC LIMIT 1",
+            params![connector, chapter_id, now.saturating_sub(SESSION_GAP)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    match current {
+        Some((id, opened_at)) => {
+            conn.execute(
+                "UPDATE history SET page = ?2, duration = ?3 WHERE id = ?1",
+                params![id, page, now.saturating_sub(opened_at)],
+            )?;
+            Ok(())
+        }
+        None => insert(conn, connector, manga_id, chapter_id, page, now, 0),
+    }
+}
+
+impl Database {
+    /// Starts a reading session for the chapter, or picks up the current
+    /// one, so fetching the chapter again doesn't add another entry.
+    pub fn open_chapter(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        chapter_id: &str,
+        page: u32,
+    ) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        let conn = self.conn.lock().unwrap();
+        touch(&conn, connector, manga_id, chapter_id, page)
+    }
+
+    /// Entries opened in `[from, to)` for the given manga, newest first.
+    /// `None` leaves that filter out. Entries from connectors that no longer
+    /// exist are left out too.
+    pub fn history(
+        &self,
+        from: Option<u64>,
+        to: Option<u64>,
+        connector_idx: Option<u32>,
+        manga_id: Option<&str>,
+    ) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let connector = connector_idx.map(|idx| self.connector_id(idx));
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, connector, manga_id, chapter_id, page, opened_at, duration
+             FROM history
+             WHERE (?1 IS NULL OR opened_at >= ?1)
+               AND (?2 IS NULL OR opened_at < ?2)
+               AND (?3 IS NULL OR connector = ?3)
+               AND (?4 IS NULL OR manga_id = ?4)
+             ORDER BY opened_at DESC, id DESC",
+        )?;
+        let rows = stmt.query_map(params![from, to, connector, manga_id], |row| {
+            let connector: String = row.get(1)?;
+            self.connector_idx(&connector)
+                .map(|idx| HistoryEntry::from_row(row, idx))
+                .transpose()
+        })?;
+        rows.filter_map(Result::transpose).collect()
+    }
+
+    /// Returns whether there was an entry with that ID.
+    pub fn delete_history_entry(&self, id: u32) -> rusqlite::Result<bool> {
+        let deleted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM history WHERE id = ?1", [id])?;
+        Ok(deleted > 0)
+    }
+
+    pub fn clear_history(&self) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute("DELETE FROM history", [])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reopening_continues_session() {
+        let db = Database::in_memory(&["test"]);
+        db.open_chapter(0, "manga", "ch1", 0).unwrap();
+        db.set_progress(0, "manga", "ch1", 4, 20).unwrap();
+        db.open_chapter(0, "manga", "ch1", 4).unwrap();
+        db.open_chapter(0, "manga", "ch2", 0).unwrap();
+
+        let history = db.history(None, None, None, None).unwrap();
+        let chapters = history.iter().map(|e| e.chapter_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(chapters, ["ch2", "ch1"]);
+        assert_eq!(history[1].page, 4);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use rusqlite::params;
+use serde::Deserialize;
+
+use super::{history, library, Database, HistoryEntry};
+use crate::{
+    connectors::{legacy_id, Format},
+    prefs::StoredManga,
+    progress::ChapterProgress,
+    updates::ChapterUpdate,
+};
+
+const IMPORTED_KEY: &str = "userdata_imported";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyHistory {
+    entries: Vec<HistoryEntry>,
+}
+
+/// The library part of `userdata.json` from before the database, keyed by
+/// the connectors' old positions.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyData {
+    liked: Vec<StoredManga>,
+    views: HashMap<u32, HashMap<String, Format>>,
+    read: HashMap<u32, HashSet<String>>,
+    progress: HashMap<u32, HashMap<String, ChapterProgress>>,
+    known_chapters: HashMap<u32, HashMap<String, HashSet<String>>>,
+    updates: Vec<ChapterUpdate>,
+    history: LegacyHistory,
+}
+
+/// Pairs up the entries of a map keyed by old connector position with the
+/// connector's ID.
+fn by_connector<T>(map: &HashMap<u32, T>) -> impl Iterator<Item = (&'static str, &T)> {
+    map.iter()
+        .filter_map(|(idx, value)| Some((legacy_id(*idx)?, value)))
+}
+
+/// Moves the library out of `userdata.json` into the database, once. The
+/// file is copied to `userdata.json.pre-sqlite` as is and imported from
+/// there, since loading the settings may move a corrupt file aside and
+/// saving them drops everything that was imported. Returns whether
+/// anything was imported.
+///
+/// Runs before the settings are loaded. Nothing is marked imported unless
+/// the copy could be read, so a failed import is retried next time.
+pub fn import_userdata(db: &Database, path: &Path) -> Result<bool, String> {
+    if db.get_meta(IMPORTED_KEY).map_err(|e| e.to_string())?.is_some() {
+        return Ok(false);
+    }
+
+    let copy = path.with_extension("json.pre-sqlite");
+    // one left by an earlier attempt is the untouched original
+    if !copy.exists() {
+        match fs::copy(path, &copy) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("failed to copy {}: {e}", path.display())),
+        }
+    }
+    let data: Option<LegacyData> = match File::open(&copy) {
+        Ok(file) => Some(
+            serde_json::from_reader(io::BufReader::new(file)).map_err(|e| {
+                format!(
+                    "failed to import the library from {}, fix or remove it to continue: {e}",
+                    copy.display()
+                )
+            })?,
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("failed to read {}: {e}", copy.display())),
+    };
+
+    db.transaction(|tx| {
+        if let Some(data) = &data {
+            // keep the order things were liked in
+            for (i, manga) in data.liked.iter().enumerate() {
+                if let Some(connector) = legacy_id(manga.connector_idx) {
+                    library::insert_liked(tx, connector, &manga.manga_id, i as u64)?;
+                }
+            }
+            for (connector, views) in by_connector(&data.views) {
+                for (manga_id, format) in views {
+                    library::insert_view(tx, connector, manga_id, *format)?;
+                }
+            }
+            for (connector, read) in by_connector(&data.read) {
+                for chapter_id in read {
+                    library::insert_read(tx, connector, chapter_id)?;
+                }
+            }
+            for (connector, progress) in by_connector(&data.progress) {
+                for (chapter_id, progress) in progress {
+                    library::upsert_progress(tx, connector, chapter_id, progress)?;
+                }
+            }
+            for (connector, known) in by_connector(&data.known_chapters) {
+                for (manga_id, chapters) in known {
+                    library::insert_known_chapters(tx, connector, manga_id, chapters)?;
+                    tx.execute(
+                        "UPDATE liked SET checked_at = 0 WHERE connector = ?1 AND manga_id = ?2",
+                        params![connector, manga_id],
+                    )?;
+                }
+            }
+            for update in &data.updates {
+                if let Some(connector) = legacy_id(update.connector_idx) {
+                    library::insert_update(tx, connector, update)?;
+                }
+            }
+            for e in &data.history.entries {
+                let connector = match legacy_id(e.connector_idx) {
+                    Some(connector) => connector,
+                    None => continue,
+                };
+                history::insert(
+                    tx,
+                    connector,
+                    &e.manga_id,
+                    &e.chapter_id,
+                    e.page,
+                    e.opened_at,
+                    e.duration,
+                )?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES (?1, '1')",
+            [IMPORTED_KEY],
+        )?;
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+
+    Ok(data.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTORS: &[&str] = &["mangadex", "mangakakalot", "local"];
+
+    #[test]
+    fn imports_once_from_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("userdata.json");
+        let json = r#"{"liked":[{"connector_idx":0,"manga_id":"a"}],"read":{"0":["c1"]}}"#;
+        fs::write(&path, json).unwrap();
+        let db = Database::in_memory(CONNECTORS);
+
+        assert_eq!(import_userdata(&db, &path), Ok(true));
+        assert_eq!(fs::read_to_string(path.with_extension("json.pre-sqlite")).unwrap(), json);
+        let liked = db.liked().unwrap();
+        assert_eq!(liked.len(), 1);
+        assert_eq!(liked[0].manga_id, "a");
+        assert!(db.read_chapters(0, &["c1".to_string()]).unwrap().contains("c1"));
+
+        assert_eq!(import_userdata(&db, &path), Ok(false));
+    }
+
+    #[test]
+    fn corrupt_file_is_kept_and_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("userdata.json");
+        fs::write(&path, r#"{"liked":[{"connector_idx":0,"#).unwrap();
+        let db = Database::in_memory(CONNECTORS);
+
+        assert!(import_userdata(&db, &path).is_err());
+        // loading the settings moves the corrupt file aside
+        fs::remove_file(&path).unwrap();
+        assert!(import_userdata(&db, &path).is_err());
+        assert_eq!(db.get_meta(IMPORTED_KEY).unwrap(), None);
+
+        let json = r#"{"liked":[{"connector_idx":0,"manga_id":"a"}]}"#;
+        fs::write(path.with_extension("json.pre-sqlite"), json).unwrap();
+        assert_eq!(import_userdata(&db, &path), Ok(true));
+        assert_eq!(db.liked().unwrap().len(), 1);
+    }
+}
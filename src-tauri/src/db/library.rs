@@ -0,0 +1,504 @@
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::{params, types::Type, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{history, Database};
+use crate::{
+    connectors::{Format, Manga},
+    prefs::StoredManga,
+    progress::ChapterProgress,
+    updates::{now_millis, ChapterUpdate},
+};
+
+fn to_json(value: &impl Serialize) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))
+}
+
+fn from_json<T: DeserializeOwned>(column: usize, json: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, e.into()))
+}
+
+pub(super) fn format_name(format: Format) -> &'static str {
+    match format {
+        Format::Normal => "Normal",
+        Format::Long => "Long",
+    }
+}
+
+pub(super) fn parse_format(name: &str) -> Option<Format> {
+    match name {
+        "Normal" => Some(Format::Normal),
+        "Long" => Some(Format::Long),
+        _ => None,
+    }
+}
+
+pub(super) fn insert_liked(
+    conn: &Connection,
+    connector: &str,
+    manga_id: &str,
+    added_at: u64,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO liked (connector, manga_id, added_at) VALUES (?1, ?2, ?3)",
+        params![connector, manga_id, added_at],
+    )?;
+    Ok(())
+}
+
+pub(super) fn insert_view(
+    conn: &Connection,
+    connector: &str,
+    manga_id: &str,
+    format: Format,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO views (connector, manga_id, format) VALUES (?1, ?2, ?3)",
+        params![connector, manga_id, format_name(format)],
+    )?;
+    Ok(())
+}
+
+pub(super) fn insert_read(conn: &Connection, connector: &str, chapter_id: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO read_chapters (connector, chapter_id) VALUES (?1, ?2)",
+        params![connector, chapter_id],
+    )?;
+    Ok(())
+}
+
+pub(super) fn upsert_progress(
+    conn: &Connection,
+    connector: &str,
+    chapter_id: &str,
+    progress: &ChapterProgress,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO progress
+            (connector, chapter_id, page, total, updated_at, completed)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            connector,
+            chapter_id,
+            progress.page,
+            progress.total,
+            progress.updated_at,
+            progress.completed
+        ],
+    )?;
+    Ok(())
+}
+
+pub(super) fn get_progress(
+    conn: &Connection,
+    connector: &str,
+    chapter_id: &str,
+) -> rusqlite::Result<Option<ChapterProgress>> {
+    conn.prepare_cached(
+        "SELECT page, total, updated_at, completed FROM progress
+         WHERE connector = ?1 AND chapter_id = ?2",
+    )?
+    .query_row(params![connector, chapter_id], |row| {
+        Ok(ChapterProgress {
+            page: row.get(0)?,
+            total: row.get(1)?,
+            updated_at: row.get(2)?,
+            completed: row.get(3)?,
+        })
+    })
+    .optional()
+}
+
+pub(super) fn insert_known_chapters<'a>(
+    conn: &Connection,
+    connector: &str,
+    manga_id: &str,
+    chapter_ids: impl IntoIterator<Item = &'a String>,
+) -> rusqlite::Result<()> {
+    let mut insert = conn.prepare_cached(
+        "INSERT OR IGNORE INTO known_chapters (connector, manga_id, chapter_id)
+         VALUES (?1, ?2, ?3)",
+    )?;
+    for id in chapter_ids {
+        insert.execute(params![connector, manga_id, id])?;
+    }
+    Ok(())
+}
+
+pub(super) fn insert_update(
+    conn: &Connection,
+    connector: &str,
+    update: &ChapterUpdate,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO updates (connector, manga_id, manga_title, cover_url, chapter, found_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            connector,
+            update.manga_id,
+            update.manga_title,
+            update.cover_url,
+            to_json(&update.chapter)?,
+            update.found_at
+        ],
+    )?;
+    Ok(())
+}
+
+impl Database {
+    /// Liked manga in the order they were liked, leaving out those from
+    /// connectors that no longer exist.
+    pub fn liked(&self) -> rusqlite::Result<Vec<StoredManga>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT connector, manga_id FROM liked ORDER BY added_at, rowid")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?))
+        })?;
+        let mut liked = Vec::new();
+        for row in rows {
+            let (connector, manga_id) = row?;
+            if let Some(connector_idx) = self.connector_idx(&connector) {
+                liked.push(StoredManga {
+                    connector_idx,
+                    manga_id,
+                });
+            }
+        }
+        Ok(liked)
+    }
+
+    pub fn is_liked(&self, connector_idx: u32, manga_id: &str) -> rusqlite::Result<bool> {
+        let connector = self.connector_id(connector_idx);
+        self.conn.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM liked WHERE connector = ?1 AND manga_id = ?2)",
+            params![connector, manga_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Likes or unlikes the manga, returning whether it's liked now.
+    pub fn toggle_liked(&self, connector_idx: u32, manga_id: &str) -> rusqlite::Result<bool> {
+        let connector = self.connector_id(connector_idx);
+        self.transaction(|tx| {
+            let removed = tx.execute(
+                "DELETE FROM liked WHERE connector = ?1 AND manga_id = ?2",
+                params![connector, manga_id],
+            )?;
+            if removed > 0 {
+                // start from scratch if it's liked again
+                tx.execute(
+                    "DELETE FROM known_chapters WHERE connector = ?1 AND manga_id = ?2",
+                    params![connector, manga_id],
+                )?;
+                return Ok(false);
+            }
+            insert_liked(tx, connector, manga_id, now_millis())?;
+            Ok(true)
+        })
+    }
+
+    pub fn view(&self, connector_idx: u32, manga_id: &str) -> rusqlite::Result<Option<Format>> {
+        let connector = self.connector_id(connector_idx);
+        let name: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT format FROM views WHERE connector = ?1 AND manga_id = ?2",
+                params![connector, manga_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(name.as_deref().and_then(parse_format))
+    }
+
+    pub fn set_view(&self, connector_idx: u32, manga_id: &str, format: Format) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        insert_view(&self.conn.lock().unwrap(), connector, manga_id, format)
+    }
+
+    /// The subset of `chapter_ids` that has been read.
+    pub fn read_chapters<'a>(
+        &self,
+        connector_idx: u32,
+        chapter_ids: impl IntoIterator<Item = &'a String>,
+    ) -> rusqlite::Result<HashSet<String>> {
+        let connector = self.connector_id(connector_idx);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT EXISTS (SELECT 1 FROM read_chapters WHERE connector = ?1 AND chapter_id = ?2)",
+        )?;
+        let mut read = HashSet::new();
+        for id in chapter_ids {
+            if stmt.query_row(params![connector, id], |row| row.get(0))? {
+                read.insert(id.clone());
+            }
+        }
+        Ok(read)
+    }
+
+    pub fn progress(
+        &self,
+        connector_idx: u32,
+        chapter_id: &str,
+    ) -> rusqlite::Result<Option<ChapterProgress>> {
+        let connector = self.connector_id(connector_idx);
+        get_progress(&self.conn.lock().unwrap(), connector, chapter_id)
+    }
+
+    /// Progress of whichever of `chapter_ids` have been opened.
+    pub fn progress_of<'a>(
+        &self,
+        connector_idx: u32,
+        chapter_ids: impl IntoIterator<Item = &'a String>,
+    ) -> rusqlite::Result<HashMap<String, ChapterProgress>> {
+        let connector = self.connector_id(connector_idx);
+        let conn = self.conn.lock().unwrap();
+        let mut progress = HashMap::new();
+        for id in chapter_ids {
+            if let Some(p) = get_progress(&conn, connector, id)? {
+                progress.insert(id.clone(), p);
+            }
+        }
+        Ok(progress)
+    }
+
+    pub fn mark_read(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        chapter_id: &str,
+    ) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        self.transaction(|tx| {
+            insert_read(tx, connector, chapter_id)?;
+            let progress = ChapterProgress {
+                updated_at: now_millis(),
+                completed: true,
+                ..get_progress(tx, connector, chapter_id)?.unwrap_or(ChapterProgress {
+                    page: 0,
+                    total: 0,
+                    updated_at: 0,
+                    completed: true,
+                })
+            };
+            upsert_progress(tx, connector, chapter_id, &progress)?;
+            history::touch(tx, connector, manga_id, chapter_id, progress.page)
+        })
+    }
+
+    /// Records the page the reader is on, returning whether that finished
+    /// the chapter. Finished chapters are marked read.
+    pub fn set_progress(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        chapter_id: &str,
+        page: u32,
+        total: u32,
+    ) -> rusqlite::Result<bool> {
+        let connector = self.connector_id(connector_idx);
+        self.transaction(|tx| {
+            let completed = total > 0 && page + 1 >= total;
+            let was_completed = get_progress(tx, connector, chapter_id)?
+                .map_or(false, |p| p.completed);
+            let progress = ChapterProgress {
+                page,
+                total,
+                updated_at: now_millis(),
+                // going back to an earlier page doesn't make it unread
+                completed: completed || was_completed,
+            };
+            upsert_progress(tx, connector, chapter_id, &progress)?;
+            history::touch(tx, connector, manga_id, chapter_id, page)?;
+
+            let finished = completed && !was_completed;
+            if finished {
+                insert_read(tx, connector, chapter_id)?;
+            }
+            Ok(finished)
+        })
+    }
+
+    /// Replaces the chapters seen on the last update check, returning the
+    /// previous ones. `None` when the manga wasn't checked before.
+    pub fn replace_known_chapters(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        chapter_ids: &[String],
+    ) -> rusqlite::Result<Option<HashSet<String>>> {
+        let connector = self.connector_id(connector_idx);
+        self.transaction(|tx| {
+            let checked_at: Option<u64> = tx
+                .query_row(
+                    "SELECT checked_at FROM liked WHERE connector = ?1 AND manga_id = ?2",
+                    params![connector, manga_id],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .flatten();
+            tx.execute(
+                "UPDATE liked SET checked_at = ?3 WHERE connector = ?1 AND manga_id = ?2",
+                params![connector, manga_id, now_millis()],
+            )?;
+
+            let known = {
+                let mut stmt = tx.prepare_cached(
+                    "SELECT chapter_id FROM known_chapters
+                     WHERE connector = ?1 AND manga_id = ?2",
+                )?;
+                let rows = stmt.query_map(params![connector, manga_id], |row| row.get(0))?;
+                rows.collect::<rusqlite::Result<HashSet<String>>>()?
+            };
+            tx.execute(
+                "DELETE FROM known_chapters WHERE connector = ?1 AND manga_id = ?2",
+                params![connector, manga_id],
+            )?;
+            insert_known_chapters(tx, connector, manga_id, chapter_ids)?;
+            Ok(checked_at.map(|_| known))
+        })
+    }
+
+    /// Makes the next update check treat the connector's liked manga, or
+    /// every liked manga, as seen for the first time. For when the chapters
+    /// listed change for another reason than being released, like a
+    /// language being added.
+    pub fn reseed_known_chapters(&self, connector_idx: Option<u32>) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        match connector_idx {
+            Some(idx) => conn.execute(
+                "UPDATE liked SET checked_at = NULL WHERE connector = ?1",
+                [self.connector_id(idx)],
+            )?,
+            None => conn.execute("UPDATE liked SET checked_at = NULL", [])?,
+        };
+        Ok(())
+    }
+
+    /// Appends to the updates feed, dropping the oldest entries past
+    /// `max_len`.
+    pub fn add_updates(&self, updates: &[ChapterUpdate], max_len: usize) -> rusqlite::Result<()> {
+        self.transaction(|tx| {
+            for update in updates {
+                insert_update(tx, self.connector_id(update.connector_idx), update)?;
+            }
+            tx.execute(
+                "DELETE FROM updates WHERE id NOT IN
+                    (SELECT id FROM updates ORDER BY found_at DESC, id DESC LIMIT ?1)",
+                [max_len],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// The updates feed, newest first. Chapters found in the same check
+    /// keep the order they were added in.
+    pub fn updates(&self) -> rusqlite::Result<Vec<ChapterUpdate>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT connector, manga_id, manga_title, cover_url, chapter, found_at
+             FROM updates ORDER BY found_at DESC, id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let connector: String = row.get(0)?;
+            let connector_idx = match self.connector_idx(&connector) {
+                Some(idx) => idx,
+                None => return Ok(None),
+            };
+            Ok(Some(ChapterUpdate {
+                connector_idx,
+                manga_id: row.get(1)?,
+                manga_title: row.get(2)?,
+                cover_url: row.get(3)?,
+                chapter: from_json(4, &row.get::<_, String>(4)?)?,
+                found_at: row.get(5)?,
+            }))
+        })?;
+        rows.filter_map(Result::transpose).collect()
+    }
+
+    /// Remembers the last successful fetch so the manga can still be shown
+    /// offline.
+    pub fn cache_manga(&self, connector_idx: u32, manga: &Manga) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO manga_cache (connector, manga_id, manga, fetched_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![connector, manga.desc.id, to_json(manga)?, now_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// The cached manga with when it was fetched, in milliseconds since the
+    /// epoch.
+    pub fn cached_manga(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+    ) -> rusqlite::Result<Option<(Manga, u64)>> {
+        let connector = self.connector_id(connector_idx);
+        let row: Option<(String, u64)> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT manga, fetched_at FROM manga_cache WHERE connector = ?1 AND manga_id = ?2",
+                params![connector, manga_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        row.map(|(json, fetched_at)| Ok((from_json(0, &json)?, fetched_at)))
+            .transpose()
+    }
+
+    /// Makes the connector's cached manga, or every cached manga, stale, so
+    /// each is refreshed the next time it's shown while still showing
+    /// offline.
+    pub fn expire_manga_cache(&self, connector_idx: Option<u32>) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        match connector_idx {
+            Some(idx) => conn.execute(
+                "UPDATE manga_cache SET fetched_at = 0 WHERE connector = ?1",
+                [self.connector_id(idx)],
+            )?,
+            None => conn.execute("UPDATE manga_cache SET fetched_at = 0", [])?,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::SearchItem;
+
+    fn manga(id: &str) -> Manga {
+        Manga {
+            desc: SearchItem {
+                id: id.to_string(),
+                title: id.to_string(),
+                description: String::new(),
+                cover_url: String::new(),
+            },
+            chapters: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn expires_one_connectors_cache() {
+        let db = Database::in_memory(&["a", "b"]);
+        db.cache_manga(0, &manga("1")).unwrap();
+        db.cache_manga(1, &manga("2")).unwrap();
+
+        db.expire_manga_cache(Some(0)).unwrap();
+        assert_eq!(db.cached_manga(0, "1").unwrap().unwrap().1, 0);
+        assert_ne!(db.cached_manga(1, "2").unwrap().unwrap().1, 0);
+
+        db.expire_manga_cache(None).unwrap();
+        assert_eq!(db.cached_manga(1, "2").unwrap().unwrap().1, 0);
+    }
+}
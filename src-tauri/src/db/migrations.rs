@@ -0,0 +1,359 @@
+use rusqlite::{ffi, params, Connection};
+
+use crate::connectors::legacy_id;
+
+/// Schema changes, applied in order. The database's `user_version` is the
+/// number of migrations it has seen, so only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: everything that used to live in userdata.json
+    "
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+
+    CREATE TABLE liked (
+        connector_idx INTEGER NOT NULL,
+        manga_id TEXT NOT NULL,
+        added_at INTEGER NOT NULL,
+        -- last update check, NULL until the first one
+        checked_at INTEGER,
+        PRIMARY KEY (connector_idx, manga_id)
+    );
+
+    CREATE TABLE views (
+        connector_idx INTEGER NOT NULL,
+        manga_id TEXT NOT NULL,
+        format TEXT NOT NULL,
+        PRIMARY KEY (connector_idx, manga_id)
+    );
+
+    CREATE TABLE read_chapters (
+        connector_idx INTEGER NOT NULL,
+        chapter_id TEXT NOT NULL,
+        PRIMARY KEY (connector_idx, chapter_id)
+    );
+
+    CREATE TABLE progress (
+        connector_idx INTEGER NOT NULL,
+        chapter_id TEXT NOT NULL,
+        page INTEGER NOT NULL,
+        total INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        completed INTEGER NOT NULL,
+        PRIMARY KEY (connector_idx, chapter_id)
+    );
+
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        connector_idx INTEGER NOT NULL,
+        manga_id TEXT NOT NULL,
+        chapter_id TEXT NOT NULL,
+        page INTEGER NOT NULL,
+        opened_at INTEGER NOT NULL,
+        duration INTEGER NOT NULL
+    );
+    CREATE INDEX history_opened_at ON history (opened_at);
+    CREATE INDEX history_manga ON history (connector_idx, manga_id);
+    CREATE INDEX history_chapter ON history (connector_idx, chapter_id);
+
+    CREATE TABLE known_chapters (
+        connector_idx INTEGER NOT NULL,
+        manga_id TEXT NOT NULL,
+        chapter_id TEXT NOT NULL,
+        PRIMARY KEY (connector_idx, manga_id, chapter_id)
+    );
+
+    CREATE TABLE updates (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        connector_idx INTEGER NOT NULL,
+        manga_id TEXT NOT NULL,
+        manga_title TEXT NOT NULL,
+        cover_url TEXT NOT NULL,
+        chapter TEXT NOT NULL,
+        found_at INTEGER NOT NULL
+    );
+    CREATE INDEX updates_found_at ON updates (found_at);
+
+    CREATE TABLE manga_cache (
+        connector_idx INTEGER NOT NULL,
+        manga_id TEXT NOT NULL,
+        manga TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL,
+        PRIMARY KEY (connector_idx, manga_id)
+    );
+    ",
+    // 2: key everything by connector ID instead of position
+    "
+    CREATE TABLE connectors (
+        id TEXT PRIMARY KEY,
+        version INTEGER NOT NULL
+    );
+
+    ALTER TABLE liked RENAME TO liked_old;
+    CREATE TABLE liked (
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        added_at INTEGER NOT NULL,
+        checked_at INTEGER,
+        PRIMARY KEY (connector, manga_id)
+    );
+    INSERT INTO liked (connector, manga_id, added_at, checked_at)
+        SELECT l.id, o.manga_id, o.added_at, o.checked_at
+        FROM liked_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE liked_old;
+
+    ALTER TABLE views RENAME TO views_old;
+    CREATE TABLE views (
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        format TEXT NOT NULL,
+        PRIMARY KEY (connector, manga_id)
+    );
+    INSERT INTO views (connector, manga_id, format)
+        SELECT l.id, o.manga_id, o.format
+        FROM views_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE views_old;
+
+    ALTER TABLE read_chapters RENAME TO read_chapters_old;
+    CREATE TABLE read_chapters (
+        connector TEXT NOT NULL,
+        chapter_id TEXT NOT NULL,
+        PRIMARY KEY (connector, chapter_id)
+    );
+    INSERT INTO read_chapters (connector, chapter_id)
+        SELECT l.id, o.chapter_id
+        FROM read_chapters_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE read_chapters_old;
+
+    ALTER TABLE progress RENAME TO progress_old;
+    CREATE TABLE progress (
+        connector TEXT NOT NULL,
+        chapter_id TEXT NOT NULL,
+        page INTEGER NOT NULL,
+        total INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        completed INTEGER NOT NULL,
+        PRIMARY KEY (connector, chapter_id)
+    );
+    INSERT INTO progress (connector, chapter_id, page, total, updated_at, completed)
+        SELECT l.id, o.chapter_id, o.page, o.total, o.updated_at, o.completed
+        FROM progress_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE progress_old;
+
+    ALTER TABLE history RENAME TO history_old;
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        chapter_id TEXT NOT NULL,
+        page INTEGER NOT NULL,
+        opened_at INTEGER NOT NULL,
+        duration INTEGER NOT NULL
+    );
+    INSERT INTO history (id, connector, manga_id, chapter_id, page, opened_at, duration)
+        SELECT o.id, l.id, o.manga_id, o.chapter_id, o.page, o.opened_at, o.duration
+        FROM history_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE history_old;
+
+    ALTER TABLE known_chapters RENAME TO known_chapters_old;
+    CREATE TABLE known_chapters (
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        chapter_id TEXT NOT NULL,
+        PRIMARY KEY (connector, manga_id, chapter_id)
+    );
+    INSERT INTO known_chapters (connector, manga_id, chapter_id)
+        SELECT l.id, o.manga_id, o.chapter_id
+        FROM known_chapters_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE known_chapters_old;
+
+    ALTER TABLE updates RENAME TO updates_old;
+    CREATE TABLE updates (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        manga_title TEXT NOT NULL,
+        cover_url TEXT NOT NULL,
+        chapter TEXT NOT NULL,
+        found_at INTEGER NOT NULL
+    );
+    INSERT INTO updates (id, connector, manga_id, manga_title, cover_url, chapter, found_at)
+        SELECT o.id, l.id, o.manga_id, o.manga_title, o.cover_url, o.chapter, o.found_at
+        FROM updates_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE updates_old;
+
+    ALTER TABLE manga_cache RENAME TO manga_cache_old;
+    CREATE TABLE manga_cache (
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        manga TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL,
+        PRIMARY KEY (connector, manga_id)
+    );
+    INSERT INTO manga_cache (connector, manga_id, manga, fetched_at)
+        SELECT l.id, o.manga_id, o.manga, o.fetched_at
+        FROM manga_cache_old o JOIN legacy_connectors l ON l.idx = o.connector_idx;
+    DROP TABLE manga_cache_old;
+
+    CREATE INDEX history_opened_at ON history (opened_at);
+    CREATE INDEX history_manga ON history (connector, manga_id);
+    CREATE INDEX history_chapter ON history (connector, chapter_id);
+    CREATE INDEX updates_found_at ON updates (found_at);
+    ",
+    // 3: categories for the liked manga
+    "
+    CREATE TABLE categories (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        position INTEGER NOT NULL
+    );
+
+    CREATE TABLE category_manga (
+        category_id INTEGER NOT NULL REFERENCES categories (id) ON DELETE CASCADE,
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (category_id, connector, manga_id),
+        FOREIGN KEY (connector, manga_id) REFERENCES liked (connector, manga_id) ON DELETE CASCADE
+    );
+    CREATE INDEX category_manga_manga ON category_manga (connector, manga_id);
+
+    INSERT INTO categories (name, position) VALUES
+        ('Reading', 0),
+        ('Plan to read', 1),
+        ('Completed', 2),
+        ('Dropped', 3);
+    ",
+    // 4: links to tracker entries
+    "
+    CREATE TABLE tracker_links (
+        connector TEXT NOT NULL,
+        manga_id TEXT NOT NULL,
+        tracker TEXT NOT NULL,
+        remote_id TEXT NOT NULL,
+        title TEXT NOT NULL,
+        status TEXT NOT NULL,
+        score REAL,
+        progress INTEGER NOT NULL,
+        -- status and score changed here after synced_at still need pushing
+        updated_at INTEGER NOT NULL,
+        synced_at INTEGER NOT NULL,
+        PRIMARY KEY (connector, manga_id, tracker)
+    );
+    ",
+];
+
+pub fn run(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    // a newer app changed it in ways we can't know, writing to it could
+    // break that version
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CANTOPEN),
+            Some(format!(
+                "the library database is at version {version} but this app only knows {}, \
+                 update the app to open it",
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+
+    // maps the connector positions data used to be saved under to their IDs
+    conn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS legacy_connectors (idx INTEGER PRIMARY KEY, id TEXT NOT NULL)",
+    )?;
+    let mut idx = 0;
+    while let Some(id) = legacy_id(idx) {
+        conn.execute(
+            "INSERT OR REPLACE INTO legacy_connectors (idx, id) VALUES (?1, ?2)",
+            params![idx, id],
+        )?;
+        idx += 1;
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrates_from_first_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute_batch(
+            "INSERT INTO liked (connector_idx, manga_id, added_at) VALUES (0, 'a', 1), (1, 'b', 2), (9, 'gone', 3);
+             INSERT INTO read_chapters (connector_idx, chapter_id) VALUES (1, 'c1');
+             INSERT INTO history (connector_idx, manga_id, chapter_id, page, opened_at, duration)
+                 VALUES (0, 'a', 'c2', 3, 10, 20);",
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+
+        let liked = conn
+            .prepare("SELECT connector, manga_id FROM liked ORDER BY added_at")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()
+            .unwrap();
+        assert_eq!(
+            liked,
+            [
+                ("mangadex".to_string(), "a".to_string()),
+                ("mangakakalot".to_string(), "b".to_string())
+            ]
+        );
+        let read: String = conn
+            .query_row("SELECT connector FROM read_chapters WHERE chapter_id = 'c1'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(read, "mangakakalot");
+        let page: u32 = conn
+            .query_row(
+                "SELECT page FROM history WHERE connector = 'mangadex' AND chapter_id = 'c2'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(page, 3);
+
+        let categories: u32 = conn
+            .query_row("SELECT COUNT(*) FROM categories", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(categories, 4);
+        conn.execute_batch("SELECT * FROM tracker_links").unwrap();
+    }
+
+    #[test]
+    fn running_again_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        run(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        assert!(run(&mut conn).is_err());
+        assert_eq!(version(&conn), MIGRATIONS.len() + 1);
+    }
+}
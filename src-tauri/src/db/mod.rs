@@ -0,0 +1,136 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, Transaction};
+
+use crate::connectors::Connectors;
+
+pub use self::import::import_userdata;
+
+mod backup;
+mod categories;
+mod history;
+mod import;
+mod library;
+mod migrations;
+mod trackers;
+
+pub use self::backup::{
+    BackupCategory, BackupChapter, BackupManga, BackupProgress, ImportMode, LibraryBackup,
+};
+pub use self::categories::Category;
+pub use self::history::HistoryEntry;
+pub use self::trackers::TrackerLink;
+
+/// Library state too big to rewrite on every change: liked manga, views,
+/// read chapters, progress, history, update checks, tracker links and
+/// cached metadata.
+/// Settings stay in `userdata.json`.
+///
+/// Rows are keyed by the connector's ID, while the methods take and return
+/// its current position in `Connectors` like the rest of the app.
+pub struct Database {
+    conn: Mutex<Connection>,
+    /// Connector IDs in the order of `Connectors`.
+    connectors: Vec<String>,
+}
+
+impl Database {
+    /// Opens and migrates the database. The connectors are only known after
+    /// `register_connectors`, so the legacy import can run before the
+    /// settings they need are loaded.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// An empty database in memory, knowing connectors by `ids`.
+    #[cfg(test)]
+    pub fn in_memory(ids: &[&str]) -> Self {
+        let mut db = Self::init(Connection::open_in_memory().unwrap()).unwrap();
+        let connectors = ids.iter().map(|id| (*id, 1)).collect::<Vec<_>>();
+        db.register(&connectors).unwrap();
+        db
+    }
+
+    fn init(mut conn: Connection) -> rusqlite::Result<Self> {
+        // WAL keeps reads from waiting on writes and survives crashes mid-write
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrations::run(&mut conn)?;
+        Ok(Database {
+            conn: Mutex::new(conn),
+            connectors: Vec::new(),
+        })
+    }
+
+    pub fn register_connectors(&mut self, connectors: &Connectors) -> rusqlite::Result<()> {
+        let connectors = connectors
+            .0
+            .iter()
+            .map(|c| (c.id(), c.version()))
+            .collect::<Vec<_>>();
+        self.register(&connectors)
+    }
+
+    /// Records the connectors' versions, given as `(id, version)` in the
+    /// order of `Connectors`.
+    pub(crate) fn register(&mut self, connectors: &[(&str, u32)]) -> rusqlite::Result<()> {
+        let conn = self.conn.get_mut().unwrap();
+        for (id, version) in connectors {
+            let previous: Option<u32> = {
+                use rusqlite::OptionalExtension;
+                conn.query_row(
+                    "SELECT version FROM connectors WHERE id = ?1",
+                    [id],
+                    |row| row.get(0),
+                )
+                .optional()?
+            };
+            // the place to migrate a connector's IDs once one changes format
+            if let Some(previous) = previous.filter(|v| v != version) {
+                println!("connector {id} changed from version {previous} to {version}");
+                // its chapters may be listed differently now without being new
+                conn.execute("UPDATE liked SET checked_at = NULL WHERE connector = ?1", [id])?;
+            }
+            conn.execute(
+                "INSERT OR REPLACE INTO connectors (id, version) VALUES (?1, ?2)",
+                params![id, version],
+            )?;
+        }
+        self.connectors = connectors.iter().map(|(id, _)| id.to_string()).collect();
+        Ok(())
+    }
+
+    fn connector_id(&self, connector_idx: u32) -> &str {
+        &self.connectors[connector_idx as usize]
+    }
+
+    fn connector_idx(&self, connector_id: &str) -> Option<u32> {
+        self.connectors
+            .iter()
+            .position(|id| id == connector_id)
+            .map(|i| i as u32)
+    }
+
+    /// Runs `f` in a transaction, committing only if it succeeds.
+    fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Transaction) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    fn get_meta(&self, key: &str) -> rusqlite::Result<Option<String>> {
+        use rusqlite::OptionalExtension;
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+}
@@ -0,0 +1,265 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::Database;
+use crate::{
+    trackers::{TrackEntry, TrackStatus},
+    updates::now_millis,
+};
+
+/// A manga linked to its entry on a tracker, with the entry as last seen or
+/// changed here.
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct TrackerLink {
+    pub tracker: String,
+    pub remote_id: String,
+    pub title: String,
+    pub entry: TrackEntry,
+    /// When the status or score were last changed here, in milliseconds
+    /// since the epoch.
+    pub updated_at: u64,
+    /// When the entry was last synced with the tracker.
+    pub synced_at: u64,
+}
+
+impl TrackerLink {
+    /// Whether the status or score changed here since the last sync.
+    pub fn has_changes(&self) -> bool {
+        self.updated_at > self.synced_at
+    }
+}
+
+pub(super) fn status_name(status: TrackStatus) -> &'static str {
+    match status {
+        TrackStatus::Reading => "Reading",
+        TrackStatus::Completed => "Completed",
+        TrackStatus::OnHold => "OnHold",
+        TrackStatus::Dropped => "Dropped",
+        TrackStatus::PlanToRead => "PlanToRead",
+        TrackStatus::Rereading => "Rereading",
+    }
+}
+
+pub(super) fn parse_status(name: &str) -> Option<TrackStatus> {
+    Some(match name {
+        "Reading" => TrackStatus::Reading,
+        "Completed" => TrackStatus::Completed,
+        "OnHold" => TrackStatus::OnHold,
+        "Dropped" => TrackStatus::Dropped,
+        "PlanToRead" => TrackStatus::PlanToRead,
+        "Rereading" => TrackStatus::Rereading,
+        _ => return None,
+    })
+}
+
+const COLUMNS: &str = "tracker, remote_id, title, status, score, progress, updated_at, synced_at";
+
+fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Option<TrackerLink>> {
+    let status: String = row.get(3)?;
+    let status = match parse_status(&status) {
+        Some(status) => status,
+        None => return Ok(None),
+    };
+    Ok(Some(TrackerLink {
+        tracker: row.get(0)?,
+        remote_id: row.get(1)?,
+        title: row.get(2)?,
+        entry: TrackEntry {
+            status,
+            score: row.get(4)?,
+            progress: row.get(5)?,
+        },
+        updated_at: row.get(6)?,
+        synced_at: row.get(7)?,
+    }))
+}
+
+/// Replaces the link if `replace`, otherwise keeps the one already there.
+pub(super) fn insert_link(
+    conn: &Connection,
+    connector: &str,
+    manga_id: &str,
+    link: &TrackerLink,
+    replace: bool,
+) -> rusqlite::Result<()> {
+    let verb = match replace {
+        true => "INSERT OR REPLACE",
+        false => "INSERT OR IGNORE",
+    };
+    conn.execute(
+        &format!(
+            "{verb} INTO tracker_links (connector, manga_id, {COLUMNS})
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
+        ),
+        params![
+            connector,
+            manga_id,
+            link.tracker,
+            link.remote_id,
+            link.title,
+            status_name(link.entry.status),
+            link.entry.score,
+            link.entry.progress,
+            link.updated_at,
+            link.synced_at,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Links as `(connector, manga_id, link)`, for backups.
+pub(super) fn all_links(conn: &Connection) -> rusqlite::Result<Vec<(String, String, TrackerLink)>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {COLUMNS}, connector, manga_id FROM tracker_links"
+    ))?;
+    let rows = stmt.query_map([], |row| match from_row(row)? {
+        Some(link) => Ok(Some((row.get(8)?, row.get(9)?, link))),
+        None => Ok(None),
+    })?;
+    rows.filter_map(Result::transpose).collect()
+}
+
+impl Database {
+    pub fn tracker_links(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+    ) -> rusqlite::Result<Vec<TrackerLink>> {
+        let connector = self.connector_id(connector_idx);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM tracker_links
+             WHERE connector = ?1 AND manga_id = ?2 ORDER BY tracker"
+        ))?;
+        let rows = stmt.query_map(params![connector, manga_id], from_row)?;
+        rows.filter_map(Result::transpose).collect()
+    }
+
+    pub fn tracker_link(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        tracker: &str,
+    ) -> rusqlite::Result<Option<TrackerLink>> {
+        let connector = self.connector_id(connector_idx);
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!(
+                    "SELECT {COLUMNS} FROM tracker_links
+                     WHERE connector = ?1 AND manga_id = ?2 AND tracker = ?3"
+                ),
+                params![connector, manga_id, tracker],
+                from_row,
+            )
+            .optional()
+            .map(Option::flatten)
+    }
+
+    /// Links the manga to a tracker entry, replacing any previous link to
+    /// that tracker.
+    pub fn link_tracker(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        link: &TrackerLink,
+    ) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        insert_link(&self.conn.lock().unwrap(), connector, manga_id, link, true)
+    }
+
+    /// Whether there was a link to remove.
+    pub fn unlink_tracker(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        tracker: &str,
+    ) -> rusqlite::Result<bool> {
+        let connector = self.connector_id(connector_idx);
+        let removed = self.conn.lock().unwrap().execute(
+            "DELETE FROM tracker_links WHERE connector = ?1 AND manga_id = ?2 AND tracker = ?3",
+            params![connector, manga_id, tracker],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Records a change the user made, to push on the next sync.
+    pub fn set_tracker_entry(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        tracker: &str,
+        entry: &TrackEntry,
+    ) -> rusqlite::Result<bool> {
+        let connector = self.connector_id(connector_idx);
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE tracker_links SET status = ?4, score = ?5, progress = ?6, updated_at = ?7
+             WHERE connector = ?1 AND manga_id = ?2 AND tracker = ?3",
+            params![
+                connector,
+                manga_id,
+                tracker,
+                status_name(entry.status),
+                entry.score,
+                entry.progress,
+                now_millis(),
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Raises the chapters read on every link of the manga to `progress`,
+    /// returning the links that changed. Progress only ever goes up, so this
+    /// doesn't count as a change to push over the tracker's status.
+    pub fn raise_tracker_progress(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        progress: u32,
+    ) -> rusqlite::Result<Vec<TrackerLink>> {
+        let changed = self
+            .tracker_links(connector_idx, manga_id)?
+            .into_iter()
+            .filter(|link| link.entry.progress < progress)
+            .map(|mut link| {
+                link.entry.progress = progress;
+                link
+            })
+            .collect();
+        let connector = self.connector_id(connector_idx);
+        self.conn.lock().unwrap().execute(
+            "UPDATE tracker_links SET progress = ?3
+             WHERE connector = ?1 AND manga_id = ?2 AND progress < ?3",
+            params![connector, manga_id, progress],
+        )?;
+        Ok(changed)
+    }
+
+    /// Stores the entry both sides agree on after a sync.
+    pub fn tracker_synced(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        tracker: &str,
+        entry: &TrackEntry,
+    ) -> rusqlite::Result<()> {
+        let connector = self.connector_id(connector_idx);
+        let now = now_millis();
+        self.conn.lock().unwrap().execute(
+            "UPDATE tracker_links SET status = ?4, score = ?5, progress = ?6, updated_at = ?7, synced_at = ?7
+             WHERE connector = ?1 AND manga_id = ?2 AND tracker = ?3",
+            params![
+                connector,
+                manga_id,
+                tracker,
+                status_name(entry.status),
+                entry.score,
+                entry.progress,
+                now,
+            ],
+        )?;
+        Ok(())
+    }
+}
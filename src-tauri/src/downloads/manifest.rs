@@ -0,0 +1,39 @@
+use std::{fs::File, io, path::Path};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use specta::Type;
+
+use crate::connectors::{Chapter, Format, SearchItem};
+
+pub const MANGA_MANIFEST: &str = "manga.json";
+pub const CHAPTER_MANIFEST: &str = "chapter.json";
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct MangaManifest {
+    pub connector_id: String,
+    pub desc: SearchItem,
+    pub cover: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct ChapterManifest {
+    pub chapter: Chapter,
+    pub format: Format,
+    pub pages: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Type)]
+pub struct DownloadedManga {
+    pub manifest: MangaManifest,
+    pub chapters: Vec<ChapterManifest>,
+}
+
+pub fn read<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let file = File::open(path)?;
+    serde_json::from_reader(file).map_err(io::Error::from)
+}
+
+pub fn write<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let file = File::create(path)?;
+    serde_json::to_writer(file, value).map_err(io::Error::from)
+}
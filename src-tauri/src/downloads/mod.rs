@@ -0,0 +1,429 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    fs::{self, create_dir_all},
+    io,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    connectors::{
+        legacy_id, Chapter, ChapterImages, Connector, ConnectorError, Format, Image, Manga,
+        SearchItem,
+    },
+    protocol,
+};
+
+use self::manifest::{
+    ChapterManifest, DownloadedManga, MangaManifest, CHAPTER_MANIFEST, MANGA_MANIFEST,
+};
+
+pub mod manifest;
+pub mod queue;
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Connector(ConnectorError),
+    Io(io::Error),
+    MissingChapter(String),
+    Cancelled,
+}
+
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Connector(e) => write!(f, "{e}"),
+            DownloadError::Io(e) => write!(f, "io error: {e}"),
+            DownloadError::MissingChapter(id) => write!(f, "chapter {id} not found"),
+            DownloadError::Cancelled => write!(f, "download cancelled"),
+        }
+    }
+}
+
+impl From<ConnectorError> for DownloadError {
+    fn from(value: ConnectorError) -> Self {
+        DownloadError::Connector(value)
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(value: io::Error) -> Self {
+        DownloadError::Io(value)
+    }
+}
+
+impl From<DownloadError> for ConnectorError {
+    fn from(value: DownloadError) -> Self {
+        match value {
+            DownloadError::Connector(e) => e,
+            DownloadError::Io(e) => e.into(),
+            DownloadError::MissingChapter(id) => ConnectorError::NotFound(format!("chapter {id}")),
+            DownloadError::Cancelled => ConnectorError::Io(value.to_string()),
+        }
+    }
+}
+
+/// Hooks for whoever is driving a download, so it can be paused or
+/// cancelled between requests and report progress.
+#[async_trait]
+pub trait Progress: Send + Sync {
+    /// Awaited before every request. Returning an error aborts the download.
+    async fn checkpoint(&self) -> Result<(), DownloadError>;
+    fn page_saved(&self, page: usize, total: usize);
+}
+
+/// Chapters saved to disk for offline reading, laid out as
+/// `downloads/<connector id>/<manga>/<chapter>/` with a JSON manifest at the
+/// manga and chapter level. A chapter only counts as downloaded once its
+/// manifest has been written, so interrupted downloads are never served.
+pub struct Downloads {
+    root: PathBuf,
+}
+
+/// Manga and chapter IDs are URLs or contain characters that aren't valid in
+/// file names on every platform, so percent-encode anything unusual.
+fn escape(id: &str) -> String {
+    id.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+pub fn extension(content_type: &str) -> &str {
+    match content_type {
+        "image/png" => "png",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        _ => "jpg",
+    }
+}
+
+pub fn content_type(path: &Path) -> &str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        _ => "image/jpeg",
+    }
+}
+
+/// Loads an image the connector handed out, which may already be wrapped
+/// for the webview like the local connector's covers.
+async fn fetch_page(connector: &dyn Connector, url: &str) -> Result<Image, ConnectorError> {
+    match protocol::parse_image_url(url) {
+        Some((connector_id, src)) if connector_id == connector.id() => {
+            connector.fetch_image(&src).await
+        }
+        _ => connector.fetch_image(url).await,
+    }
+}
+
+impl Downloads {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self {
+            root: data_dir.join("downloads"),
+        }
+    }
+
+    fn connector_dir(&self, connector_id: &str) -> PathBuf {
+        self.root.join(escape(connector_id))
+    }
+
+    fn manga_dir(&self, connector_id: &str, manga_id: &str) -> PathBuf {
+        self.connector_dir(connector_id).join(escape(manga_id))
+    }
+
+    /// Downloads used to be filed under the connector's index. Moves them to
+    /// the connector's ID and updates the manifests to match.
+    pub fn migrate_connector_dirs(&self) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let old = entry?.path();
+            let id = match old
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse().ok())
+                .and_then(legacy_id)
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let new = self.connector_dir(id);
+            create_dir_all(&new)?;
+            for manga in fs::read_dir(&old)? {
+                let manga = manga?;
+                let dest = new.join(manga.file_name());
+                if dest.exists() {
+                    println!("not migrating {}, {} exists", manga.path().display(), dest.display());
+                    continue;
+                }
+                fs::rename(manga.path(), &dest)?;
+
+                let path = dest.join(MANGA_MANIFEST);
+                if let Ok(mut manifest) = manifest::read::<serde_json::Value>(&path) {
+                    if let Some(manifest) = manifest.as_object_mut() {
+                        manifest.remove("connector_idx");
+                        manifest.insert("connector_id".to_string(), id.into());
+                    }
+                    manifest::write(&path, &manifest)?;
+                }
+            }
+            // only goes if everything was moved
+            let _ = fs::remove_dir(&old);
+        }
+        Ok(())
+    }
+
+    fn chapter_dirs(manga_dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(manga_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.join(CHAPTER_MANIFEST).is_file())
+            .collect()
+    }
+
+    fn read_chapters(manga_dir: &Path) -> Vec<ChapterManifest> {
+        let mut chapters = Self::chapter_dirs(manga_dir)
+            .iter()
+            .filter_map(|dir| manifest::read(&dir.join(CHAPTER_MANIFEST)).ok())
+            .collect::<Vec<ChapterManifest>>();
+        chapters.sort_by(|a, b| {
+            b.chapter
+                .number
+                .partial_cmp(&a.chapter.number)
+                .unwrap_or(Ordering::Equal)
+        });
+        chapters
+    }
+
+    fn read_manga(manga_dir: &Path) -> io::Result<MangaManifest> {
+        let mut manga: MangaManifest = manifest::read(&manga_dir.join(MANGA_MANIFEST))?;
+        if manga.cover.is_some() {
+            manga.desc.cover_url = protocol::cover_url(&manga.connector_id, &manga.desc.id);
+        }
+        Ok(manga)
+    }
+
+    fn find_chapter(&self, connector_id: &str, chapter_id: &str) -> Option<PathBuf> {
+        let name = escape(chapter_id);
+        fs::read_dir(self.connector_dir(connector_id))
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path().join(&name))
+            .find(|p| p.join(CHAPTER_MANIFEST).is_file())
+    }
+
+    pub fn is_downloaded(&self, connector_id: &str, chapter_id: &str) -> bool {
+        self.find_chapter(connector_id, chapter_id).is_some()
+    }
+
+    /// A downloaded chapter with `bunni://` URLs for its pages, or `None` if
+    /// the chapter isn't on disk (or can't be read) and should be fetched
+    /// remotely.
+    pub fn load_chapter(&self, connector_id: &str, chapter_id: &str) -> Option<ChapterImages> {
+        let dir = self.find_chapter(connector_id, chapter_id)?;
+        let chapter: ChapterManifest = manifest::read(&dir.join(CHAPTER_MANIFEST)).ok()?;
+        Some(ChapterImages {
+            images: (0..chapter.pages.len())
+                .map(|page| protocol::download_url(connector_id, chapter_id, page))
+                .collect(),
+            format: chapter.format,
+        })
+    }
+
+    /// Page `index` of a downloaded chapter.
+    pub fn load_page(&self, connector_id: &str, chapter_id: &str, index: usize) -> Option<Image> {
+        let dir = self.find_chapter(connector_id, chapter_id)?;
+        let chapter: ChapterManifest = manifest::read(&dir.join(CHAPTER_MANIFEST)).ok()?;
+        let path = dir.join(chapter.pages.get(index)?);
+        Some(Image {
+            content_type: content_type(&path).to_string(),
+            bytes: fs::read(path).ok()?,
+        })
+    }
+
+    /// The saved cover of a downloaded manga.
+    pub fn load_cover(&self, connector_id: &str, manga_id: &str) -> Option<Image> {
+        let dir = self.manga_dir(connector_id, manga_id);
+        let manga: MangaManifest = manifest::read(&dir.join(MANGA_MANIFEST)).ok()?;
+        let path = dir.join(manga.cover?);
+        Some(Image {
+            content_type: content_type(&path).to_string(),
+            bytes: fs::read(path).ok()?,
+        })
+    }
+
+    /// Gets a chapter's pages in reading order, from disk when it has been
+    /// downloaded and from the connector otherwise.
+    pub async fn fetch_pages(
+        &self,
+        connector: &dyn Connector,
+        chapter_id: &str,
+    ) -> Result<(Vec<Image>, Format), DownloadError> {
+        if let Some(dir) = self.find_chapter(connector.id(), chapter_id) {
+            let chapter: ChapterManifest = manifest::read(&dir.join(CHAPTER_MANIFEST))?;
+            let images = chapter
+                .pages
+                .iter()
+                .map(|page| {
+                    let path = dir.join(page);
+                    Ok(Image {
+                        content_type: content_type(&path).to_string(),
+                        bytes: fs::read(path)?,
+                    })
+                })
+                .collect::<io::Result<_>>()?;
+            return Ok((images, chapter.format));
+        }
+
+        let urls = connector.fetch_chapter_urls(chapter_id).await?;
+        let mut images = Vec::with_capacity(urls.images.len());
+        for url in &urls.images {
+            images.push(fetch_page(connector, url).await?);
+        }
+        Ok((images, urls.format))
+    }
+
+    /// Builds a `Manga` out of the downloaded chapters, used when the
+    /// connector can't be reached.
+    pub fn load_manga(&self, connector_id: &str, manga_id: &str) -> Option<Manga> {
+        let dir = self.manga_dir(connector_id, manga_id);
+        let manga = Self::read_manga(&dir).ok()?;
+        Some(Manga {
+            desc: manga.desc,
+            chapters: Self::read_chapters(&dir)
+                .into_iter()
+                .map(|c| c.chapter)
+                .collect(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<DownloadedManga> {
+        fs::read_dir(&self.root)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .flat_map(|connector| fs::read_dir(connector.path()).into_iter().flatten())
+            .filter_map(|e| e.ok())
+            .filter_map(|manga| {
+                let dir = manga.path();
+                Some(DownloadedManga {
+                    manifest: Self::read_manga(&dir).ok()?,
+                    chapters: Self::read_chapters(&dir),
+                })
+            })
+            .collect()
+    }
+
+    async fn save_manga(
+        &self,
+        connector: &dyn Connector,
+        desc: &SearchItem,
+    ) -> Result<PathBuf, DownloadError> {
+        let dir = self.manga_dir(connector.id(), &desc.id);
+        create_dir_all(&dir)?;
+
+        let existing = manifest::read::<MangaManifest>(&dir.join(MANGA_MANIFEST))
+            .ok()
+            .and_then(|m| m.cover)
+            .filter(|cover| dir.join(cover).is_file());
+        // the cover is nice to have offline but shouldn't block the download
+        let cover = match existing {
+            Some(cover) => Some(cover),
+            None => match fetch_page(connector, &desc.cover_url).await {
+                Ok(image) => {
+                    let name = format!("cover.{}", extension(&image.content_type));
+                    fs::write(dir.join(&name), image.bytes)?;
+                    Some(name)
+                }
+                Err(_) => None,
+            },
+        };
+
+        manifest::write(
+            &dir.join(MANGA_MANIFEST),
+            &MangaManifest {
+                connector_id: connector.id().to_string(),
+                desc: desc.clone(),
+                cover,
+            },
+        )?;
+        Ok(dir)
+    }
+
+    pub async fn download(
+        &self,
+        connector: &dyn Connector,
+        desc: &SearchItem,
+        chapter: &Chapter,
+        progress: &dyn Progress,
+    ) -> Result<ChapterManifest, DownloadError> {
+        progress.checkpoint().await?;
+        let manga_dir = self
+            .save_manga(connector, desc)
+            .await?;
+        let dir = manga_dir.join(escape(&chapter.id));
+        create_dir_all(&dir)?;
+
+        let result = async {
+            progress.checkpoint().await?;
+            let images = connector.fetch_chapter_urls(&chapter.id).await?;
+            let total = images.images.len();
+            let mut pages = Vec::with_capacity(total);
+            for (i, url) in images.images.iter().enumerate() {
+                progress.checkpoint().await?;
+                let image = fetch_page(connector, url).await?;
+                let name = format!("{:03}.{}", i + 1, extension(&image.content_type));
+                fs::write(dir.join(&name), image.bytes)?;
+                pages.push(name);
+                progress.page_saved(i + 1, total);
+            }
+
+            let chapter = ChapterManifest {
+                chapter: chapter.clone(),
+                format: images.format,
+                pages,
+            };
+            manifest::write(&dir.join(CHAPTER_MANIFEST), &chapter)?;
+            Ok(chapter)
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        result
+    }
+
+    /// Deletes a single chapter, or the whole manga when `chapter_id` is
+    /// `None`. The manga folder is also removed once its last chapter goes.
+    pub fn delete(
+        &self,
+        connector_id: &str,
+        manga_id: &str,
+        chapter_id: Option<&str>,
+    ) -> io::Result<()> {
+        let manga_dir = self.manga_dir(connector_id, manga_id);
+        match chapter_id {
+            Some(id) => {
+                fs::remove_dir_all(manga_dir.join(escape(id)))?;
+                if Self::chapter_dirs(&manga_dir).is_empty() {
+                    fs::remove_dir_all(&manga_dir)?;
+                }
+                Ok(())
+            }
+            None => fs::remove_dir_all(&manga_dir),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+
+use async_trait::async_trait;
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use serde::Serialize;
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+use crate::connectors::{Chapter, Connectors, SearchItem};
+
+use super::{DownloadError, Downloads, Progress};
+
+/// How many chapters are downloaded at the same time.
+const MAX_CONCURRENT: usize = 3;
+
+#[derive(Serialize, Type, Clone, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Downloading,
+    Paused,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Serialize, Type, Clone)]
+pub struct DownloadJob {
+    pub job_id: u32,
+    pub connector_idx: u32,
+    pub manga_id: String,
+    pub chapter_id: String,
+    pub status: JobStatus,
+    pub page: u32,
+    pub total: u32,
+}
+
+struct Job {
+    state: Mutex<DownloadJob>,
+    /// Resolved once when queued, so the job doesn't refetch the manga.
+    desc: SearchItem,
+    chapter: Chapter,
+    paused: Mutex<bool>,
+    cancelled: Mutex<bool>,
+}
+
+impl Job {
+    fn snapshot(&self) -> DownloadJob {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn is_chapter(&self, connector_idx: u32, chapter_id: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.connector_idx == connector_idx && state.chapter_id == chapter_id
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(
+            self.state.lock().unwrap().status,
+            JobStatus::Completed | JobStatus::Failed(_) | JobStatus::Cancelled
+        )
+    }
+}
+
+/// Background chapter downloads. Jobs run with bounded concurrency, and
+/// every state change or saved page is emitted to the frontend as a
+/// `download_progress` event. Requests are rate limited by the connectors'
+/// shared `HttpClient`.
+pub struct DownloadQueue {
+    app: AppHandle,
+    next_id: AtomicU32,
+    jobs: Mutex<Vec<Arc<Job>>>,
+    permits: Arc<Semaphore>,
+    paused: Mutex<bool>,
+    /// Bumped whenever something is paused, resumed or cancelled so waiting
+    /// jobs re-check their state.
+    changed: watch::Sender<()>,
+}
+
+struct JobProgress<'a> {
+    queue: &'a DownloadQueue,
+    job: &'a Job,
+    /// Given back while the job is paused, so paused jobs don't keep
+    /// others from running.
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+}
+
+#[async_trait]
+impl Progress for JobProgress<'_> {
+    async fn checkpoint(&self) -> Result<(), DownloadError> {
+        let mut changes = self.queue.changed.subscribe();
+        loop {
+            if *self.job.cancelled.lock().unwrap() {
+                return Err(DownloadError::Cancelled);
+            }
+            let paused = *self.queue.paused.lock().unwrap() || *self.job.paused.lock().unwrap();
+            if paused {
+                self.permit.lock().unwrap().take();
+                self.queue.set_status(self.job, JobStatus::Paused);
+                // the sender lives as long as the queue, so this can't fail
+                let _ = changes.changed().await;
+                continue;
+            }
+            if self.permit.lock().unwrap().is_none() {
+                self.queue.set_status(self.job, JobStatus::Queued);
+                let permit = self
+                    .queue
+                    .acquire(self.job)
+                    .await
+                    .ok_or(DownloadError::Cancelled)?;
+                *self.permit.lock().unwrap() = Some(permit);
+                // it may have been paused or cancelled while waiting
+                continue;
+            }
+            self.queue.set_status(self.job, JobStatus::Downloading);
+            break;
+        }
+        Ok(())
+    }
+
+    fn page_saved(&self, page: usize, total: usize) {
+        let snapshot = {
+            let mut state = self.job.state.lock().unwrap();
+            state.page = page as u32;
+            state.total = total as u32;
+            state.clone()
+        };
+        self.queue.emit(snapshot);
+    }
+}
+
+impl DownloadQueue {
+    pub fn new(app: AppHandle) -> Self {
+        Self {
+            app,
+            next_id: AtomicU32::new(0),
+            jobs: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT)),
+            paused: Mutex::new(false),
+            changed: watch::channel(()).0,
+        }
+    }
+
+    fn emit(&self, job: DownloadJob) {
+        self.app.emit_all("download_progress", job).unwrap();
+    }
+
+    fn set_status(&self, job: &Job, status: JobStatus) {
+        let snapshot = {
+            let mut state = job.state.lock().unwrap();
+            if state.status == status {
+                return;
+            }
+            state.status = status;
+            state.clone()
+        };
+        self.emit(snapshot);
+    }
+
+    /// Waits for a free slot, giving up as soon as the job is cancelled.
+    async fn acquire(&self, job: &Job) -> Option<OwnedSemaphorePermit> {
+        let mut changes = self.changed.subscribe();
+        loop {
+            if *job.cancelled.lock().unwrap() {
+                return None;
+            }
+            let permit = self.permits.clone().acquire_owned();
+            let changed = changes.changed();
+            pin_mut!(permit, changed);
+            match select(permit, changed).await {
+                Either::Left((permit, _)) => return Some(permit.unwrap()),
+                Either::Right(_) => continue,
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<DownloadJob> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|j| j.snapshot())
+            .collect()
+    }
+
+    /// Queues the given chapters of `desc`, skipping any that are already
+    /// downloaded or waiting in the queue. Failed or cancelled jobs for them
+    /// are replaced.
+    pub fn enqueue(
+        &self,
+        downloads: &Downloads,
+        connector_idx: u32,
+        desc: &SearchItem,
+        chapters: Vec<Chapter>,
+    ) -> Vec<DownloadJob> {
+        let connectors = self.app.state::<Connectors>();
+        let connector_id = connectors[connector_idx].id();
+        let mut jobs = self.jobs.lock().unwrap();
+        let added = chapters
+            .into_iter()
+            .filter(|c| !downloads.is_downloaded(connector_id, &c.id))
+            .filter(|c| !jobs.iter().any(|j| !j.is_done() && j.is_chapter(connector_idx, &c.id)))
+            .map(|chapter| {
+                Arc::new(Job {
+                    state: Mutex::new(DownloadJob {
+                        job_id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                        connector_idx,
+                        manga_id: desc.id.clone(),
+                        chapter_id: chapter.id.clone(),
+                        status: JobStatus::Queued,
+                        page: 0,
+                        total: 0,
+                    }),
+                    desc: desc.clone(),
+                    chapter,
+                    paused: Mutex::new(false),
+                    cancelled: Mutex::new(false),
+                })
+            })
+            .collect::<Vec<_>>();
+        jobs.retain(|j| {
+            !(j.is_done()
+                && added
+                    .iter()
+                    .any(|a| j.is_chapter(connector_idx, &a.state.lock().unwrap().chapter_id)))
+        });
+        jobs.extend(added.iter().cloned());
+        drop(jobs);
+
+        added
+            .into_iter()
+            .map(|job| {
+                let snapshot = job.snapshot();
+                let app = self.app.clone();
+                tauri::async_runtime::spawn(async move {
+                    app.state::<DownloadQueue>().run(&app, job).await;
+                });
+                self.emit(snapshot.clone());
+                snapshot
+            })
+            .collect()
+    }
+
+    async fn run(&self, app: &AppHandle, job: Arc<Job>) {
+        let result = match self.acquire(&job).await {
+            Some(permit) => {
+                let connector_idx = job.snapshot().connector_idx;
+                let connectors = app.state::<Connectors>();
+                let connector = &*connectors[connector_idx];
+                let progress = JobProgress {
+                    queue: self,
+                    job: &job,
+                    permit: Mutex::new(Some(permit)),
+                };
+                app.state::<Downloads>()
+                    .download(connector, &job.desc, &job.chapter, &progress)
+                    .await
+            }
+            None => Err(DownloadError::Cancelled),
+        };
+
+        self.set_status(
+            &job,
+            match result {
+                Ok(_) => JobStatus::Completed,
+                Err(DownloadError::Cancelled) => JobStatus::Cancelled,
+                Err(e) => JobStatus::Failed(e.to_string()),
+            },
+        );
+        // failed jobs stay listed until they're dismissed with cancel
+        self.jobs.lock().unwrap().retain(|j| {
+            !Arc::ptr_eq(j, &job) || matches!(j.snapshot().status, JobStatus::Failed(_))
+        });
+    }
+
+    fn matching(&self, job_id: Option<u32>) -> Vec<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|j| job_id.map_or(true, |id| j.state.lock().unwrap().job_id == id))
+            .cloned()
+            .collect()
+    }
+
+    /// Pauses a single job, or the whole queue when `job_id` is `None`.
+    pub fn pause(&self, job_id: Option<u32>) {
+        match job_id {
+            Some(_) => self
+                .matching(job_id)
+                .iter()
+                .for_each(|j| *j.paused.lock().unwrap() = true),
+            None => *self.paused.lock().unwrap() = true,
+        }
+        self.changed.send_replace(());
+    }
+
+    pub fn resume(&self, job_id: Option<u32>) {
+        if job_id.is_none() {
+            *self.paused.lock().unwrap() = false;
+        }
+        self.matching(job_id)
+            .iter()
+            .for_each(|j| *j.paused.lock().unwrap() = false);
+        self.changed.send_replace(());
+    }
+
+    pub fn cancel(&self, job_id: Option<u32>) {
+        let jobs = self.matching(job_id);
+        jobs.iter()
+            .for_each(|j| *j.cancelled.lock().unwrap() = true);
+        // finished jobs have no task left to clean them up
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|j| !(jobs.iter().any(|c| Arc::ptr_eq(c, j)) && j.is_done()));
+        self.changed.send_replace(());
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    comic_info::{self, ComicInfo},
+    connectors::{Chapter, Connector, Format, Image, Manga, SearchItem},
+    downloads::{extension, DownloadError, Downloads},
+};
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy)]
+pub enum ExportGrouping {
+    Chapter,
+    Volume,
+}
+
+/// Strips characters that aren't allowed in file names on Windows.
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn group(chapters: Vec<Chapter>, grouping: ExportGrouping) -> Vec<Vec<Chapter>> {
+    let mut groups: Vec<Vec<Chapter>> = Vec::new();
+    for chapter in chapters {
+        match groups.last_mut() {
+            Some(last)
+                if matches!(grouping, ExportGrouping::Volume)
+                    && chapter.volume.is_some()
+                    && last[0].volume == chapter.volume =>
+            {
+                last.push(chapter)
+            }
+            _ => groups.push(vec![chapter]),
+        }
+    }
+    groups
+}
+
+fn write_cbz(path: &Path, info: &ComicInfo, pages: &[(String, Image)]) -> io::Result<()> {
+    let part = path.with_extension("cbz.part");
+    let mut zip = ZipWriter::new(File::create(&part)?);
+
+    zip.start_file(comic_info::FILE_NAME, FileOptions::default())?;
+    let xml = info
+        .to_xml()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    zip.write_all(xml.as_bytes())?;
+
+    // images are already compressed, so don't bother deflating them again
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for (name, image) in pages {
+        zip.start_file(name, stored)?;
+        zip.write_all(&image.bytes)?;
+    }
+    zip.finish()?;
+
+    fs::rename(part, path)
+}
+
+/// Writes the selected chapters of `manga` (or every chapter when
+/// `chapter_ids` is `None`) to CBZ archives in `dest`, one per chapter or per
+/// volume. Archives already in `dest` are kept, new ones with the same name
+/// get a number. Returns the paths of the archives that were written.
+pub async fn export_cbz(
+    connector: &dyn Connector,
+    downloads: &Downloads,
+    manga: Manga,
+    chapter_ids: Option<Vec<String>>,
+    grouping: ExportGrouping,
+    dest: &Path,
+) -> Result<Vec<PathBuf>, DownloadError> {
+    let mut chapters = match chapter_ids {
+        Some(ids) => ids
+            .into_iter()
+            .map(|id| {
+                manga
+                    .chapters
+                    .iter()
+                    .find(|c| c.id == id)
+                    .cloned()
+                    .ok_or(DownloadError::MissingChapter(id))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => manga.chapters,
+    };
+    chapters.sort_by(|a, b| a.number.partial_cmp(&b.number).unwrap_or(Ordering::Equal));
+
+    fs::create_dir_all(dest)?;
+    let series = file_name(&manga.desc.title);
+    let mut written = Vec::new();
+    for chapters in group(chapters, grouping) {
+        let volume = match grouping {
+            ExportGrouping::Chapter => None,
+            ExportGrouping::Volume => chapters[0].volume.as_deref(),
+        };
+        let (info, name, pages) = collect_group(
+            connector,
+            downloads,
+            &manga.desc,
+            &chapters,
+            volume,
+        )
+        .await?;
+        // the same chapter often comes from several groups
+        let scanlator = match (grouping, &chapters[0].scanlation_group) {
+            (ExportGrouping::Chapter, Some(group)) => format!(" [{group}]"),
+            _ => String::new(),
+        };
+        let stem = file_name(&format!("{series} - {name}{scanlator}"));
+        let mut path = dest.join(format!("{stem}.cbz"));
+        // covers both earlier exports and groups of this one with the same name
+        for n in 2.. {
+            if !path.exists() {
+                break;
+            }
+            path = dest.join(format!("{stem} ({n}).cbz"));
+        }
+        write_cbz(&path, &info, &pages)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+async fn collect_group(
+    connector: &dyn Connector,
+    downloads: &Downloads,
+    manga: &SearchItem,
+    chapters: &[Chapter],
+    volume: Option<&str>,
+) -> Result<(ComicInfo, String, Vec<(String, Image)>), DownloadError> {
+    let mut pages = Vec::new();
+    let mut format = Format::Normal;
+    for (i, chapter) in chapters.iter().enumerate() {
+        let (images, chapter_format) = downloads
+            .fetch_pages(connector, &chapter.id)
+            .await?;
+        format = chapter_format;
+        pages.extend(images.into_iter().enumerate().map(|(page, image)| {
+            let ext = extension(&image.content_type);
+            // prefix with the chapter so pages stay in order within a volume
+            let name = match volume {
+                None => format!("{:03}.{ext}", page + 1),
+                Some(_) => format!("{:03}-{:03}.{ext}", i + 1, page + 1),
+            };
+            (name, image)
+        }));
+    }
+
+    let (mut info, name) = match volume {
+        None => (
+            ComicInfo::chapter(manga, &chapters[0], format),
+            chapters[0].name.clone(),
+        ),
+        Some(volume) => (
+            ComicInfo {
+                title: Some(format!("Volume {volume}")),
+                number: Some(volume.to_string()),
+                volume: Some(volume.to_string()),
+                ..ComicInfo::new(manga, format)
+            },
+            format!("Vol.{volume}"),
+        ),
+    };
+    info.page_count = Some(pages.len() as u32);
+
+    Ok((info, name, pages))
+}
@@ -0,0 +1,419 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use reqwest::{
+    header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    RequestBuilder, StatusCode,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use specta::Type;
+
+use crate::{
+    connectors::{check_status, ConnectorError, HttpClient, Image},
+    updates::now_millis,
+};
+
+pub const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+/// Cached images younger than this are used without asking the server.
+const FRESH_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    size INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS images (
+    url TEXT PRIMARY KEY,
+    hash TEXT NOT NULL REFERENCES blobs (hash),
+    content_type TEXT NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    fetched_at INTEGER NOT NULL,
+    accessed_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS images_hash ON images (hash);
+";
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct CacheUsage {
+    pub bytes: u64,
+    pub images: u32,
+    pub max_bytes: u64,
+}
+
+struct Entry {
+    hash: String,
+    content_type: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    fetched_at: u64,
+}
+
+impl Entry {
+    fn is_fresh(&self) -> bool {
+        now_millis().saturating_sub(self.fetched_at) < FRESH_FOR.as_millis() as u64
+    }
+}
+
+/// Page and cover images on disk, stored once per content hash however
+/// many URLs point at them. The least recently used go first once the
+/// cache grows past its limit.
+pub struct ImageCache {
+    dir: PathBuf,
+    conn: Mutex<Connection>,
+    max_size: AtomicU64,
+}
+
+impl ImageCache {
+    pub fn open(dir: PathBuf, max_size: u64) -> rusqlite::Result<Self> {
+        if let Err(e) = fs::create_dir_all(dir.join("blobs")) {
+            println!("failed to create {}: {e}", dir.display());
+        }
+        let conn = Connection::open(dir.join("index.db"))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(ImageCache {
+            dir,
+            conn: Mutex::new(conn),
+            max_size: AtomicU64::new(max_size),
+        })
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("blobs").join(&hash[..2]).join(hash)
+    }
+
+    fn entry(&self, url: &str) -> rusqlite::Result<Option<Entry>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT hash, content_type, etag, last_modified, fetched_at FROM images WHERE url = ?1",
+                [url],
+                |row| {
+                    Ok(Entry {
+                        hash: row.get(0)?,
+                        content_type: row.get(1)?,
+                        etag: row.get(2)?,
+                        last_modified: row.get(3)?,
+                        fetched_at: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// The cached image, touching it for eviction. `None` if its file is
+    /// gone.
+    fn read(&self, url: &str, entry: &Entry, revalidated: bool) -> Option<Image> {
+        let bytes = fs::read(self.blob_path(&entry.hash)).ok()?;
+        let now = now_millis();
+        let sql = match revalidated {
+            true => "UPDATE images SET accessed_at = ?2, fetched_at = ?2 WHERE url = ?1",
+            false => "UPDATE images SET accessed_at = ?2 WHERE url = ?1",
+        };
+        if let Err(e) = self.conn.lock().unwrap().execute(sql, params![url, now]) {
+            println!("failed to touch cached {url}: {e}");
+        }
+        Some(Image {
+            content_type: entry.content_type.clone(),
+            bytes,
+        })
+    }
+
+    /// The cached image if it's fresh enough to use without asking the
+    /// server.
+    pub fn cached(&self, url: &str) -> Option<Image> {
+        let entry = self.entry(url).unwrap_or_else(|e| {
+            println!("failed to look up cached {url}: {e}");
+            None
+        })?;
+        if !entry.is_fresh() {
+            return None;
+        }
+        self.read(url, &entry, false)
+    }
+
+    fn store(
+        &self,
+        url: &str,
+        image: &Image,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> io::Result<()> {
+        let hash = format!("{:x}", Sha256::digest(&image.bytes));
+        let path = self.blob_path(&hash);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap())?;
+            let tmp = path.with_extension("part");
+            fs::write(&tmp, &image.bytes)?;
+            fs::rename(tmp, &path)?;
+        }
+
+        let now = now_millis();
+        {
+            let conn = self.conn.lock().unwrap();
+            conn.execute(
+                "INSERT OR IGNORE INTO blobs (hash, size) VALUES (?1, ?2)",
+                params![hash, image.bytes.len() as u64],
+            )
+            .map_err(to_io)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO images
+                 (url, hash, content_type, etag, last_modified, fetched_at, accessed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+                params![url, hash, image.content_type, etag, last_modified, now],
+            )
+            .map_err(to_io)?;
+        }
+        self.evict()
+    }
+
+    /// Fetches `url` with `request` sent by `client`, or takes it from the
+    /// cache. Stale images are revalidated with their ETag or Last-Modified,
+    /// and served as they are when the server can't be reached.
+    pub async fn fetch(
+        &self,
+        client: &HttpClient,
+        url: &str,
+        request: RequestBuilder,
+    ) -> Result<Image, ConnectorError> {
+        let entry = self.entry(url).unwrap_or_else(|e| {
+            println!("failed to look up cached {url}: {e}");
+            None
+        });
+        let mut request = request;
+        if let Some(entry) = &entry {
+            if entry.is_fresh() {
+                if let Some(image) = self.read(url, entry, false) {
+                    return Ok(image);
+                }
+            } else if self.blob_path(&entry.hash).exists() {
+                if let Some(etag) = &entry.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &entry.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+        }
+
+        let res = match client.send(request).await {
+            Ok(res) => res,
+            Err(e) => {
+                return entry
+                    .and_then(|entry| self.read(url, &entry, false))
+                    .ok_or_else(|| e.into())
+            }
+        };
+        if res.status() == StatusCode::NOT_MODIFIED {
+            if let Some(image) = entry.and_then(|entry| self.read(url, &entry, true)) {
+                return Ok(image);
+            }
+        }
+
+        let res = check_status(res)?;
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        // browsers sniff images anyway
+        let content_type = header(CONTENT_TYPE).unwrap_or_else(|| "image/jpeg".to_string());
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let image = Image {
+            content_type,
+            bytes: res.bytes().await?.to_vec(),
+        };
+        if let Err(e) = self.store(url, &image, etag.as_deref(), last_modified.as_deref()) {
+            println!("failed to cache {url}: {e}");
+        }
+        Ok(image)
+    }
+
+    pub fn usage(&self) -> rusqlite::Result<CacheUsage> {
+        let conn = self.conn.lock().unwrap();
+        let (bytes, images) = conn.query_row(
+            "SELECT COALESCE(SUM(size), 0), (SELECT COUNT(*) FROM images) FROM blobs",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(CacheUsage {
+            bytes,
+            images,
+            max_bytes: self.max_size.load(Ordering::Relaxed),
+        })
+    }
+
+    pub fn set_max_size(&self, bytes: u64) -> io::Result<()> {
+        self.max_size.store(bytes, Ordering::Relaxed);
+        self.evict()
+    }
+
+    /// Drops the least recently used images until the cache fits its limit.
+    fn evict(&self) -> io::Result<()> {
+        let max_size = self.max_size.load(Ordering::Relaxed);
+        let conn = self.conn.lock().unwrap();
+        let mut size: u64 = conn
+            .query_row("SELECT COALESCE(SUM(size), 0) FROM blobs", [], |row| row.get(0))
+            .map_err(to_io)?;
+        if size <= max_size {
+            return Ok(());
+        }
+
+        // blobs by when any of their URLs was last used
+        let blobs = {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT b.hash, b.size FROM blobs b LEFT JOIN images i ON i.hash = b.hash
+                     GROUP BY b.hash ORDER BY COALESCE(MAX(i.accessed_at), 0)",
+                )
+                .map_err(to_io)?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))
+                .map_err(to_io)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>().map_err(to_io)?
+        };
+        for (hash, blob_size) in blobs {
+            if size <= max_size {
+                break;
+            }
+            conn.execute("DELETE FROM images WHERE hash = ?1", [&hash])
+                .map_err(to_io)?;
+            conn.execute("DELETE FROM blobs WHERE hash = ?1", [&hash])
+                .map_err(to_io)?;
+            remove_file(&self.blob_path(&hash))?;
+            size = size.saturating_sub(blob_size);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("DELETE FROM images; DELETE FROM blobs;")
+            .map_err(to_io)?;
+        let blobs = self.dir.join("blobs");
+        match fs::remove_dir_all(&blobs) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        fs::create_dir_all(blobs)
+    }
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+
+    /// Every path answers with its own name as the image.
+    async fn server() -> TestServer {
+        TestServer::start(|req| {
+            let mut res = Response::status(200);
+            res.body = req.path.clone();
+            res.header("ETag", &format!("\"{}\"", req.path))
+        })
+        .await
+    }
+
+    async fn fetch(cache: &ImageCache, client: &HttpClient, url: &str) -> Image {
+        let image = cache.fetch(client, url, client.get(url)).await.unwrap();
+        // accessed_at is by the millisecond
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        image
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let server = server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::open(dir.path().to_path_buf(), 10).unwrap();
+        let client = HttpClient::new();
+        let url = |path: &str| format!("{}{path}", server.url);
+
+        fetch(&cache, &client, &url("/aaa")).await;
+        fetch(&cache, &client, &url("/bbb")).await;
+        // a fresh hit still counts as using it
+        fetch(&cache, &client, &url("/aaa")).await;
+        assert_eq!(server.requests().len(), 2);
+        let evicted = cache.entry(&url("/bbb")).unwrap().unwrap().hash;
+
+        fetch(&cache, &client, &url("/ccc")).await;
+        assert!(cache.entry(&url("/bbb")).unwrap().is_none());
+        assert!(!cache.blob_path(&evicted).exists());
+        assert!(cache.entry(&url("/aaa")).unwrap().is_some());
+        assert!(cache.entry(&url("/ccc")).unwrap().is_some());
+        let usage = cache.usage().unwrap();
+        assert_eq!((usage.bytes, usage.images), (8, 2));
+
+        cache.set_max_size(4).unwrap();
+        assert!(cache.entry(&url("/aaa")).unwrap().is_none());
+        assert!(cache.entry(&url("/ccc")).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_images() {
+        let server = TestServer::start(|req| {
+            let cached = req.headers.get("if-none-match").map(String::as_str) == Some("\"v1\"")
+                || req.headers.contains_key("if-modified-since");
+            if cached {
+                return Response::status(304);
+            }
+            let mut res = Response::status(200);
+            res.body = "page".to_string();
+            match req.path.as_str() {
+                "/etag" => res.header("ETag", "\"v1\""),
+                _ => res.header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::open(dir.path().to_path_buf(), DEFAULT_MAX_SIZE).unwrap();
+        let client = HttpClient::new();
+
+        for path in ["/etag", "/last-modified"] {
+            let url = format!("{}{path}", server.url);
+            fetch(&cache, &client, &url).await;
+            assert!(cache.cached(&url).is_some());
+            cache
+                .conn
+                .lock()
+                .unwrap()
+                .execute("UPDATE images SET fetched_at = 0 WHERE url = ?1", [&url])
+                .unwrap();
+            assert!(cache.cached(&url).is_none(), "stale images need revalidating");
+
+            let image = fetch(&cache, &client, &url).await;
+            assert_eq!(image.bytes, b"page");
+            assert!(cache.cached(&url).is_some(), "a 304 makes it fresh again");
+            let requests = server.requests_to(path);
+            assert_eq!(requests.len(), 2);
+            let conditional = ["if-none-match", "if-modified-since"];
+            assert!(conditional.iter().any(|h| requests[1].headers.contains_key(*h)));
+        }
+    }
+}
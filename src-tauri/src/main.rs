@@ -1,19 +1,65 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-#![feature(async_closure)]
 
-use connectors::{ChapterImages, Connectors, Format, Manga};
+use std::{
+    collections::HashSet,
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use backup::Backup;
+use connectors::{
+    local::LocalLibrary, mangadex_account::MangaDexAccount, ChapterImages,
+    ConnectorError, ConnectorSettings, Connectors, FilterCapabilities, Format, HttpClient, Manga,
+    Page, SearchFilters, SettingValue, Settings,
+};
+use downloads::{
+    manifest::DownloadedManga,
+    queue::{DownloadJob, DownloadQueue},
+    Downloads,
+};
+use export::ExportGrouping;
+use db::{Category, Database, HistoryEntry, ImportMode, TrackerLink};
 use futures::future::join_all;
-use prefs::StoredManga;
+use image_cache::{CacheUsage, ImageCache};
+use metadata::MetadataCache;
+use progress::{ChapterProgress, ResumePoint};
 use serde::Serialize;
-use specta::collect_types;
+use specta::{
+    collect_types,
+    ts::{BigIntExportBehavior, ExportConfiguration},
+    DefOpts, Type, TypeDefs,
+};
 use tauri::{Manager, State, AppHandle};
 use tauri_specta::ts;
+use sync::SyncReport;
+use tachiyomi::TachiyomiReport;
+use trackers::{TrackEntry, TrackStatus, TrackerInfo, TrackerItem, Trackers};
+use updates::{ChapterUpdate, Updater};
 
-use crate::{connectors::SearchItem, prefs::UserPrefs};
+use crate::{
+    connectors::SearchItem,
+    prefs::{StoredManga, UserPrefs},
+};
 
+mod backup;
+mod comic_info;
 mod connectors;
+mod db;
+mod downloads;
+mod export;
+mod image_cache;
+mod metadata;
 mod prefs;
+mod progress;
+mod protocol;
+mod sync;
+mod tachiyomi;
+#[cfg(test)]
+mod test_server;
+mod trackers;
+mod updates;
 
 #[tauri::command]
 #[specta::specta]
@@ -24,104 +70,229 @@ fn get_connectors(connectors: State<Connectors>) -> Vec<String> {
 #[tauri::command]
 #[specta::specta]
 async fn search_manga(
+    prefs: State<'_, UserPrefs>,
     connectors: State<'_, Connectors>,
     idx: u32,
     query: &str,
-) -> Result<Vec<SearchItem>, String> {
+    filters: SearchFilters,
+    page: u32,
+) -> Result<Page<SearchItem>, ConnectorError> {
+    let languages = prefs.inner.lock().unwrap().languages();
     connectors[idx]
-        .search(query)
+        .search(query, &filters, &languages, page)
         .await
-        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 #[specta::specta]
-async fn fetch_manga(
-    prefs: State<'_, UserPrefs>,
+async fn get_search_filters(
     connectors: State<'_, Connectors>,
     idx: u32,
+) -> Result<FilterCapabilities, ConnectorError> {
+    connectors[idx].filter_capabilities().await
+}
+
+#[tauri::command]
+#[specta::specta]
+/// Shows the cached manga right away, refreshing it in the background when
+/// it's stale, unless `force` asks to wait for the site.
+async fn fetch_manga(
+    app: AppHandle,
+    db: State<'_, Database>,
+    cache: State<'_, MetadataCache>,
+    idx: u32,
     id: &str,
-) -> Result<Manga, String> {
-    let read = {
-        let mut data = prefs.inner.lock().unwrap();
-        data.read.entry(idx).or_default().clone()
-    };
-    connectors[idx]
-        .fetch_manga(id)
-        .await
-        .map(|mut manga| {
-            manga.chapters.iter_mut().for_each(|c| {
-                c.read = Some(read.contains(&c.id));
-            });
-            manga
-        })
-        .map_err(|e| e.to_string())
+    force: bool,
+) -> Result<Manga, ConnectorError> {
+    let mut manga = cache.manga(&app, idx, id, force).await?;
+    metadata::mark_read(&db, idx, &mut manga)?;
+    Ok(manga)
 }
 
 #[tauri::command]
 #[specta::specta]
 async fn fetch_chapter(
+    db: State<'_, Database>,
     connectors: State<'_, Connectors>,
+    downloads: State<'_, Downloads>,
     idx: u32,
+    manga_id: &str,
     id: &str,
-) -> Result<ChapterImages, String> {
-    connectors[idx]
-        .fetch_chapter(id)
-        .await
-        .map_err(|e| e.to_string())
+) -> Result<ChapterImages, ConnectorError> {
+    let images = match downloads.load_chapter(connectors[idx].id(), id) {
+        Some(images) => images,
+        None => connectors[idx].fetch_chapter(id).await?,
+    };
+
+    let page = db
+        .progress(idx, id)
+        .ok()
+        .flatten()
+        .filter(|p| !p.completed)
+        .map_or(0, |p| p.page);
+    if let Err(e) = db.open_chapter(idx, manga_id, id, page) {
+        println!("failed to record history for {id}: {e}");
+    }
+
+    Ok(images)
 }
 
 #[tauri::command]
 #[specta::specta]
-async fn toggle_liked(
-    prefs: State<'_, UserPrefs>,
+/// Looks the manga up once for all of its queued chapters.
+async fn queue_download(
+    app: AppHandle,
+    queue: State<'_, DownloadQueue>,
+    downloads: State<'_, Downloads>,
+    cache: State<'_, MetadataCache>,
     connector_idx: u32,
-    id: &str,
-) -> Result<bool, ()> {
-    let mut data = prefs.inner.lock().unwrap();
-    let item = StoredManga {
-        manga_id: id.to_string(),
-        connector_idx,
-    };
-
-    let is_liked = data.liked.contains(&item);
-    if is_liked {
-        data.liked.retain(|it| *it != item);
-    } else {
-        data.liked.push(item);
+    manga_id: String,
+    chapter_ids: Vec<String>,
+) -> Result<Vec<DownloadJob>, ConnectorError> {
+    let mut manga = cache.manga(&app, connector_idx, &manga_id, false).await?;
+    // a stale cached copy may not list chapters released since
+    if !chapter_ids.iter().all(|id| manga.chapters.iter().any(|c| &c.id == id)) {
+        manga = cache.manga(&app, connector_idx, &manga_id, true).await?;
     }
+    let chapters = chapter_ids
+        .into_iter()
+        .map(|id| {
+            manga
+                .chapters
+                .iter()
+                .find(|c| c.id == id)
+                .cloned()
+                .ok_or(ConnectorError::NotFound(format!("chapter {id}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(queue.enqueue(&downloads, connector_idx, &manga.desc, chapters))
+}
 
-    drop(data);
-    prefs.save().unwrap();
+#[tauri::command]
+#[specta::specta]
+fn list_download_jobs(queue: State<'_, DownloadQueue>) -> Vec<DownloadJob> {
+    queue.list()
+}
 
-    Ok(!is_liked)
+#[tauri::command]
+#[specta::specta]
+fn pause_download(queue: State<'_, DownloadQueue>, job_id: Option<u32>) {
+    queue.pause(job_id)
 }
 
 #[tauri::command]
 #[specta::specta]
-async fn is_liked(
+fn resume_download(queue: State<'_, DownloadQueue>, job_id: Option<u32>) {
+    queue.resume(job_id)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn cancel_download(queue: State<'_, DownloadQueue>, job_id: Option<u32>) {
+    queue.cancel(job_id)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn list_downloads(downloads: State<'_, Downloads>) -> Vec<DownloadedManga> {
+    downloads.list()
+}
+
+#[tauri::command]
+#[specta::specta]
+fn delete_download(
+    connectors: State<'_, Connectors>,
+    downloads: State<'_, Downloads>,
+    connector_idx: u32,
+    manga_id: String,
+    chapter_id: Option<String>,
+) -> Result<(), ConnectorError> {
+    Ok(downloads.delete(connectors[connector_idx].id(), &manga_id, chapter_id.as_deref())?)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[allow(clippy::too_many_arguments)]
+async fn export_cbz(
     prefs: State<'_, UserPrefs>,
+    connectors: State<'_, Connectors>,
+    downloads: State<'_, Downloads>,
     connector_idx: u32,
     manga_id: String,
-) -> Result<bool, ()> {
-    let data = prefs.inner.lock().unwrap();
-    let manga = StoredManga {
-        connector_idx,
-        manga_id,
+    chapter_ids: Option<Vec<String>>,
+    grouping: ExportGrouping,
+    dest: String,
+) -> Result<Vec<String>, ConnectorError> {
+    let connector = &*connectors[connector_idx];
+    let languages = prefs.inner.lock().unwrap().languages();
+    let manga = match connector.fetch_manga(&manga_id, &languages).await {
+        Ok(manga) => manga,
+        Err(e) => downloads.load_manga(connector.id(), &manga_id).ok_or(e)?,
     };
-    Ok(data.liked.contains(&manga))
+    let paths = export::export_cbz(
+        connector,
+        &downloads,
+        manga,
+        chapter_ids,
+        grouping,
+        Path::new(&dest),
+    )
+    .await?;
+    Ok(paths
+        .into_iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect())
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn toggle_liked(
+    app: AppHandle,
+    db: State<'_, Database>,
+    connector_idx: u32,
+    id: &str,
+) -> Result<bool, ConnectorError> {
+    let liked = db.toggle_liked(connector_idx, id)?;
+    sync::push_follow(&app, connector_idx, id, liked);
+    Ok(liked)
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn is_liked(
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+) -> Result<bool, ConnectorError> {
+    Ok(db.is_liked(connector_idx, &manga_id)?)
 }
 
 #[tauri::command]
 #[specta::specta]
 async fn fetch_liked(
+    app: AppHandle,
     connectors: State<'_, Connectors>,
-    prefs: State<'_, UserPrefs>,
-) -> Result<Vec<(u32, Manga)>, ()> {
-    let data = prefs.inner.lock().unwrap().liked.clone();
-    Ok(join_all(data.iter().map(|saved| {
+    cache: State<'_, MetadataCache>,
+    db: State<'_, Database>,
+    category_id: Option<u32>,
+    force: bool,
+) -> Result<Vec<(u32, Manga)>, ConnectorError> {
+    let data = match category_id {
+        Some(id) => db.category_manga(id),
+        None => db.liked(),
+    }?;
+    let (app, connectors, cache) = (&app, &*connectors, &*cache);
+    Ok(join_all(data.iter().map(|saved| async move {
         let connector = &connectors[saved.connector_idx];
-        connector.fetch_manga(&saved.manga_id)
+        let mut manga = cache
+            .manga(app, saved.connector_idx, &saved.manga_id, force)
+            .await?;
+        // served from the image cache rather than fetched by the webview
+        // every time the library opens
+        if manga.desc.cover_url.starts_with("http") {
+            manga.desc.cover_url = protocol::image_url(connector.id(), &manga.desc.cover_url);
+        }
+        Ok::<_, ConnectorError>(manga)
     }))
     .await
     .into_iter()
@@ -132,51 +303,111 @@ async fn fetch_liked(
 
 #[tauri::command]
 #[specta::specta]
-fn set_manga_view(
-    prefs: State<'_, UserPrefs>,
+fn get_categories(db: State<'_, Database>) -> Result<Vec<Category>, ConnectorError> {
+    Ok(db.categories()?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn create_category(db: State<'_, Database>, name: String) -> Result<Category, ConnectorError> {
+    Ok(db.create_category(&name)?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn rename_category(db: State<'_, Database>, id: u32, name: String) -> Result<bool, ConnectorError> {
+    Ok(db.rename_category(id, &name)?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn delete_category(db: State<'_, Database>, id: u32) -> Result<bool, ConnectorError> {
+    Ok(db.delete_category(id)?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn reorder_categories(db: State<'_, Database>, ids: Vec<u32>) -> Result<(), ConnectorError> {
+    Ok(db.reorder_categories(&ids)?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_manga_categories(
+    db: State<'_, Database>,
     connector_idx: u32,
     manga_id: String,
-    long: bool,
-) -> Result<(), ()> {
-    let mut data = prefs.inner.lock().unwrap();
+) -> Result<Vec<u32>, ConnectorError> {
+    Ok(db.manga_categories(connector_idx, &manga_id)?)
+}
 
-    data.views.entry(connector_idx).or_default().insert(
-        manga_id,
-        match long {
-            true => Format::Long,
-            false => Format::Normal,
-        },
-    );
+#[tauri::command]
+#[specta::specta]
+fn set_manga_categories(
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+    category_ids: Vec<u32>,
+) -> Result<(), ConnectorError> {
+    Ok(db.set_manga_categories(connector_idx, &manga_id, &category_ids)?)
+}
 
-    drop(data);
-    prefs.save().unwrap();
+/// Orders a category's manga, given as `(connector_idx, manga_id)` pairs
+/// like `fetch_liked` returns them.
+#[tauri::command]
+#[specta::specta]
+fn reorder_category(
+    db: State<'_, Database>,
+    category_id: u32,
+    manga: Vec<(u32, String)>,
+) -> Result<(), ConnectorError> {
+    let manga = manga
+        .into_iter()
+        .map(|(connector_idx, manga_id)| StoredManga {
+            connector_idx,
+            manga_id,
+        })
+        .collect::<Vec<_>>();
+    Ok(db.reorder_category(category_id, &manga)?)
+}
 
-    Ok(())
+#[tauri::command]
+#[specta::specta]
+fn set_manga_view(
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+    long: bool,
+) -> Result<(), ConnectorError> {
+    let format = match long {
+        true => Format::Long,
+        false => Format::Normal,
+    };
+    Ok(db.set_view(connector_idx, &manga_id, format)?)
 }
 
 #[tauri::command]
 #[specta::specta]
 fn get_manga_view(
-    prefs: State<'_, UserPrefs>,
+    db: State<'_, Database>,
     connector_idx: u32,
     manga_id: String,
-) -> Result<Option<Format>, ()> {
-    let data = prefs.inner.lock().unwrap();
-    Ok(data.views.get(&connector_idx).and_then(|c| c.get(&manga_id)).copied())
+) -> Result<Option<Format>, ConnectorError> {
+    Ok(db.view(connector_idx, &manga_id)?)
 }
 
 #[tauri::command]
 #[specta::specta]
 fn mark_chapter_read(
     app: AppHandle,
-    prefs: State<'_, UserPrefs>,
+    db: State<'_, Database>,
     connector_idx: u32,
+    manga_id: String,
     chapter_id: String,
-) -> Result<(), ()> {
-    let mut data = prefs.inner.lock().unwrap();
-    data.read.entry(connector_idx).or_default().insert(chapter_id.clone());
-    drop(data);
-    prefs.save().unwrap();
+) -> Result<(), ConnectorError> {
+    db.mark_read(connector_idx, &manga_id, &chapter_id)?;
+    sync::push_read(&app, connector_idx, &manga_id, &chapter_id);
+    sync::push_tracker_progress(&app, connector_idx, &manga_id, &chapter_id);
 
     app.emit_all("chapter_read", ReadEvent {
         connector_idx, chapter_id
@@ -185,6 +416,488 @@ fn mark_chapter_read(
     Ok(())
 }
 
+/// Records the page the reader is on. Reaching the last page marks the
+/// chapter read.
+#[tauri::command]
+#[specta::specta]
+fn set_chapter_progress(
+    app: AppHandle,
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+    chapter_id: String,
+    page: u32,
+    total: u32,
+) -> Result<(), ConnectorError> {
+    let finished = db.set_progress(connector_idx, &manga_id, &chapter_id, page, total)?;
+    if finished {
+        sync::push_read(&app, connector_idx, &manga_id, &chapter_id);
+        sync::push_tracker_progress(&app, connector_idx, &manga_id, &chapter_id);
+        app.emit_all("chapter_read", ReadEvent {
+            connector_idx, chapter_id
+        }).unwrap();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_chapter_progress(
+    db: State<'_, Database>,
+    connector_idx: u32,
+    chapter_id: String,
+) -> Result<Option<ChapterProgress>, ConnectorError> {
+    Ok(db.progress(connector_idx, &chapter_id)?)
+}
+
+/// The chapter and page to open to pick the manga back up, `None` when
+/// every chapter has been read.
+#[tauri::command]
+#[specta::specta]
+async fn continue_reading(
+    app: AppHandle,
+    db: State<'_, Database>,
+    cache: State<'_, MetadataCache>,
+    connector_idx: u32,
+    manga_id: String,
+) -> Result<Option<ResumePoint>, ConnectorError> {
+    let manga = cache.manga(&app, connector_idx, &manga_id, false).await?;
+
+    let ids = manga.chapters.iter().map(|c| &c.id);
+    let progress = db.progress_of(connector_idx, ids.clone())?;
+    let read = db.read_chapters(connector_idx, ids)?;
+    Ok(progress::resume_point(manga.chapters, &progress, &read))
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_local_library(library: State<'_, LocalLibrary>) -> String {
+    library.get().to_string_lossy().to_string()
+}
+
+#[tauri::command]
+#[specta::specta]
+fn export_backup(
+    db: State<'_, Database>,
+    prefs: State<'_, UserPrefs>,
+    path: String,
+) -> Result<(), ConnectorError> {
+    let library = db.backup()?;
+    let backup = Backup::new(library, &prefs.inner.lock().unwrap())?;
+    backup::write(Path::new(&path), &backup)
+}
+
+#[tauri::command]
+#[specta::specta]
+#[allow(clippy::too_many_arguments)]
+fn import_backup(
+    db: State<'_, Database>,
+    prefs: State<'_, UserPrefs>,
+    connectors: State<'_, Connectors>,
+    library: State<'_, LocalLibrary>,
+    cache: State<'_, Arc<ImageCache>>,
+    path: String,
+    mode: ImportMode,
+) -> Result<(), ConnectorError> {
+    let backup = backup::read(Path::new(&path))?;
+    let settings = backup.settings()?;
+    db.restore(&backup.library, mode)?;
+
+    {
+        let mut data = prefs.inner.lock().unwrap();
+        backup::restore_settings(&mut data, settings, mode);
+        if let Some(path) = &data.local_library {
+            library.set(path.clone());
+        }
+        connectors.apply_settings(&data.connector_settings);
+        cache.set_max_size(data.image_cache_size)?;
+    }
+    Ok(prefs.save()?)
+}
+
+/// Merges the library from a Tachiyomi/Mihon backup into ours.
+#[tauri::command]
+#[specta::specta]
+fn import_tachiyomi(
+    db: State<'_, Database>,
+    path: String,
+) -> Result<TachiyomiReport, ConnectorError> {
+    let (library, report) = tachiyomi::read(Path::new(&path))?;
+    db.restore(&library, ImportMode::Merge)?;
+    Ok(report)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn set_local_library(
+    prefs: State<'_, UserPrefs>,
+    library: State<'_, LocalLibrary>,
+    path: String,
+) -> Result<(), ConnectorError> {
+    let path = PathBuf::from(path);
+    create_dir_all(&path)?;
+    library.set(path.clone());
+
+    prefs.inner.lock().unwrap().local_library = Some(path);
+    Ok(prefs.save()?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_connector_settings(
+    prefs: State<'_, UserPrefs>,
+    connectors: State<'_, Connectors>,
+    idx: u32,
+) -> ConnectorSettings {
+    let connector = &connectors[idx];
+    let fields = connector.settings_schema();
+    let data = prefs.inner.lock().unwrap();
+    let settings = Settings::resolve(&fields, data.connector_settings.get(connector.id()));
+    ConnectorSettings {
+        values: settings.values().clone(),
+        fields,
+    }
+}
+
+/// Changes one of a connector's own settings, taking effect right away.
+/// Its cached manga are refreshed next time, as their chapters may differ now.
+#[tauri::command]
+#[specta::specta]
+fn set_connector_setting(
+    prefs: State<'_, UserPrefs>,
+    db: State<'_, Database>,
+    connectors: State<'_, Connectors>,
+    idx: u32,
+    key: String,
+    value: SettingValue,
+) -> Result<(), ConnectorError> {
+    let connector = connectors
+        .get(idx)
+        .ok_or_else(|| ConnectorError::NotFound(format!("connector {idx}")))?;
+    let fields = connector.settings_schema();
+    let field = fields
+        .iter()
+        .find(|f| f.key == key)
+        .ok_or_else(|| ConnectorError::NotFound(format!("{} setting {key}", connector.name())))?;
+    if !field.accepts(&value) {
+        return Err(ConnectorError::Invalid(format!("value for {}", field.name)));
+    }
+
+    let mut data = prefs.inner.lock().unwrap();
+    let saved = data
+        .connector_settings
+        .entry(connector.id().to_string())
+        .or_default();
+    match value == field.default {
+        true => saved.remove(&key),
+        false => saved.insert(key, value),
+    };
+    if saved.is_empty() {
+        data.connector_settings.remove(connector.id());
+    }
+    connector.apply_settings(&Settings::resolve(
+        &fields,
+        data.connector_settings.get(connector.id()),
+    ));
+    drop(data);
+    prefs.save()?;
+    db.expire_manga_cache(Some(idx))?;
+    Ok(db.reseed_known_chapters(Some(idx))?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_image_cache_usage(cache: State<'_, Arc<ImageCache>>) -> Result<CacheUsage, ConnectorError> {
+    Ok(cache.usage()?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn clear_image_cache(cache: State<'_, Arc<ImageCache>>) -> Result<(), ConnectorError> {
+    Ok(cache.clear()?)
+}
+
+/// Limits the image cache to `bytes`, evicting the least recently used
+/// images right away if it's over.
+#[tauri::command]
+#[specta::specta]
+fn set_image_cache_size(
+    prefs: State<'_, UserPrefs>,
+    cache: State<'_, Arc<ImageCache>>,
+    bytes: u64,
+) -> Result<(), ConnectorError> {
+    cache.set_max_size(bytes)?;
+    prefs.inner.lock().unwrap().image_cache_size = bytes;
+    Ok(prefs.save()?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_languages(prefs: State<'_, UserPrefs>) -> Vec<String> {
+    prefs.inner.lock().unwrap().languages()
+}
+
+#[tauri::command]
+#[specta::specta]
+fn set_languages(
+    prefs: State<'_, UserPrefs>,
+    db: State<'_, Database>,
+    languages: Vec<String>,
+) -> Result<(), ConnectorError> {
+    let mut data = prefs.inner.lock().unwrap();
+    let previous = data.languages().into_iter().collect::<HashSet<_>>();
+    data.languages = languages;
+    let changed = data.languages().into_iter().collect::<HashSet<_>>() != previous;
+    drop(data);
+    prefs.save()?;
+    if changed {
+        // cached chapter lists are in the old languages, and chapters in
+        // the new ones aren't new releases
+        db.expire_manga_cache(None)?;
+        db.reseed_known_chapters(None)?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_manga_cache_ttl(prefs: State<'_, UserPrefs>) -> u64 {
+    prefs.inner.lock().unwrap().manga_cache_ttl
+}
+
+/// Sets how many seconds fetched manga are shown before being refreshed.
+#[tauri::command]
+#[specta::specta]
+fn set_manga_cache_ttl(prefs: State<'_, UserPrefs>, seconds: u64) -> Result<(), ConnectorError> {
+    prefs.inner.lock().unwrap().manga_cache_ttl = seconds;
+    Ok(prefs.save()?)
+}
+
+/// Logs in to MangaDex with a personal API client's credentials.
+#[tauri::command]
+#[specta::specta]
+async fn mangadex_login(
+    account: State<'_, MangaDexAccount>,
+    username: String,
+    password: String,
+    client_id: String,
+    client_secret: String,
+) -> Result<(), ConnectorError> {
+    account
+        .login(&username, &password, &client_id, &client_secret)
+        .await
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn mangadex_logout(account: State<'_, MangaDexAccount>) -> Result<(), ConnectorError> {
+    account.logout().await
+}
+
+/// The name of the logged in MangaDex user.
+#[tauri::command]
+#[specta::specta]
+async fn mangadex_user(account: State<'_, MangaDexAccount>) -> Result<Option<String>, ()> {
+    Ok(account.username().await)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_mangadex_follow(prefs: State<'_, UserPrefs>) -> bool {
+    prefs.inner.lock().unwrap().mangadex_follow
+}
+
+#[tauri::command]
+#[specta::specta]
+fn set_mangadex_follow(prefs: State<'_, UserPrefs>, follow: bool) -> Result<(), ConnectorError> {
+    prefs.inner.lock().unwrap().mangadex_follow = follow;
+    Ok(prefs.save()?)
+}
+
+/// Likes everything followed on MangaDex and marks the chapters read there
+/// read here too.
+#[tauri::command]
+#[specta::specta]
+async fn sync_mangadex(
+    account: State<'_, MangaDexAccount>,
+    db: State<'_, Database>,
+    connectors: State<'_, Connectors>,
+) -> Result<SyncReport, ConnectorError> {
+    sync::pull_mangadex(&account, &db, &connectors).await
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_trackers(trackers: State<'_, Trackers>) -> Vec<TrackerInfo> {
+    trackers.info()
+}
+
+/// The page to send the user to for logging in to a tracker. What it hands
+/// back goes to `tracker_login`.
+#[tauri::command]
+#[specta::specta]
+fn tracker_auth_url(trackers: State<'_, Trackers>, tracker: String) -> Result<String, ConnectorError> {
+    trackers.get(&tracker)?.auth_url()
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn tracker_login(
+    trackers: State<'_, Trackers>,
+    tracker: String,
+    code: String,
+) -> Result<(), ConnectorError> {
+    trackers.login(&tracker, &code).await
+}
+
+#[tauri::command]
+#[specta::specta]
+fn tracker_logout(trackers: State<'_, Trackers>, tracker: String) -> Result<(), ConnectorError> {
+    trackers.logout(&tracker)
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn search_tracker(
+    trackers: State<'_, Trackers>,
+    tracker: String,
+    query: String,
+) -> Result<Vec<TrackerItem>, ConnectorError> {
+    let (tracker, token) = trackers.session(&tracker).await?;
+    tracker.search(&token, &query).await
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_tracker_links(
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+) -> Result<Vec<TrackerLink>, ConnectorError> {
+    Ok(db.tracker_links(connector_idx, &manga_id)?)
+}
+
+/// Links a manga to a tracker entry found with `search_tracker`, taking over
+/// the entry if it's on the user's list already.
+#[tauri::command]
+#[specta::specta]
+async fn link_tracker(
+    trackers: State<'_, Trackers>,
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+    tracker: String,
+    item: TrackerItem,
+) -> Result<TrackerLink, ConnectorError> {
+    let link = TrackerLink {
+        tracker,
+        remote_id: item.id,
+        title: item.title,
+        entry: TrackEntry {
+            status: TrackStatus::Reading,
+            score: None,
+            progress: 0,
+        },
+        updated_at: 0,
+        synced_at: 0,
+    };
+    db.link_tracker(connector_idx, &manga_id, &link)?;
+    sync::sync_tracker(&trackers, &db, connector_idx, &manga_id, &link).await?;
+    db.tracker_link(connector_idx, &manga_id, &link.tracker)?
+        .ok_or_else(|| ConnectorError::NotFound(format!("{} link of {manga_id}", link.tracker)))
+}
+
+#[tauri::command]
+#[specta::specta]
+fn unlink_tracker(
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+    tracker: String,
+) -> Result<bool, ConnectorError> {
+    Ok(db.unlink_tracker(connector_idx, &manga_id, &tracker)?)
+}
+
+/// Changes the status, score or progress of a linked manga and pushes it to
+/// the tracker. If that fails the change is kept for the next sync.
+#[tauri::command]
+#[specta::specta]
+async fn set_tracker_entry(
+    trackers: State<'_, Trackers>,
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+    tracker: String,
+    entry: TrackEntry,
+) -> Result<TrackEntry, ConnectorError> {
+    db.set_tracker_entry(connector_idx, &manga_id, &tracker, &entry)?;
+    let link = db
+        .tracker_link(connector_idx, &manga_id, &tracker)?
+        .ok_or_else(|| ConnectorError::NotFound(format!("{tracker} link of {manga_id}")))?;
+    sync::sync_tracker(&trackers, &db, connector_idx, &manga_id, &link).await
+}
+
+/// Syncs every tracker the manga is linked to both ways.
+#[tauri::command]
+#[specta::specta]
+async fn sync_trackers(
+    trackers: State<'_, Trackers>,
+    db: State<'_, Database>,
+    connector_idx: u32,
+    manga_id: String,
+) -> Result<Vec<TrackerLink>, ConnectorError> {
+    let links = db.tracker_links(connector_idx, &manga_id)?;
+    for link in &links {
+        sync::sync_tracker(&trackers, &db, connector_idx, &manga_id, link).await?;
+    }
+    Ok(db.tracker_links(connector_idx, &manga_id)?)
+}
+
+/// Checks the library for new chapters right away instead of waiting for
+/// the next scheduled check.
+#[tauri::command]
+#[specta::specta]
+async fn check_updates(
+    app: AppHandle,
+    updater: State<'_, Updater>,
+) -> Result<Vec<ChapterUpdate>, ConnectorError> {
+    Ok(updater.check(&app).await?)
+}
+
+/// New chapters found in the library, newest first.
+#[tauri::command]
+#[specta::specta]
+fn get_updates(db: State<'_, Database>) -> Result<Vec<ChapterUpdate>, ConnectorError> {
+    Ok(db.updates()?)
+}
+
+/// Reading history, newest first. Times are milliseconds since the epoch,
+/// `from` inclusive and `to` exclusive; leave any filter out to not filter
+/// on it.
+#[tauri::command]
+#[specta::specta]
+fn get_history(
+    db: State<'_, Database>,
+    from: Option<u64>,
+    to: Option<u64>,
+    connector_idx: Option<u32>,
+    manga_id: Option<String>,
+) -> Result<Vec<HistoryEntry>, ConnectorError> {
+    Ok(db.history(from, to, connector_idx, manga_id.as_deref())?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn delete_history_entry(db: State<'_, Database>, id: u32) -> Result<bool, ConnectorError> {
+    Ok(db.delete_history_entry(id)?)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn clear_history(db: State<'_, Database>) -> Result<(), ConnectorError> {
+    Ok(db.clear_history()?)
+}
+
 #[derive(Clone, Serialize)]
 struct ReadEvent {
     connector_idx: u32,
@@ -193,43 +906,221 @@ struct ReadEvent {
 
 fn main() {
     #[cfg(debug_assertions)]
-    ts::export(
-        collect_types![
+    {
+        // command errors aren't part of the generated signatures, so register
+        // them up front to get their types exported too
+        let mut type_map = TypeDefs::default();
+        ConnectorError::reference(
+            DefOpts {
+                parent_inline: false,
+                type_map: &mut type_map,
+            },
+            &[],
+        )
+        .unwrap();
+
+        let types = collect_types![
+            type_map: type_map,
             get_connectors,
             search_manga,
+            get_search_filters,
             fetch_manga,
             fetch_chapter,
+            queue_download,
+            list_download_jobs,
+            pause_download,
+            resume_download,
+            cancel_download,
+            list_downloads,
+            delete_download,
+            export_cbz,
             toggle_liked,
             is_liked,
             fetch_liked,
+            get_categories,
+            create_category,
+            rename_category,
+            delete_category,
+            reorder_categories,
+            get_manga_categories,
+            set_manga_categories,
+            reorder_category,
             set_manga_view,
             get_manga_view,
             mark_chapter_read,
-        ],
-        "../src/lib/backend.ts",
-    )
-    .unwrap();
+            set_chapter_progress,
+            get_chapter_progress,
+            continue_reading,
+            get_history,
+            delete_history_entry,
+            clear_history,
+            get_local_library,
+            export_backup,
+            import_backup,
+            import_tachiyomi,
+            set_local_library,
+            get_connector_settings,
+            set_connector_setting,
+            get_image_cache_usage,
+            clear_image_cache,
+            set_image_cache_size,
+            get_manga_cache_ttl,
+            set_manga_cache_ttl,
+            get_languages,
+            set_languages,
+            mangadex_login,
+            mangadex_logout,
+            mangadex_user,
+            get_mangadex_follow,
+            set_mangadex_follow,
+            sync_mangadex,
+            get_trackers,
+            tracker_auth_url,
+            tracker_login,
+            tracker_logout,
+            search_tracker,
+            get_tracker_links,
+            link_tracker,
+            unlink_tracker,
+            set_tracker_entry,
+            sync_trackers,
+            check_updates,
+            get_updates,
+        ]
+        .unwrap();
+        // timestamps are u64 milliseconds, which stay well within what a JS
+        // number can hold exactly
+        ts::export_with_cfg(
+            types,
+            ExportConfiguration::default().bigint(BigIntExportBehavior::Number),
+            "../src/lib/backend.ts",
+        )
+        .unwrap();
+    }
 
     tauri::Builder::default()
         .setup(|app| {
             let data_dir = app.path_resolver().app_data_dir().unwrap();
             println!("data_dir={data_dir:?}");
-            let _handle = app.handle();
-            app.manage(UserPrefs::new(data_dir));
-            app.manage(Connectors::new());
+            let handle = app.handle();
+            app.manage(DownloadQueue::new(handle.clone()));
+            create_dir_all(&data_dir)?;
+            let mut db = Database::open(&data_dir.join("library.db"))?;
+            // before the settings load, which would move a corrupt file aside,
+            // and are saved without the library
+            if db::import_userdata(&db, &data_dir.join("userdata.json"))? {
+                println!("imported userdata.json into the library database");
+            }
+            let prefs = UserPrefs::new(data_dir.clone());
+            let library = LocalLibrary::new(
+                prefs
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .local_library
+                    .clone()
+                    .unwrap_or_else(|| data_dir.join("library")),
+            );
+            let (settings, cache_size) = {
+                let data = prefs.inner.lock().unwrap();
+                (data.connector_settings.clone(), data.image_cache_size)
+            };
+            let cache = Arc::new(ImageCache::open(data_dir.join("cache").join("images"), cache_size)?);
+            let client = HttpClient::new();
+            let connectors = Connectors::new(
+                library.clone(),
+                &settings,
+                client.clone(),
+                cache.clone(),
+            );
+
+            db.register_connectors(&connectors)?;
+            let downloads = Downloads::new(data_dir.clone());
+            if let Err(e) = downloads.migrate_connector_dirs() {
+                println!("failed to migrate downloads: {e}");
+            }
+
+            app.manage(db);
+            app.manage(downloads);
+            app.manage(prefs);
+            app.manage(connectors);
+            app.manage(library);
+            app.manage(cache);
+            app.manage(MetadataCache::new());
+            app.manage(protocol::Loads::default());
+            app.manage(Trackers::new(client.clone(), data_dir.clone()));
+            app.manage(MangaDexAccount::new(client, data_dir));
+            app.manage(Updater::new());
+            Updater::spawn(handle);
             Ok(())
         })
+        .register_uri_scheme_protocol(protocol::SCHEME, protocol::handle)
         .invoke_handler(tauri::generate_handler![
             get_connectors,
             search_manga,
+            get_search_filters,
             fetch_manga,
             fetch_chapter,
+            queue_download,
+            list_download_jobs,
+            pause_download,
+            resume_download,
+            cancel_download,
+            list_downloads,
+            delete_download,
+            export_cbz,
             toggle_liked,
             is_liked,
             fetch_liked,
+            get_categories,
+            create_category,
+            rename_category,
+            delete_category,
+            reorder_categories,
+            get_manga_categories,
+            set_manga_categories,
+            reorder_category,
             set_manga_view,
             get_manga_view,
             mark_chapter_read,
+            set_chapter_progress,
+            get_chapter_progress,
+            continue_reading,
+            get_history,
+            delete_history_entry,
+            clear_history,
+            get_local_library,
+            export_backup,
+            import_backup,
+            import_tachiyomi,
+            set_local_library,
+            get_connector_settings,
+            set_connector_setting,
+            get_image_cache_usage,
+            clear_image_cache,
+            set_image_cache_size,
+            get_manga_cache_ttl,
+            set_manga_cache_ttl,
+            get_languages,
+            set_languages,
+            mangadex_login,
+            mangadex_logout,
+            mangadex_user,
+            get_mangadex_follow,
+            set_mangadex_follow,
+            sync_mangadex,
+            get_trackers,
+            tracker_auth_url,
+            tracker_login,
+            tracker_logout,
+            search_tracker,
+            get_tracker_links,
+            link_tracker,
+            unlink_tracker,
+            set_tracker_entry,
+            sync_trackers,
+            check_updates,
+            get_updates,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
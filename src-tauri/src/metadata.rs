@@ -0,0 +1,278 @@
+use std::{collections::HashSet, sync::Mutex, time::Duration};
+
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+
+use crate::{
+    connectors::{ConnectorError, Connectors, Manga},
+    db::Database,
+    downloads::Downloads,
+    prefs::UserPrefs,
+    updates::now_millis,
+};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Serialize)]
+struct MangaUpdatedEvent {
+    connector_idx: u32,
+    manga: Manga,
+}
+
+/// Serves manga from the library database's cache, refreshing the ones
+/// older than the TTL in the background while the cached copy is shown.
+pub struct MetadataCache {
+    /// Manga being refreshed right now, so opening one twice in a row
+    /// doesn't fetch it twice.
+    refreshing: Mutex<HashSet<(u32, String)>>,
+}
+
+impl MetadataCache {
+    pub fn new() -> Self {
+        MetadataCache {
+            refreshing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The cached manga, or the site's when it isn't cached yet or `force`
+    /// is set. Falls back on a download or the cache when the site can't be
+    /// reached. Read flags are left for the caller to fill in.
+    pub async fn manga(
+        &self,
+        app: &AppHandle,
+        connector_idx: u32,
+        manga_id: &str,
+        force: bool,
+    ) -> Result<Manga, ConnectorError> {
+        let (manga, stale) = Sources::new(app)
+            .lookup(connector_idx, manga_id, force)
+            .await?;
+        if stale {
+            self.refresh(app, connector_idx, manga_id);
+        }
+        Ok(manga)
+    }
+
+    fn refresh(&self, app: &AppHandle, connector_idx: u32, manga_id: &str) {
+        let key = (connector_idx, manga_id.to_string());
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            Sources::new(&app).refresh(connector_idx, &key.1).await;
+            app.state::<MetadataCache>().refreshing.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+/// The app state the cache works with, borrowed apart from the app so it
+/// can be tested without one.
+struct Sources<'a> {
+    db: &'a Database,
+    connectors: &'a Connectors,
+    downloads: &'a Downloads,
+    languages: Vec<String>,
+    /// Seconds a cached manga is shown as it is.
+    ttl: u64,
+    /// Tells the frontend about a manga that changed.
+    updated: Box<dyn Fn(MangaUpdatedEvent) + Send + Sync + 'a>,
+}
+
+impl<'a> Sources<'a> {
+    fn new(app: &'a AppHandle) -> Self {
+        let (languages, ttl) = {
+            let prefs = app.state::<UserPrefs>();
+            let prefs = prefs.inner.lock().unwrap();
+            (prefs.languages(), prefs.manga_cache_ttl)
+        };
+        Sources {
+            db: app.state::<Database>().inner(),
+            connectors: app.state::<Connectors>().inner(),
+            downloads: app.state::<Downloads>().inner(),
+            languages,
+            ttl,
+            updated: Box::new(move |event| app.emit_all("manga_updated", event).unwrap()),
+        }
+    }
+
+    /// The manga, and whether it's a cached copy past the TTL that should
+    /// be refreshed.
+    async fn lookup(
+        &self,
+        connector_idx: u32,
+        manga_id: &str,
+        force: bool,
+    ) -> Result<(Manga, bool), ConnectorError> {
+        let cached = self.db.cached_manga(connector_idx, manga_id).unwrap_or_else(|e| {
+            println!("failed to read cached {manga_id}: {e}");
+            None
+        });
+        if let Some((manga, fetched_at)) = cached.as_ref().filter(|_| !force) {
+            let stale = now_millis().saturating_sub(*fetched_at) >= self.ttl * 1000;
+            return Ok((manga.clone(), stale));
+        }
+
+        match self.fetch(connector_idx, manga_id).await {
+            Ok(manga) => {
+                self.store(connector_idx, &manga);
+                Ok((manga, false))
+            }
+            Err(e) => self
+                .downloads
+                .load_manga(self.connectors[connector_idx].id(), manga_id)
+                .or_else(|| cached.map(|(manga, _)| manga))
+                .map(|manga| (manga, false))
+                .ok_or(e),
+        }
+    }
+
+    async fn refresh(&self, connector_idx: u32, manga_id: &str) {
+        match self.fetch(connector_idx, manga_id).await {
+            Ok(manga) => self.store(connector_idx, &manga),
+            Err(e) => println!("failed to refresh {manga_id}: {e}"),
+        }
+    }
+
+    async fn fetch(&self, connector_idx: u32, manga_id: &str) -> Result<Manga, ConnectorError> {
+        self.connectors[connector_idx]
+            .fetch_manga(manga_id, &self.languages)
+            .await
+    }
+
+    fn store(&self, connector_idx: u32, manga: &Manga) {
+        let previous = self.db.cached_manga(connector_idx, &manga.desc.id).ok().flatten();
+        if let Err(e) = self.db.cache_manga(connector_idx, manga) {
+            println!("failed to cache {}: {e}", manga.desc.id);
+        }
+        if previous.map_or(true, |(previous, _)| previous == *manga) {
+            return;
+        }
+
+        let mut manga = manga.clone();
+        if let Err(e) = mark_read(self.db, connector_idx, &mut manga) {
+            println!("failed to read the read chapters of {}: {e}", manga.desc.id);
+        }
+        (self.updated)(MangaUpdatedEvent {
+            connector_idx,
+            manga,
+        });
+    }
+}
+
+/// Caches a freshly fetched manga. When it differs from the cached copy,
+/// a `manga_updated` event carries it to the frontend with its read flags.
+pub fn store(app: &AppHandle, connector_idx: u32, manga: &Manga) {
+    Sources::new(app).store(connector_idx, manga);
+}
+
+/// Fills in which chapters have been read.
+pub fn mark_read(db: &Database, connector_idx: u32, manga: &mut Manga) -> rusqlite::Result<()> {
+    let read = db.read_chapters(connector_idx, manga.chapters.iter().map(|c| &c.id))?;
+    manga.chapters.iter_mut().for_each(|c| {
+        c.read = Some(read.contains(&c.id));
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+    use super::*;
+    use crate::{
+        connectors::{local::LocalLibrary, HttpClient},
+        image_cache::ImageCache,
+    };
+
+    const LOCAL: u32 = 2;
+
+    fn setup(dir: &Path) -> (Database, Connectors, Downloads) {
+        let db = Database::in_memory(&["mangadex", "mangakakalot", "local"]);
+        let connectors = Connectors::new(
+            LocalLibrary::new(dir.join("library")),
+            &HashMap::new(),
+            HttpClient::new(),
+            Arc::new(ImageCache::open(dir.join("cache"), 0).unwrap()),
+        );
+        (db, connectors, Downloads::new(dir.to_path_buf()))
+    }
+
+    fn sources<'a>(
+        db: &'a Database,
+        connectors: &'a Connectors,
+        downloads: &'a Downloads,
+    ) -> Sources<'a> {
+        Sources {
+            db,
+            connectors,
+            downloads,
+            languages: Vec::new(),
+            ttl: DEFAULT_TTL.as_secs(),
+            updated: Box::new(|_| ()),
+        }
+    }
+
+    /// Adds a chapter to the local library, which shows when the manga is
+    /// fetched again.
+    fn add_chapter(dir: &Path, chapter: &str) {
+        let chapter = dir.join("library").join("Series").join(chapter);
+        fs::create_dir_all(&chapter).unwrap();
+        fs::write(chapter.join("1.jpg"), b"page").unwrap();
+    }
+
+    async fn chapters(sources: &Sources<'_>, force: bool) -> (usize, bool) {
+        let (manga, stale) = sources.lookup(LOCAL, "Series", force).await.unwrap();
+        (manga.chapters.len(), stale)
+    }
+
+    #[tokio::test]
+    async fn serves_cached_until_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, connectors, downloads) = setup(dir.path());
+        let mut sources = sources(&db, &connectors, &downloads);
+
+        add_chapter(dir.path(), "Ch 1");
+        assert_eq!(chapters(&sources, false).await, (1, false));
+
+        // a fresh hit doesn't fetch
+        add_chapter(dir.path(), "Ch 2");
+        assert_eq!(chapters(&sources, false).await, (1, false));
+        assert_eq!(chapters(&sources, true).await, (2, false));
+
+        // a stale hit still shows the cached copy, and asks for a refresh
+        add_chapter(dir.path(), "Ch 3");
+        sources.ttl = 0;
+        assert_eq!(chapters(&sources, false).await, (2, true));
+        sources.refresh(LOCAL, "Series").await;
+        sources.ttl = DEFAULT_TTL.as_secs();
+        assert_eq!(chapters(&sources, false).await, (3, false));
+
+        // expiring makes the next hit refresh
+        add_chapter(dir.path(), "Ch 4");
+        db.expire_manga_cache(Some(LOCAL)).unwrap();
+        assert_eq!(chapters(&sources, false).await, (3, true));
+        sources.refresh(LOCAL, "Series").await;
+        assert_eq!(chapters(&sources, false).await, (4, false));
+    }
+
+    #[tokio::test]
+    async fn tells_frontend_about_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, connectors, downloads) = setup(dir.path());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let sources = Sources {
+            updated: Box::new(move |event| recorded.lock().unwrap().push(event.manga.chapters.len())),
+            ..sources(&db, &connectors, &downloads)
+        };
+
+        add_chapter(dir.path(), "Ch 1");
+        sources.refresh(LOCAL, "Series").await;
+        sources.refresh(LOCAL, "Series").await;
+        add_chapter(dir.path(), "Ch 2");
+        sources.refresh(LOCAL, "Series").await;
+        // nothing to tell until a cached copy changes
+        assert_eq!(*events.lock().unwrap(), [2]);
+    }
+}
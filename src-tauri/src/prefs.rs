@@ -1,12 +1,84 @@
-use std::{sync::Mutex, path::PathBuf, fs::{File, create_dir_all}, collections::{HashMap, HashSet}};
+use std::{
+    collections::HashMap,
+    fs::{self, create_dir_all, File},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::connectors::Format;
+use crate::{
+    connectors::SettingValue,
+    image_cache, metadata,
+    updates::now_millis,
+};
+
+/// How many previous versions of `userdata.json` to keep around.
+const MAX_BACKUPS: usize = 5;
+
+/// Upgrades the raw JSON of a file at version `i` to version `i + 1`. Only
+/// ever append to this list; `PrefData::version` is its length.
+const MIGRATIONS: &[fn(&mut Value)] = &[
+    // 0 -> 1: the library moved to the database, which imported it already
+    |data| {
+        if let Some(data) = data.as_object_mut() {
+            for key in ["liked", "views", "read", "progress", "known_chapters", "updates", "history"] {
+                data.remove(key);
+            }
+        }
+    },
+    // 1 -> 2: connectors' addresses became settings like the rest
+    |data| {
+        let data = match data.as_object_mut() {
+            Some(data) => data,
+            None => return,
+        };
+        let configs = match data.remove("connectors") {
+            Some(Value::Object(configs)) => configs,
+            _ => return,
+        };
+        let settings = data
+            .entry("connector_settings")
+            .or_insert_with(|| Value::Object(Default::default()));
+        let settings = match settings.as_object_mut() {
+            Some(settings) => settings,
+            None => return,
+        };
+        for (id, config) in configs {
+            let mut values = serde_json::Map::new();
+            for key in ["base_url", "image_proxy"] {
+                if let Some(url) = config.get(key).and_then(Value::as_str) {
+                    values.insert(key.to_string(), serde_json::json!({ "String": url }));
+                }
+            }
+            match config.get("mirrors").and_then(Value::as_array) {
+                Some(mirrors) if !mirrors.is_empty() => {
+                    values.insert("mirrors".to_string(), serde_json::json!({ "StringList": mirrors }));
+                }
+                _ => (),
+            }
+            if values.is_empty() {
+                continue;
+            }
+            let saved = settings.entry(id).or_insert_with(|| Value::Object(Default::default()));
+            if let Some(saved) = saved.as_object_mut() {
+                saved.extend(values);
+            }
+        }
+    },
+];
 
 pub struct UserPrefs {
     path: PathBuf,
-    pub inner: Mutex<PrefData>
+    pub inner: Mutex<PrefData>,
+    /// Set when the file is from a newer version, which saving over would
+    /// downgrade, dropping whatever this version doesn't know.
+    read_only: AtomicBool,
 }
 
 impl UserPrefs {
@@ -14,46 +86,323 @@ impl UserPrefs {
         let val = Self {
             path: data_dir.join("userdata.json"),
             inner: Mutex::new(PrefData::default()),
+            read_only: AtomicBool::new(false),
         };
-        val.load().unwrap();
+        val.load();
         val
     }
 
-    pub fn save(&self) -> Result<(), serde_json::Error> {
+    /// Writes to a temporary file and renames it over the old one, so a
+    /// crash can't leave a half-written file behind. The previous file is
+    /// kept as a backup.
+    pub fn save(&self) -> io::Result<()> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the settings are from a newer version of the app, update it to change them",
+            ));
+        }
         let inner = self.inner.lock().unwrap();
-        create_dir_all(self.path.parent().expect("blank save path")).unwrap();
-        serde_json::to_writer(File::create(self.path.clone()).unwrap(), &*inner)
+        create_dir_all(self.path.parent().expect("blank save path"))?;
+
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &*inner)?;
+        file.flush()?;
+        file.sync_all()?;
+        drop(file);
+
+        if self.path.exists() {
+            if let Err(e) = self.backup() {
+                println!("failed to back up {}: {e}", self.path.display());
+            }
+        }
+        fs::rename(&tmp, &self.path)
     }
 
-    pub fn load(&self) -> Result<(), serde_json::Error> {
+    /// Loads the settings, falling back to the newest backup that parses if
+    /// the file is corrupt, and to the defaults if none do. Settings from a
+    /// newer version are used as far as they're understood, but never saved
+    /// over.
+    pub fn load(&self) {
         let mut inner = self.inner.lock().unwrap();
-        match File::open(self.path.clone()) {
-            Ok(file) => *inner = serde_json::from_reader(file)?,
-            Err(_) => (),
-        };
+        match read_prefs(&self.path) {
+            Ok(Some((data, version))) => {
+                self.check_version(&self.path, version);
+                *inner = data;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                println!("failed to read {}: {e}", self.path.display());
+                // otherwise the next save would back up over the last good copy
+                let corrupt = self.path.with_extension(format!("json.corrupt-{}", now_millis()));
+                if let Err(e) = fs::rename(&self.path, &corrupt) {
+                    println!("failed to move aside {}: {e}", self.path.display());
+                }
+
+                let backups = self.backups().unwrap_or_default();
+                // newest first
+                for backup in backups.iter().rev() {
+                    match read_prefs(backup) {
+                        Ok(Some((data, version))) => {
+                            println!("restored settings from {}", backup.display());
+                            self.check_version(backup, version);
+                            *inner = data;
+                            return;
+                        }
+                        Ok(None) => (),
+                        Err(e) => println!("failed to read {}: {e}", backup.display()),
+                    }
+                }
+                println!("no usable backup, starting with default settings");
+            }
+        }
+    }
+
+    fn check_version(&self, path: &Path, version: usize) {
+        if version > MIGRATIONS.len() {
+            println!(
+                "{} is from a newer version ({version}), it won't be saved over",
+                path.display()
+            );
+            self.read_only.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn backup_dir(&self) -> PathBuf {
+        self.path.with_file_name("backups")
+    }
+
+    /// Backups, oldest first.
+    fn backups(&self) -> io::Result<Vec<PathBuf>> {
+        let mut backups = fs::read_dir(self.backup_dir())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| name.starts_with("userdata-") && name.ends_with(".json"))
+            })
+            .collect::<Vec<_>>();
+        // the names hold the timestamp, padded so they sort by it
+        backups.sort();
+        Ok(backups)
+    }
+
+    fn backup(&self) -> io::Result<()> {
+        let dir = self.backup_dir();
+        create_dir_all(&dir)?;
+        fs::copy(&self.path, dir.join(format!("userdata-{:020}.json", now_millis())))?;
+
+        let backups = self.backups()?;
+        for old in backups.iter().take(backups.len().saturating_sub(MAX_BACKUPS)) {
+            fs::remove_file(old)?;
+        }
         Ok(())
     }
 }
 
+/// Atomically writes a file only the current user can read, for tokens and
+/// the like.
+pub fn write_private<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    serde_json::to_writer(&mut file, value)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(tmp, path)
+}
+
+/// Reads and migrates a settings file, along with the version it was
+/// saved at. `None` if there isn't one.
+fn read_prefs(path: &Path) -> io::Result<Option<(PrefData, usize)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let data: Value = serde_json::from_reader(BufReader::new(file))?;
+    let version = file_version(&data);
+    Ok(Some((PrefData::from_value(data)?, version)))
+}
+
+fn file_version(data: &Value) -> usize {
+    data.get("version").and_then(Value::as_u64).unwrap_or(0) as usize
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Hash, Eq)]
 pub struct StoredManga {
     pub connector_idx: u32,
     pub manga_id: String
 }
 
+/// Settings. The library itself lives in the database.
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct PrefData {
-    pub liked: Vec<StoredManga>,
-    pub views: HashMap<u32, HashMap<String, Format>>,
-    pub read: HashMap<u32, HashSet<String>>
+    /// Format version of the file, see `MIGRATIONS`.
+    pub version: u32,
+    pub local_library: Option<PathBuf>,
+    /// Languages to show chapters and titles in, most preferred first.
+    pub languages: Vec<String>,
+    /// Follow manga on MangaDex when they're liked, if logged in.
+    pub mangadex_follow: bool,
+    /// Values of the connectors' settings, addresses included, by connector
+    /// ID and key.
+    /// Settings left at their default aren't saved.
+    pub connector_settings: HashMap<String, HashMap<String, SettingValue>>,
+    /// Bytes the image cache may take up.
+    pub image_cache_size: u64,
+    /// Seconds a fetched manga is shown as it is before being refreshed in
+    /// the background.
+    pub manga_cache_ttl: u64,
 }
 
 impl Default for PrefData {
     fn default() -> Self {
         PrefData {
-            liked: Vec::new(),
-            views: HashMap::new(),
-            read: HashMap::new(),
+            version: MIGRATIONS.len() as u32,
+            local_library: None,
+            languages: Vec::new(),
+            mangadex_follow: false,
+            connector_settings: HashMap::new(),
+            image_cache_size: image_cache::DEFAULT_MAX_SIZE,
+            manga_cache_ttl: metadata::DEFAULT_TTL.as_secs(),
+        }
+    }
+}
+
+impl PrefData {
+    /// Parses settings saved by any version, upgrading older ones. Ones
+    /// from a newer version are read as far as they're understood.
+    pub fn from_value(mut data: Value) -> serde_json::Result<Self> {
+        let version = file_version(&data);
+        for migrate in MIGRATIONS.iter().skip(version) {
+            migrate(&mut data);
+        }
+
+        let mut data: PrefData = serde_json::from_value(data)?;
+        data.version = MIGRATIONS.len() as u32;
+        Ok(data)
+    }
+
+    /// The preferred languages, English when none were picked.
+    pub fn languages(&self) -> Vec<String> {
+        if self.languages.is_empty() {
+            vec!["en".to_string()]
+        } else {
+            self.languages.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn moves_addresses_into_settings() {
+        let data = PrefData::from_value(json!({
+            "version": 1,
+            "connectors": {
+                "mangakakalot": {
+                    "base_url": "http://localhost:8080",
+                    "mirrors": ["https://mirror.test"],
+                    "image_proxy": null,
+                },
+                "mangadex": { "base_url": null, "mirrors": [], "image_proxy": null },
+            },
+            "connector_settings": {
+                "mangakakalot": { "proxy_images": { "Enum": "all" } },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(data.connector_settings.len(), 1);
+        let settings = &data.connector_settings["mangakakalot"];
+        assert_eq!(settings.len(), 3);
+        assert!(settings["proxy_images"] == SettingValue::Enum("all".to_string()));
+        assert!(settings["base_url"] == SettingValue::String("http://localhost:8080".to_string()));
+        assert!(settings["mirrors"] == SettingValue::StringList(vec!["https://mirror.test".to_string()]));
+    }
+
+    fn saved_languages(prefs: &UserPrefs, languages: &[&str]) {
+        prefs.inner.lock().unwrap().languages = languages.iter().map(|l| l.to_string()).collect();
+        prefs.save().unwrap();
+        // backups are named by the millisecond
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    #[test]
+    fn saves_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefs = UserPrefs::new(dir.path().to_path_buf());
+        saved_languages(&prefs, &["fr"]);
+
+        assert!(!prefs.path.with_extension("json.tmp").exists());
+        assert!(prefs.backups().is_err(), "nothing to back up yet");
+        let (data, version) = read_prefs(&prefs.path).unwrap().unwrap();
+        assert_eq!(data.languages, ["fr"]);
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn prunes_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefs = UserPrefs::new(dir.path().to_path_buf());
+        for i in 0..MAX_BACKUPS + 3 {
+            saved_languages(&prefs, &[&i.to_string()]);
         }
+
+        let backups = prefs.backups().unwrap();
+        assert_eq!(backups.len(), MAX_BACKUPS);
+        // the oldest are gone, the newest is the save before last
+        let newest = read_prefs(backups.last().unwrap()).unwrap().unwrap().0;
+        assert_eq!(newest.languages, [(MAX_BACKUPS + 1).to_string()]);
+        let oldest = read_prefs(&backups[0]).unwrap().unwrap().0;
+        assert_eq!(oldest.languages, ["2"]);
+    }
+
+    #[test]
+    fn falls_back_on_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefs = UserPrefs::new(dir.path().to_path_buf());
+        saved_languages(&prefs, &["fr"]);
+        saved_languages(&prefs, &["de"]);
+        saved_languages(&prefs, &["ja"]);
+        // newer than the last good backup, and corrupt too
+        fs::write(prefs.backup_dir().join(format!("userdata-{:020}.json", u64::MAX)), "{").unwrap();
+        fs::write(&prefs.path, "{\"languages\": [").unwrap();
+
+        let prefs = UserPrefs::new(dir.path().to_path_buf());
+        assert_eq!(prefs.inner.lock().unwrap().languages, ["de"]);
+        assert!(!prefs.path.exists());
+        let moved = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with("userdata.json.corrupt-"))
+            .count();
+        assert_eq!(moved, 1);
+    }
+
+    #[test]
+    fn never_saves_over_newer_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("userdata.json");
+        let newer = json!({ "version": 99, "languages": ["fr"], "from_the_future": true });
+        fs::write(&path, newer.to_string()).unwrap();
+
+        let prefs = UserPrefs::new(dir.path().to_path_buf());
+        assert_eq!(prefs.inner.lock().unwrap().languages, ["fr"]);
+        prefs.inner.lock().unwrap().languages.clear();
+        assert!(prefs.save().is_err());
+        let saved: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, newer);
     }
 }
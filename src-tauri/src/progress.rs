@@ -0,0 +1,139 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::connectors::Chapter;
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy)]
+pub struct ChapterProgress {
+    /// Zero-based index of the last page that was open.
+    pub page: u32,
+    pub total: u32,
+    /// Milliseconds since the epoch.
+    pub updated_at: u64,
+    pub completed: bool,
+}
+
+/// Where to pick a manga back up.
+#[derive(Serialize, Type)]
+pub struct ResumePoint {
+    pub chapter: Chapter,
+    pub page: u32,
+}
+
+/// Picks the chapter and page to open next: the page we stopped on if the
+/// most recently opened chapter isn't finished, otherwise the chapter after
+/// it. Without any progress, or nothing unread after the last chapter
+/// opened, it's the first unread chapter. `None` when everything has been
+/// read.
+pub fn resume_point(
+    mut chapters: Vec<Chapter>,
+    progress: &HashMap<String, ChapterProgress>,
+    read: &HashSet<String>,
+) -> Option<ResumePoint> {
+    chapters.sort_by(|a, b| a.number.partial_cmp(&b.number).unwrap_or(Ordering::Equal));
+
+    let last = chapters
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| Some((i, progress.get(&c.id)?)))
+        .max_by_key(|(_, p)| p.updated_at);
+
+    let start = match last {
+        Some((i, p)) if !p.completed => {
+            let page = p.page;
+            return Some(ResumePoint {
+                chapter: chapters.swap_remove(i),
+                page,
+            });
+        }
+        // several uploads of the same chapter share a number, skip them all
+        Some((i, _)) => {
+            let number = chapters[i].number;
+            chapters
+                .iter()
+                .position(|c| c.number > number)
+                .unwrap_or(chapters.len())
+        }
+        None => 0,
+    };
+
+    let unread = |c: &Chapter| !read.contains(&c.id);
+    let next = chapters[start..]
+        .iter()
+        .position(unread)
+        .map(|i| start + i)
+        .or_else(|| chapters.iter().position(unread))?;
+    Some(ResumePoint {
+        chapter: chapters.swap_remove(next),
+        page: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(number: f32) -> Chapter {
+        Chapter {
+            id: number.to_string(),
+            name: format!("Chapter {number}"),
+            number,
+            volume: None,
+            language: None,
+            scanlation_group: None,
+            read: None,
+        }
+    }
+
+    fn progress(page: u32, updated_at: u64, completed: bool) -> ChapterProgress {
+        ChapterProgress {
+            page,
+            total: 20,
+            updated_at,
+            completed,
+        }
+    }
+
+    fn resume(progress: &[(&str, ChapterProgress)], read: &[&str]) -> Option<(String, u32)> {
+        // newest first, like the connectors list them
+        let chapters = [3.0, 2.0, 2.0, 1.5, 1.0].into_iter().map(chapter).collect();
+        let progress = progress.iter().map(|(id, p)| (id.to_string(), *p)).collect();
+        let read = read.iter().map(|id| id.to_string()).collect();
+        resume_point(chapters, &progress, &read).map(|r| (r.chapter.id, r.page))
+    }
+
+    #[test]
+    fn starts_at_first_unread() {
+        assert_eq!(resume(&[], &[]), Some(("1".to_string(), 0)));
+        assert_eq!(resume(&[], &["1", "1.5"]), Some(("2".to_string(), 0)));
+    }
+
+    #[test]
+    fn reopens_unfinished_chapter() {
+        let progress = [("1", progress(19, 1, true)), ("2", progress(7, 2, false))];
+        assert_eq!(resume(&progress, &["1"]), Some(("2".to_string(), 7)));
+    }
+
+    #[test]
+    fn skips_past_finished_chapter() {
+        let progress = [("2", progress(19, 2, true)), ("1.5", progress(3, 1, false))];
+        assert_eq!(resume(&progress, &["2"]), Some(("3".to_string(), 0)));
+    }
+
+    #[test]
+    fn falls_back_when_last_chapter_finished() {
+        let progress = [("3", progress(19, 1, true))];
+        assert_eq!(resume(&progress, &["3", "1"]), Some(("1.5".to_string(), 0)));
+    }
+
+    #[test]
+    fn none_when_everything_read() {
+        let progress = [("3", progress(19, 1, true))];
+        assert_eq!(resume(&progress, &["1", "1.5", "2", "3"]), None);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+use tauri::{
+    http::{Request, Response, ResponseBuilder},
+    AppHandle, Manager,
+};
+
+use crate::{
+    connectors::{ConnectorError, Connectors, Image},
+    downloads::Downloads,
+};
+
+/// Pages and covers are loaded by the webview from this scheme, so chapters
+/// only carry short URLs over IPC instead of every image inlined.
+pub const SCHEME: &str = "bunni";
+
+/// How long a finished load waits for the webview to ask for it again.
+const KEEP_LOADED: Duration = Duration::from_secs(60);
+
+// WebView2 only lets custom schemes through as a localhost subdomain
+#[cfg(windows)]
+const ORIGIN: &str = "https://bunni.localhost";
+#[cfg(not(windows))]
+const ORIGIN: &str = "bunni://localhost";
+
+fn build_url(segments: &[&str], query: &[(&str, &str)]) -> String {
+    let mut url = Url::parse(ORIGIN).unwrap();
+    url.path_segments_mut().unwrap().extend(segments);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    url.to_string()
+}
+
+/// Serves `url` through the connector's `fetch_image`, which knows the
+/// headers and mirrors the site wants and keeps the image cached.
+pub fn image_url(connector_id: &str, url: &str) -> String {
+    build_url(&["image", connector_id], &[("url", url)])
+}
+
+/// The connector and URL of an `image_url`, `None` for anything else.
+pub fn parse_image_url(url: &str) -> Option<(String, String)> {
+    let url = Url::parse(url).ok()?;
+    let origin = Url::parse(ORIGIN).unwrap();
+    if (url.scheme(), url.host()) != (origin.scheme(), origin.host()) {
+        return None;
+    }
+    let connector_id = match url.path_segments()?.collect::<Vec<_>>().as_slice() {
+        ["image", connector_id] => connector_id.to_string(),
+        _ => return None,
+    };
+    let src = url.query_pairs().find(|(key, _)| key == "url")?.1.into_owned();
+    Some((connector_id, src))
+}
+
+/// Serves the cover of a downloaded manga from disk.
+pub fn cover_url(connector_id: &str, manga_id: &str) -> String {
+    build_url(&["cover", connector_id], &[("manga", manga_id)])
+}
+
+/// Serves page `index` of a downloaded chapter from disk.
+pub fn download_url(connector_id: &str, chapter_id: &str, index: usize) -> String {
+    build_url(
+        &["download", connector_id, &index.to_string()],
+        &[("chapter", chapter_id)],
+    )
+}
+
+enum Load {
+    Running,
+    /// The image, or the status to answer with.
+    Done(Instant, Result<Image, u16>),
+}
+
+/// Images the webview asked for that weren't cached, loading in the
+/// background so the handler never waits on the network.
+#[derive(Default)]
+pub struct Loads(Mutex<HashMap<(u32, String), Load>>);
+
+impl Loads {
+    /// Takes the outcome of loading `src`, starting the load if it isn't
+    /// running already. `None` until it's done.
+    fn poll(&self, app: &AppHandle, connector_idx: u32, src: String) -> Option<Result<Image, u16>> {
+        let key = (connector_idx, src);
+        let mut loads = self.0.lock().unwrap();
+        match loads.remove(&key) {
+            Some(Load::Done(_, result)) => return Some(result),
+            Some(Load::Running) => {
+                loads.insert(key, Load::Running);
+                return None;
+            }
+            None => (),
+        }
+        // the webview may have moved on before asking again
+        loads.retain(|_, load| !matches!(load, Load::Done(at, _) if at.elapsed() > KEEP_LOADED));
+        loads.insert(key.clone(), Load::Running);
+        drop(loads);
+
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            let (connector_idx, src) = &key;
+            let connectors = app.state::<Connectors>();
+            let result = connectors[*connector_idx]
+                .fetch_image(src)
+                .await
+                .map_err(|e| {
+                    println!("failed to load {src}: {e}");
+                    match e {
+                        ConnectorError::Status { status, .. } => status,
+                        ConnectorError::NotFound(_) => 404,
+                        _ => 502,
+                    }
+                });
+            let loads = app.state::<Loads>();
+            loads.0.lock().unwrap().insert(key, Load::Done(Instant::now(), result));
+        });
+        None
+    }
+}
+
+fn respond(status: u16, image: Option<Image>) -> Result<Response, Box<dyn Error>> {
+    match image {
+        Some(image) => ResponseBuilder::new()
+            .status(status)
+            .mimetype(&image.content_type)
+            // pages don't change under the same URL, and the webview asking
+            // again costs a trip through the cache
+            .header("Cache-Control", "max-age=3600")
+            .body(image.bytes),
+        None => {
+            let mut response = ResponseBuilder::new()
+                .status(status)
+                .mimetype("text/plain");
+            // the webview retries these, see `Loads`
+            if status == 503 {
+                response = response.header("Retry-After", "1");
+            }
+            response.body(Vec::new())
+        }
+    }
+}
+
+/// Handles a `bunni://` request. Runs on the webview's thread, so images
+/// that aren't cached are answered with a 503 while they load in the
+/// background, and the webview asks again shortly after.
+pub fn handle(app: &AppHandle, request: &Request) -> Result<Response, Box<dyn Error>> {
+    let url = Url::parse(request.uri())?;
+    let segments = url.path_segments().map(|s| s.collect::<Vec<_>>());
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    match segments.as_deref() {
+        Some(["image", connector_id]) => {
+            let connectors = app.state::<Connectors>();
+            let (idx, src) = match (connectors.position(connector_id), param("url")) {
+                (Some(idx), Some(src)) => (idx, src),
+                _ => return respond(404, None),
+            };
+            if !connectors[idx].serves_image(&src) {
+                println!("refusing to load {src} for {connector_id}");
+                return respond(403, None);
+            }
+            if let Some(image) = connectors[idx].cached_image(&src) {
+                return respond(200, Some(image));
+            }
+            match app.state::<Loads>().poll(app, idx, src) {
+                Some(Ok(image)) => respond(200, Some(image)),
+                Some(Err(status)) => respond(status, None),
+                None => respond(503, None),
+            }
+        }
+        Some(["cover", connector_id]) => {
+            let cover = param("manga")
+                .and_then(|manga_id| app.state::<Downloads>().load_cover(connector_id, &manga_id));
+            match cover {
+                Some(image) => respond(200, Some(image)),
+                None => respond(404, None),
+            }
+        }
+        Some(["download", connector_id, index]) => {
+            let page = match (param("chapter"), index.parse::<usize>()) {
+                (Some(chapter_id), Ok(index)) => {
+                    app.state::<Downloads>()
+                        .load_page(connector_id, &chapter_id, index)
+                }
+                _ => None,
+            };
+            match page {
+                Some(image) => respond(200, Some(image)),
+                None => respond(404, None),
+            }
+        }
+        _ => respond(404, None),
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+
+use crate::{
+    connectors::{mangadex_account::MangaDexAccount, ConnectorError, Connectors},
+    db::{BackupChapter, BackupManga, Database, ImportMode, LibraryBackup, TrackerLink},
+    prefs::UserPrefs,
+    trackers::{TrackEntry, TrackStatus, Trackers},
+    updates::now_millis,
+};
+
+const MANGADEX: &str = "mangadex";
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct SyncReport {
+    /// Manga followed on MangaDex.
+    pub followed: u32,
+    /// Followed manga that weren't liked yet.
+    pub newly_liked: u32,
+    /// Read markers pulled, including ones already known here.
+    pub read_chapters: u32,
+}
+
+fn is_mangadex(app: &AppHandle, connector_idx: u32) -> bool {
+    app.state::<Connectors>()[connector_idx].id() == MANGADEX
+}
+
+/// Follows or unfollows a MangaDex manga in the background, when logged in
+/// and following on like is turned on.
+pub fn push_follow(app: &AppHandle, connector_idx: u32, manga_id: &str, followed: bool) {
+    let enabled = app.state::<UserPrefs>().inner.lock().unwrap().mangadex_follow;
+    if !enabled || !is_mangadex(app, connector_idx) {
+        return;
+    }
+    let app = app.clone();
+    let manga_id = manga_id.to_string();
+    tauri::async_runtime::spawn(async move {
+        let account = app.state::<MangaDexAccount>();
+        if account.username().await.is_none() {
+            return;
+        }
+        if let Err(e) = account.set_followed(&manga_id, followed).await {
+            println!("failed to update the MangaDex follow of {manga_id}: {e}");
+        }
+    });
+}
+
+/// Marks a MangaDex chapter read on the site in the background, when
+/// logged in.
+pub fn push_read(app: &AppHandle, connector_idx: u32, manga_id: &str, chapter_id: &str) {
+    if !is_mangadex(app, connector_idx) {
+        return;
+    }
+    let app = app.clone();
+    let manga_id = manga_id.to_string();
+    let chapter_id = chapter_id.to_string();
+    tauri::async_runtime::spawn(async move {
+        let account = app.state::<MangaDexAccount>();
+        if account.username().await.is_none() {
+            return;
+        }
+        if let Err(e) = account.mark_read(&manga_id, &[chapter_id]).await {
+            println!("failed to push read marker for {manga_id}: {e}");
+        }
+    });
+}
+
+/// Pulls the MangaDex follow list into the liked manga, along with the read
+/// markers of everything liked from MangaDex. Nothing local is removed.
+pub async fn pull_mangadex(
+    account: &MangaDexAccount,
+    db: &Database,
+    connectors: &Connectors,
+) -> Result<SyncReport, ConnectorError> {
+    let follows = account.follows().await?;
+
+    let liked = match connectors.position(MANGADEX) {
+        Some(idx) => db
+            .liked()?
+            .into_iter()
+            .filter(|saved| saved.connector_idx == idx)
+            .map(|saved| saved.manga_id)
+            .collect(),
+        None => HashSet::new(),
+    };
+    let new = follows
+        .iter()
+        .filter(|id| !liked.contains(*id))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let manga_ids = liked.iter().cloned().chain(new.iter().cloned()).collect::<Vec<_>>();
+    let markers = account.read_markers(&manga_ids).await?;
+
+    let added_at = now_millis();
+    let backup = LibraryBackup {
+        liked: new
+            .iter()
+            .map(|manga_id| BackupManga {
+                connector: MANGADEX.to_string(),
+                manga_id: manga_id.clone(),
+                added_at,
+            })
+            .collect(),
+        read: markers
+            .into_values()
+            .flatten()
+            .map(|chapter_id| BackupChapter {
+                connector: MANGADEX.to_string(),
+                chapter_id,
+            })
+            .collect(),
+        ..Default::default()
+    };
+    db.restore(&backup, ImportMode::Merge)?;
+
+    Ok(SyncReport {
+        followed: follows.len() as u32,
+        newly_liked: new.len() as u32,
+        read_chapters: backup.read.len() as u32,
+    })
+}
+
+/// Brings a link and its tracker entry in line. Status and score changed
+/// here since the last sync win, otherwise the tracker's do. Progress is
+/// whichever is further along.
+pub async fn sync_tracker(
+    trackers: &Trackers,
+    db: &Database,
+    connector_idx: u32,
+    manga_id: &str,
+    link: &TrackerLink,
+) -> Result<TrackEntry, ConnectorError> {
+    let (tracker, token) = trackers.session(&link.tracker).await?;
+    let remote = tracker.fetch_entry(&token, &link.remote_id).await?;
+
+    let mut entry = match &remote {
+        Some(remote) if !link.has_changes() => remote.clone(),
+        _ => link.entry.clone(),
+    };
+    entry.progress = remote
+        .as_ref()
+        .map_or(link.entry.progress, |r| r.progress.max(link.entry.progress));
+    if entry.status == TrackStatus::PlanToRead && entry.progress > 0 {
+        entry.status = TrackStatus::Reading;
+    }
+
+    if remote.as_ref() != Some(&entry) {
+        tracker.update_entry(&token, &link.remote_id, &entry).await?;
+    }
+    db.tracker_synced(connector_idx, manga_id, &link.tracker, &entry)?;
+    Ok(entry)
+}
+
+/// Raises the chapter count on the manga's trackers in the background, when
+/// the chapter read is further along than what they have.
+pub fn push_tracker_progress(app: &AppHandle, connector_idx: u32, manga_id: &str, chapter_id: &str) {
+    let app = app.clone();
+    let manga_id = manga_id.to_string();
+    let chapter_id = chapter_id.to_string();
+    tauri::async_runtime::spawn(async move {
+        let db = app.state::<Database>();
+        // chapter numbers are only known from the manga
+        let number = match db.cached_manga(connector_idx, &manga_id) {
+            Ok(manga) => manga
+                .and_then(|(m, _)| m.chapters.into_iter().find(|c| c.id == chapter_id))
+                .map(|c| c.number),
+            Err(e) => {
+                println!("failed to look up {manga_id} for trackers: {e}");
+                return;
+            }
+        };
+        let progress = match number {
+            Some(number) if number >= 1.0 => number.floor() as u32,
+            _ => return,
+        };
+
+        let links = match db.raise_tracker_progress(connector_idx, &manga_id, progress) {
+            Ok(links) => links,
+            Err(e) => {
+                println!("failed to update tracker progress of {manga_id}: {e}");
+                return;
+            }
+        };
+        let trackers = app.state::<Trackers>();
+        for link in links {
+            if let Err(e) = sync_tracker(&trackers, &db, connector_idx, &manga_id, &link).await {
+                println!("failed to sync {manga_id} with {}: {e}", link.tracker);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        connectors::{local::LocalLibrary, HttpClient},
+        image_cache::ImageCache,
+        test_server::{Response, TestServer},
+        trackers::{Tracker, TrackerItem, TrackerSession},
+    };
+
+    fn connectors(dir: &Path) -> Connectors {
+        let cache = Arc::new(ImageCache::open(dir.join("cache"), 0).unwrap());
+        Connectors::new(
+            LocalLibrary::new(dir.join("library")),
+            &HashMap::new(),
+            HttpClient::new(),
+            cache,
+        )
+    }
+
+    #[tokio::test]
+    async fn pulls_mangadex_into_library() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/user/follows/manga" => Response::json(json!({
+                "data": [{ "id": "liked" }, { "id": "followed" }],
+                "total": 2,
+            })),
+            "/manga/read" => Response::json(json!({
+                "data": { "liked": ["liked-2"], "followed": ["followed-1", "followed-2"] },
+            })),
+            _ => Response::status(404),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let account =
+            MangaDexAccount::test(&server.url, dir.path(), now_millis() + 60 * 60 * 1000);
+        let connectors = connectors(dir.path());
+        let ids = connectors.0.iter().map(|c| c.id()).collect::<Vec<_>>();
+        let db = Database::in_memory(&ids);
+        let idx = connectors.position(MANGADEX).unwrap();
+        db.toggle_liked(idx, "liked").unwrap();
+        db.mark_read(idx, "liked", "liked-1").unwrap();
+
+        let report = pull_mangadex(&account, &db, &connectors).await.unwrap();
+        assert_eq!(report.followed, 2);
+        assert_eq!(report.newly_liked, 1);
+        assert_eq!(report.read_chapters, 3);
+
+        let liked = db.liked().unwrap().into_iter().map(|m| m.manga_id).collect::<Vec<_>>();
+        assert_eq!(liked, ["liked", "followed"]);
+        let chapters = ["liked-1", "liked-2", "followed-1", "followed-2"].map(String::from);
+        assert_eq!(db.read_chapters(idx, &chapters).unwrap().len(), 4);
+
+        let mut requested = server.requests_to("/manga/read")[0]
+            .params("ids[]")
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        requested.sort();
+        assert_eq!(requested, ["followed", "liked"]);
+    }
+
+    /// A tracker with one list entry, counting the updates pushed to it.
+    #[derive(Clone, Default)]
+    struct FakeTracker {
+        remote: Arc<Mutex<Option<TrackEntry>>>,
+        updates: Arc<Mutex<u32>>,
+    }
+
+    #[async_trait]
+    impl Tracker for FakeTracker {
+        fn id(&self) -> &str {
+            "fake"
+        }
+
+        fn name(&self) -> &str {
+            "Fake"
+        }
+
+        fn auth_url(&self) -> Result<String, ConnectorError> {
+            Err(ConnectorError::Unsupported("logging in".to_string()))
+        }
+
+        async fn login(&self, _code: &str) -> Result<TrackerSession, ConnectorError> {
+            Err(ConnectorError::Unsupported("logging in".to_string()))
+        }
+
+        async fn search(&self, _token: &str, _query: &str) -> Result<Vec<TrackerItem>, ConnectorError> {
+            Ok(Vec::new())
+        }
+
+        async fn fetch_entry(
+            &self,
+            _token: &str,
+            _remote_id: &str,
+        ) -> Result<Option<TrackEntry>, ConnectorError> {
+            Ok(self.remote.lock().unwrap().clone())
+        }
+
+        async fn update_entry(
+            &self,
+            _token: &str,
+            _remote_id: &str,
+            entry: &TrackEntry,
+        ) -> Result<(), ConnectorError> {
+            *self.remote.lock().unwrap() = Some(entry.clone());
+            *self.updates.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    fn entry(status: TrackStatus, score: Option<f32>, progress: u32) -> TrackEntry {
+        TrackEntry { status, score, progress }
+    }
+
+    /// Syncs a link holding `local`, changed here since the last sync or
+    /// not, with a tracker holding `remote`. Returns the synced entry and
+    /// how many updates were pushed.
+    async fn sync(local: TrackEntry, changed: bool, remote: Option<TrackEntry>) -> (TrackEntry, u32) {
+        let dir = tempfile::tempdir().unwrap();
+        let fake = FakeTracker::default();
+        *fake.remote.lock().unwrap() = remote;
+        let session = TrackerSession {
+            access_token: "token".to_string(),
+            refresh_token: None,
+            expires_at: None,
+        };
+        let trackers = Trackers::test(vec![Box::new(fake.clone())], dir.path(), vec![("fake", session)]);
+        let db = Database::in_memory(&[MANGADEX]);
+        let link = TrackerLink {
+            tracker: "fake".to_string(),
+            remote_id: "1".to_string(),
+            title: "Manga".to_string(),
+            entry: local,
+            updated_at: if changed { 2 } else { 1 },
+            synced_at: 1,
+        };
+        db.link_tracker(0, "manga", &link).unwrap();
+
+        let synced = sync_tracker(&trackers, &db, 0, "manga", &link).await.unwrap();
+        assert_eq!(fake.remote.lock().unwrap().as_ref(), Some(&synced));
+        let stored = db.tracker_link(0, "manga", "fake").unwrap().unwrap();
+        assert_eq!(stored.entry, synced);
+        assert!(!stored.has_changes());
+        let updates = *fake.updates.lock().unwrap();
+        (synced, updates)
+    }
+
+    #[tokio::test]
+    async fn local_changes_win() {
+        let local = entry(TrackStatus::Completed, Some(9.0), 10);
+        let remote = entry(TrackStatus::Dropped, Some(3.0), 4);
+        assert_eq!(sync(local.clone(), true, Some(remote)).await, (local, 1));
+    }
+
+    #[tokio::test]
+    async fn tracker_wins_without_local_changes() {
+        let local = entry(TrackStatus::Completed, Some(9.0), 4);
+        let remote = entry(TrackStatus::OnHold, Some(6.0), 10);
+        assert_eq!(sync(local, false, Some(remote.clone())).await, (remote, 0));
+    }
+
+    #[tokio::test]
+    async fn progress_takes_the_max() {
+        // the local status still loses, but not the chapters read here
+        let local = entry(TrackStatus::Reading, None, 12);
+        let remote = entry(TrackStatus::OnHold, Some(6.0), 10);
+        assert_eq!(
+            sync(local, false, Some(remote)).await,
+            (entry(TrackStatus::OnHold, Some(6.0), 12), 1)
+        );
+
+        let local = entry(TrackStatus::Reading, None, 3);
+        let remote = entry(TrackStatus::Dropped, None, 10);
+        assert_eq!(
+            sync(local, true, Some(remote)).await,
+            (entry(TrackStatus::Reading, None, 10), 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn plan_to_read_becomes_reading() {
+        let local = entry(TrackStatus::PlanToRead, None, 2);
+        let remote = entry(TrackStatus::PlanToRead, None, 0);
+        assert_eq!(
+            sync(local, false, Some(remote)).await,
+            (entry(TrackStatus::Reading, None, 2), 1)
+        );
+
+        let local = entry(TrackStatus::PlanToRead, None, 0);
+        assert_eq!(sync(local.clone(), true, None).await, (local, 1));
+    }
+
+    #[tokio::test]
+    async fn adds_missing_entry() {
+        let local = entry(TrackStatus::Reading, Some(7.0), 5);
+        assert_eq!(sync(local.clone(), false, None).await, (local, 1));
+    }
+}
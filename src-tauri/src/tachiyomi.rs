@@ -0,0 +1,453 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    connectors::ConnectorError,
+    db::{BackupCategory, BackupChapter, BackupManga, BackupProgress, LibraryBackup},
+    progress::ChapterProgress,
+    updates::now_millis,
+};
+
+/// Just enough of the protobuf wire format to read a `.tachibk`.
+mod proto {
+    use std::io;
+
+    pub enum Value<'a> {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(&'a [u8]),
+        Fixed32(u32),
+    }
+
+    impl<'a> Value<'a> {
+        pub fn uint(&self) -> u64 {
+            match self {
+                Value::Varint(v) | Value::Fixed64(v) => *v,
+                Value::Fixed32(v) => *v as u64,
+                Value::Bytes(_) => 0,
+            }
+        }
+
+        pub fn bytes(&self) -> &'a [u8] {
+            match self {
+                Value::Bytes(b) => b,
+                _ => &[],
+            }
+        }
+
+        pub fn string(&self) -> String {
+            String::from_utf8_lossy(self.bytes()).into_owned()
+        }
+    }
+
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+    }
+
+    fn varint(buf: &mut &[u8]) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf.split_first().ok_or_else(|| invalid("truncated varint"))?;
+            *buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+        if buf.len() < len {
+            return Err(invalid("truncated field"));
+        }
+        let (value, rest) = buf.split_at(len);
+        *buf = rest;
+        Ok(value)
+    }
+
+    /// The fields of a message as `(field number, value)`, in order.
+    pub fn fields(mut buf: &[u8]) -> io::Result<Vec<(u64, Value<'_>)>> {
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf)?;
+            let value = match key & 7 {
+                0 => Value::Varint(varint(&mut buf)?),
+                1 => Value::Fixed64(u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap())),
+                2 => {
+                    let len = varint(&mut buf)? as usize;
+                    Value::Bytes(take(&mut buf, len)?)
+                }
+                5 => Value::Fixed32(u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap())),
+                _ => return Err(invalid("unsupported wire type")),
+            };
+            fields.push((key >> 3, value));
+        }
+        Ok(fields)
+    }
+
+    /// Repeated integers, which newer writers pack into one field.
+    pub fn uints(value: &Value) -> io::Result<Vec<u64>> {
+        match value {
+            Value::Bytes(buf) => {
+                let mut buf: &[u8] = buf;
+                let mut values = Vec::new();
+                while !buf.is_empty() {
+                    values.push(varint(&mut buf)?);
+                }
+                Ok(values)
+            }
+            v => Ok(vec![v.uint()]),
+        }
+    }
+}
+
+struct TachiChapter {
+    url: String,
+    read: bool,
+    last_page_read: u32,
+    modified_at: u64,
+}
+
+struct TachiManga {
+    source: u64,
+    url: String,
+    title: String,
+    added_at: u64,
+    favorite: bool,
+    chapters: Vec<TachiChapter>,
+    /// The `order` of each category the manga is in.
+    categories: Vec<u64>,
+}
+
+struct TachiCategory {
+    name: String,
+    order: u64,
+}
+
+fn parse_chapter(buf: &[u8]) -> io::Result<TachiChapter> {
+    let mut chapter = TachiChapter {
+        url: String::new(),
+        read: false,
+        last_page_read: 0,
+        modified_at: 0,
+    };
+    for (field, value) in proto::fields(buf)? {
+        match field {
+            1 => chapter.url = value.string(),
+            4 => chapter.read = value.uint() != 0,
+            6 => chapter.last_page_read = value.uint() as u32,
+            // fetch date, overridden by the last modification when there is one
+            7 if chapter.modified_at == 0 => chapter.modified_at = value.uint(),
+            // seconds, unlike everything else
+            11 => chapter.modified_at = value.uint() * 1000,
+            _ => (),
+        }
+    }
+    Ok(chapter)
+}
+
+fn parse_manga(buf: &[u8]) -> io::Result<TachiManga> {
+    let mut manga = TachiManga {
+        source: 0,
+        url: String::new(),
+        title: String::new(),
+        added_at: 0,
+        favorite: true,
+        chapters: Vec::new(),
+        categories: Vec::new(),
+    };
+    for (field, value) in proto::fields(buf)? {
+        match field {
+            1 => manga.source = value.uint(),
+            2 => manga.url = value.string(),
+            3 => manga.title = value.string(),
+            13 => manga.added_at = value.uint(),
+            16 => manga.chapters.push(parse_chapter(value.bytes())?),
+            17 => manga.categories.extend(proto::uints(&value)?),
+            100 => manga.favorite = value.uint() != 0,
+            _ => (),
+        }
+    }
+    Ok(manga)
+}
+
+fn parse_category(buf: &[u8]) -> io::Result<TachiCategory> {
+    let mut category = TachiCategory {
+        name: String::new(),
+        order: 0,
+    };
+    for (field, value) in proto::fields(buf)? {
+        match field {
+            1 => category.name = value.string(),
+            2 => category.order = value.uint(),
+            _ => (),
+        }
+    }
+    Ok(category)
+}
+
+/// Where a Tachiyomi source's manga live among our connectors, and how to
+/// turn its URLs into our IDs.
+struct SourceMapping {
+    connector: &'static str,
+    manga_id: fn(&str) -> Option<String>,
+    chapter_id: fn(&str) -> Option<String>,
+}
+
+fn mangadex_id(url: &str) -> Option<String> {
+    // "/manga/<uuid>" and "/chapter/<uuid>"
+    let id = url.trim_end_matches('/').rsplit('/').next()?;
+    (id.len() == 36).then(|| id.to_string())
+}
+
+fn mangakakalot_id(url: &str) -> Option<String> {
+    let url = match url.starts_with("http") {
+        true => url.to_string(),
+        false => format!("https://mangakakalot.com{url}"),
+    };
+    // the connector uses the URL with the slashes swapped out
+    Some(url.replace('/', " "))
+}
+
+/// Matched by name since every language of an extension has its own ID.
+fn mapping(source_name: &str) -> Option<SourceMapping> {
+    let name = source_name.to_lowercase();
+    if name.starts_with("mangadex") {
+        Some(SourceMapping {
+            connector: "mangadex",
+            manga_id: mangadex_id,
+            chapter_id: mangadex_id,
+        })
+    } else if name.starts_with("mangakakalot") {
+        Some(SourceMapping {
+            connector: "mangakakalot",
+            manga_id: mangakakalot_id,
+            chapter_id: mangakakalot_id,
+        })
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct UnmatchedManga {
+    pub title: String,
+    /// The source's name, or its numeric ID when the backup doesn't say.
+    pub source: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct TachiyomiReport {
+    pub imported: u32,
+    pub categories: u32,
+    pub read_chapters: u32,
+    pub unmatched: Vec<UnmatchedManga>,
+}
+
+/// Reads a Tachiyomi/Mihon `.tachibk` (a gzipped protobuf) into a backup
+/// that can be merged into the library, along with what couldn't be mapped
+/// onto one of our connectors.
+pub fn read(path: &Path) -> Result<(LibraryBackup, TachiyomiReport), ConnectorError> {
+    let compressed = fs::read(path)?;
+    let mut bytes = Vec::new();
+    // decoding from memory, so any error here means the file isn't a backup
+    GzDecoder::new(&compressed[..])
+        .read_to_end(&mut bytes)
+        .map_err(ConnectorError::parse)?;
+
+    let mut manga = Vec::new();
+    let mut categories = Vec::new();
+    let mut sources = HashMap::new();
+    for (field, value) in proto::fields(&bytes)? {
+        match field {
+            1 => manga.push(parse_manga(value.bytes())?),
+            2 => categories.push(parse_category(value.bytes())?),
+            101 => {
+                let mut name = String::new();
+                let mut id = 0;
+                for (field, value) in proto::fields(value.bytes())? {
+                    match field {
+                        1 => name = value.string(),
+                        2 => id = value.uint(),
+                        _ => (),
+                    }
+                }
+                sources.insert(id, name);
+            }
+            _ => (),
+        }
+    }
+    categories.sort_by_key(|c| c.order);
+
+    let mut backup = LibraryBackup {
+        categories: categories
+            .iter()
+            .map(|c| BackupCategory {
+                name: c.name.clone(),
+                manga: Vec::new(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let mut report = TachiyomiReport {
+        imported: 0,
+        categories: categories.len() as u32,
+        read_chapters: 0,
+        unmatched: Vec::new(),
+    };
+
+    for m in manga.into_iter().filter(|m| m.favorite) {
+        let source_name = sources.get(&m.source).cloned();
+        let matched = source_name.as_deref().and_then(mapping).and_then(|mapping| {
+            Some(((mapping.manga_id)(&m.url)?, mapping))
+        });
+        let (manga_id, mapping) = match matched {
+            Some(matched) => matched,
+            None => {
+                report.unmatched.push(UnmatchedManga {
+                    title: m.title,
+                    source: source_name.unwrap_or_else(|| m.source.to_string()),
+                    url: m.url,
+                });
+                continue;
+            }
+        };
+        let connector = mapping.connector.to_string();
+
+        report.imported += 1;
+        backup.liked.push(BackupManga {
+            connector: connector.clone(),
+            manga_id: manga_id.clone(),
+            added_at: m.added_at,
+        });
+        for order in &m.categories {
+            if let Some(i) = categories.iter().position(|c| c.order == *order) {
+                backup.categories[i]
+                    .manga
+                    .push((connector.clone(), manga_id.clone()));
+            }
+        }
+
+        for chapter in m.chapters {
+            let chapter_id = match (mapping.chapter_id)(&chapter.url) {
+                Some(id) => id,
+                None => continue,
+            };
+            if chapter.read {
+                report.read_chapters += 1;
+                backup.read.push(BackupChapter {
+                    connector: connector.clone(),
+                    chapter_id,
+                });
+            } else if chapter.last_page_read > 0 {
+                backup.progress.push(BackupProgress {
+                    connector: connector.clone(),
+                    chapter_id,
+                    progress: ChapterProgress {
+                        page: chapter.last_page_read,
+                        // not in the backup, the reader fills it in
+                        total: 0,
+                        updated_at: match chapter.modified_at {
+                            0 => now_millis(),
+                            t => t,
+                        },
+                        completed: false,
+                    },
+                });
+            }
+        }
+    }
+    Ok((backup, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Five manga: one from MangaDex in both categories with a read
+    /// chapter, one in progress and one untouched, one from MangaKakalot
+    /// with a chapter in progress, two from sources we don't have and one
+    /// that isn't in the library anymore.
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/library.tachibk");
+
+    #[test]
+    fn reads_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.tachibk");
+        fs::write(&path, FIXTURE).unwrap();
+        let (backup, report) = read(&path).unwrap();
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.categories, 2);
+        assert_eq!(report.read_chapters, 1);
+        let unmatched = report
+            .unmatched
+            .iter()
+            .map(|m| (m.title.as_str(), m.source.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unmatched,
+            [("Elsewhere", "Some Other Site"), ("Unknown source", "555")]
+        );
+
+        let liked = backup
+            .liked
+            .iter()
+            .map(|m| (m.connector.as_str(), m.manga_id.as_str(), m.added_at))
+            .collect::<Vec<_>>();
+        let mangakakalot_id = "https:  mangakakalot.com manga ab123";
+        assert_eq!(
+            liked,
+            [
+                ("mangadex", "32d76d19-8a05-4db0-9fc2-e0b0648fe9d0", 1_700_000_000_000),
+                ("mangakakalot", mangakakalot_id, 1_700_000_200_000),
+            ]
+        );
+
+        let categories = backup
+            .categories
+            .iter()
+            .map(|c| (c.name.as_str(), c.manga.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(categories, [("Reading", 1), ("Later", 2)]);
+
+        assert_eq!(backup.read.len(), 1);
+        assert_eq!(backup.read[0].chapter_id, "a6f2c1c4-1c0c-4d51-8d40-6a2ce3ef1d11");
+        let progress = backup
+            .progress
+            .iter()
+            .map(|p| (p.chapter_id.as_str(), p.progress.page))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            progress,
+            [
+                ("b7e3d2d5-2d1d-4e62-9e51-7b3df4f02e22", 5),
+                ("https:  mangakakalot.com chapter ab123 chapter-1", 3),
+            ]
+        );
+        // the last modification is in seconds
+        assert_eq!(backup.progress[0].progress.updated_at, 1_700_000_100_000);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.tachibk");
+        assert!(matches!(read(&path), Err(ConnectorError::NotFound(_))));
+
+        fs::write(&path, b"not gzipped").unwrap();
+        assert!(matches!(read(&path), Err(ConnectorError::Parse(_))));
+    }
+}
@@ -0,0 +1,342 @@
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use super::{TrackEntry, TrackStatus, Tracker, TrackerItem, TrackerSession};
+use crate::connectors::{check_status, env_url, ConnectorError, HttpClient};
+
+const API_URL: &str = "https://graphql.anilist.co";
+const AUTH_URL: &str = "https://anilist.co/api/v2/oauth/authorize";
+/// The AniList API client to log in with, which has to allow the implicit
+/// grant. Set when building.
+const CLIENT_ID: Option<&str> = option_env!("BUNNI_ANILIST_CLIENT_ID");
+
+const VIEWER: &str = "query { Viewer { id } }";
+
+const SEARCH: &str = "query ($search: String) {
+  Page(perPage: 20) {
+    media(search: $search, type: MANGA) {
+      id
+      title { userPreferred }
+      coverImage { large }
+      chapters
+    }
+  }
+}";
+
+const ENTRY: &str = "query ($id: Int) {
+  Media(id: $id) {
+    mediaListEntry { status score(format: POINT_100) progress }
+  }
+}";
+
+const SAVE_ENTRY: &str = "mutation ($id: Int, $status: MediaListStatus, $scoreRaw: Int, $progress: Int) {
+  SaveMediaListEntry(mediaId: $id, status: $status, scoreRaw: $scoreRaw, progress: $progress) { id }
+}";
+
+#[derive(Deserialize)]
+struct GraphQlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphQlError>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Media {
+    id: u32,
+    title: Title,
+    cover_image: CoverImage,
+    chapters: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Title {
+    user_preferred: String,
+}
+
+#[derive(Deserialize)]
+struct CoverImage {
+    large: String,
+}
+
+#[derive(Deserialize)]
+struct ListEntry {
+    status: String,
+    /// Out of 100, 0 when unscored.
+    score: f32,
+    progress: u32,
+}
+
+fn status_name(status: TrackStatus) -> &'static str {
+    match status {
+        TrackStatus::Reading => "CURRENT",
+        TrackStatus::Completed => "COMPLETED",
+        TrackStatus::OnHold => "PAUSED",
+        TrackStatus::Dropped => "DROPPED",
+        TrackStatus::PlanToRead => "PLANNING",
+        TrackStatus::Rereading => "REPEATING",
+    }
+}
+
+fn parse_status(name: &str) -> Option<TrackStatus> {
+    Some(match name {
+        "CURRENT" => TrackStatus::Reading,
+        "COMPLETED" => TrackStatus::Completed,
+        "PAUSED" => TrackStatus::OnHold,
+        "DROPPED" => TrackStatus::Dropped,
+        "PLANNING" => TrackStatus::PlanToRead,
+        "REPEATING" => TrackStatus::Rereading,
+        _ => return None,
+    })
+}
+
+fn media_id(remote_id: &str) -> Result<u32, ConnectorError> {
+    remote_id
+        .parse()
+        .map_err(|_| ConnectorError::NotFound(format!("AniList manga {remote_id}")))
+}
+
+pub struct AniList {
+    client: HttpClient,
+    api_url: String,
+    auth_url: String,
+}
+
+impl AniList {
+    pub fn new(client: HttpClient) -> Self {
+        // 90 a minute, and fewer when AniList is under load
+        client.limit("graphql.anilist.co", 1.0, 5);
+        AniList {
+            client,
+            api_url: env_url("BUNNI_ANILIST_API", API_URL),
+            auth_url: env_url("BUNNI_ANILIST_AUTH", AUTH_URL),
+        }
+    }
+
+    async fn query<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        query: &str,
+        variables: Value,
+    ) -> Result<T, ConnectorError> {
+        let request = self
+            .client
+            .post(&self.api_url)
+            .bearer_auth(token)
+            .json(&json!({ "query": query, "variables": variables }));
+        let res = self.client.send(request).await?;
+        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(ConnectorError::Auth("AniList rejected the token".to_string()));
+        }
+        let res: GraphQlResponse<T> = check_status(res)?.json().await?;
+        match (res.data, res.errors.into_iter().next()) {
+            (_, Some(e)) => Err(ConnectorError::Parse(e.message)),
+            (Some(data), None) => Ok(data),
+            (None, None) => Err(ConnectorError::parse("AniList returned no data")),
+        }
+    }
+}
+
+#[async_trait]
+impl Tracker for AniList {
+    fn id(&self) -> &str {
+        "anilist"
+    }
+
+    fn name(&self) -> &str {
+        "AniList"
+    }
+
+    fn auth_url(&self) -> Result<String, ConnectorError> {
+        let client_id = CLIENT_ID
+            .ok_or_else(|| ConnectorError::Unsupported("AniList login in this build".to_string()))?;
+        Ok(format!("{}?client_id={client_id}&response_type=token", self.auth_url))
+    }
+
+    /// The implicit grant hands out the access token itself, so this only
+    /// checks that it works. The token lasts a year and can't be refreshed.
+    async fn login(&self, code: &str) -> Result<TrackerSession, ConnectorError> {
+        let token = code.trim();
+        self.query::<Value>(token, VIEWER, json!({})).await?;
+        Ok(TrackerSession {
+            access_token: token.to_string(),
+            refresh_token: None,
+            expires_at: None,
+        })
+    }
+
+    async fn search(&self, token: &str, query: &str) -> Result<Vec<TrackerItem>, ConnectorError> {
+        #[derive(Deserialize)]
+        struct Data {
+            #[serde(rename = "Page")]
+            page: Page,
+        }
+        #[derive(Deserialize)]
+        struct Page {
+            media: Vec<Media>,
+        }
+
+        let data: Data = self.query(token, SEARCH, json!({ "search": query })).await?;
+        Ok(data
+            .page
+            .media
+            .into_iter()
+            .map(|m| TrackerItem {
+                id: m.id.to_string(),
+                title: m.title.user_preferred,
+                cover_url: m.cover_image.large,
+                chapters: m.chapters,
+            })
+            .collect())
+    }
+
+    async fn fetch_entry(
+        &self,
+        token: &str,
+        remote_id: &str,
+    ) -> Result<Option<TrackEntry>, ConnectorError> {
+        #[derive(Deserialize)]
+        struct Data {
+            #[serde(rename = "Media")]
+            media: EntryMedia,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct EntryMedia {
+            media_list_entry: Option<ListEntry>,
+        }
+
+        let data: Data = self
+            .query(token, ENTRY, json!({ "id": media_id(remote_id)? }))
+            .await?;
+        data.media
+            .media_list_entry
+            .map(|entry| {
+                Ok(TrackEntry {
+                    status: parse_status(&entry.status).ok_or_else(|| {
+                        ConnectorError::parse(format!("unknown AniList status {}", entry.status))
+                    })?,
+                    score: (entry.score > 0.0).then(|| entry.score / 10.0),
+                    progress: entry.progress,
+                })
+            })
+            .transpose()
+    }
+
+    async fn update_entry(
+        &self,
+        token: &str,
+        remote_id: &str,
+        entry: &TrackEntry,
+    ) -> Result<(), ConnectorError> {
+        let variables = json!({
+            "id": media_id(remote_id)?,
+            "status": status_name(entry.status),
+            "scoreRaw": entry.score.map_or(0, |s| (s * 10.0).round() as u32),
+            "progress": entry.progress,
+        });
+        self.query::<Value>(token, SAVE_ENTRY, variables).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl AniList {
+    /// AniList against a test server at `url`.
+    pub fn test(url: &str) -> Self {
+        AniList {
+            client: HttpClient::new(),
+            api_url: url.to_string(),
+            auth_url: url.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+
+    #[tokio::test]
+    async fn fetches_entry() {
+        let server = TestServer::start(|req| match req.json()["variables"]["id"].as_u64() {
+            Some(42) => Response::json(json!({ "data": { "Media": { "mediaListEntry": {
+                "status": "REPEATING", "score": 85, "progress": 12,
+            } } } })),
+            _ => Response::json(json!({ "data": { "Media": { "mediaListEntry": null } } })),
+        })
+        .await;
+        let anilist = AniList::test(&server.url);
+
+        let entry = anilist.fetch_entry("token", "42").await.unwrap();
+        assert_eq!(
+            entry,
+            Some(TrackEntry {
+                status: TrackStatus::Rereading,
+                score: Some(8.5),
+                progress: 12,
+            })
+        );
+        assert_eq!(anilist.fetch_entry("token", "7").await.unwrap(), None);
+
+        let req = &server.requests()[0];
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.headers["authorization"], "Bearer token");
+        assert_eq!(req.json()["query"], ENTRY);
+    }
+
+    #[tokio::test]
+    async fn updates_entry() {
+        let server = TestServer::start(|_| {
+            Response::json(json!({ "data": { "SaveMediaListEntry": { "id": 1 } } }))
+        })
+        .await;
+        let anilist = AniList::test(&server.url);
+
+        let entry = TrackEntry {
+            status: TrackStatus::Reading,
+            score: Some(7.5),
+            progress: 3,
+        };
+        anilist.update_entry("token", "42", &entry).await.unwrap();
+        let req = &server.requests()[0];
+        assert_eq!(req.json()["query"], SAVE_ENTRY);
+        assert_eq!(
+            req.json()["variables"],
+            json!({ "id": 42, "status": "CURRENT", "scoreRaw": 75, "progress": 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_errors() {
+        let server = TestServer::start(|req| match req.headers["authorization"].as_str() {
+            "Bearer token" => Response::json(json!({
+                "data": null,
+                "errors": [{ "message": "Not Found." }],
+            })),
+            _ => Response::status(401),
+        })
+        .await;
+        let anilist = AniList::test(&server.url);
+
+        assert!(matches!(
+            anilist.fetch_entry("expired", "42").await,
+            Err(ConnectorError::Auth(_))
+        ));
+        assert!(matches!(
+            anilist.fetch_entry("token", "42").await,
+            Err(ConnectorError::Parse(message)) if message == "Not Found."
+        ));
+        assert!(matches!(
+            anilist.fetch_entry("token", "not-a-number").await,
+            Err(ConnectorError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use self::{anilist::AniList, myanimelist::MyAnimeList};
+use crate::{
+    connectors::{ConnectorError, HttpClient},
+    prefs::write_private,
+    updates::now_millis,
+};
+
+mod anilist;
+mod myanimelist;
+
+/// Refresh this long before the access token actually expires.
+const REFRESH_MARGIN: u64 = 60 * 1000;
+
+/// Where a manga is on the user's list. Each tracker has its own names for
+/// these.
+#[derive(Serialize, Deserialize, Type, Clone, Copy, PartialEq, Debug)]
+pub enum TrackStatus {
+    Reading,
+    Completed,
+    OnHold,
+    Dropped,
+    PlanToRead,
+    Rereading,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone, PartialEq, Debug)]
+pub struct TrackEntry {
+    pub status: TrackStatus,
+    /// Out of 10, `None` when unscored.
+    pub score: Option<f32>,
+    /// Chapters read.
+    pub progress: u32,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct TrackerItem {
+    pub id: String,
+    pub title: String,
+    pub cover_url: String,
+    /// Total chapters, once the tracker knows.
+    pub chapters: Option<u32>,
+}
+
+/// What a tracker handed out on login.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TrackerSession {
+    pub access_token: String,
+    /// `None` when the tracker doesn't hand one out.
+    pub refresh_token: Option<String>,
+    /// Milliseconds since the epoch, `None` when the tracker doesn't say.
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct TrackerInfo {
+    pub id: String,
+    pub name: String,
+    pub logged_in: bool,
+}
+
+/// A site that keeps track of what the user reads, like AniList. Every call
+/// but `login` and `refresh` takes the access token they returned.
+#[async_trait]
+pub trait Tracker: Send + Sync {
+    /// Saved alongside links, so it has to stay the same forever.
+    fn id(&self) -> &str;
+    fn name(&self) -> &str;
+    /// The page the user logs in on.
+    fn auth_url(&self) -> Result<String, ConnectorError>;
+    /// Turns what that page handed back into a session.
+    async fn login(&self, code: &str) -> Result<TrackerSession, ConnectorError>;
+    /// A new session for an expired one. Fails with `ConnectorError::Auth`
+    /// when the refresh token no longer works.
+    async fn refresh(&self, _refresh_token: &str) -> Result<TrackerSession, ConnectorError> {
+        Err(ConnectorError::Unsupported(format!("refreshing {} logins", self.name())))
+    }
+    async fn search(&self, token: &str, query: &str) -> Result<Vec<TrackerItem>, ConnectorError>;
+    /// The user's entry for the manga, `None` when it's not on their list.
+    async fn fetch_entry(
+        &self,
+        token: &str,
+        remote_id: &str,
+    ) -> Result<Option<TrackEntry>, ConnectorError>;
+    /// Adds the manga to the user's list if needed.
+    async fn update_entry(
+        &self,
+        token: &str,
+        remote_id: &str,
+        entry: &TrackEntry,
+    ) -> Result<(), ConnectorError>;
+}
+
+/// A random string for OAuth's PKCE, from the OS's randomness.
+fn random_string() -> Result<String, ConnectorError> {
+    let mut bytes = [0; 48];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| ConnectorError::Io(format!("failed to get random bytes: {e}")))?;
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// The trackers, with the sessions of the ones the user logged in to.
+/// Sessions are saved in the app data dir, readable only by the current user.
+pub struct Trackers {
+    list: Vec<Box<dyn Tracker>>,
+    path: PathBuf,
+    sessions: Mutex<HashMap<String, TrackerSession>>,
+    /// Held while refreshing, so a refresh token is only used once.
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl Trackers {
+    pub fn new(client: HttpClient, data_dir: PathBuf) -> Self {
+        let path = data_dir.join("trackers.json");
+        let sessions = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                println!("ignoring unreadable tracker sessions: {e}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Trackers {
+            list: vec![
+                Box::new(AniList::new(client.clone())),
+                Box::new(MyAnimeList::new(client)),
+            ],
+            path,
+            sessions: Mutex::new(sessions),
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn info(&self) -> Vec<TrackerInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        self.list
+            .iter()
+            .map(|t| TrackerInfo {
+                id: t.id().to_string(),
+                name: t.name().to_string(),
+                logged_in: sessions.contains_key(t.id()),
+            })
+            .collect()
+    }
+
+    pub fn get(&self, id: &str) -> Result<&dyn Tracker, ConnectorError> {
+        self.list
+            .iter()
+            .find(|t| t.id() == id)
+            .map(|t| &**t)
+            .ok_or_else(|| ConnectorError::NotFound(format!("tracker {id}")))
+    }
+
+    /// The tracker along with a valid access token, refreshing it first if
+    /// it's about to expire. A refresh token that no longer works ends the
+    /// session.
+    pub async fn session(&self, id: &str) -> Result<(&dyn Tracker, String), ConnectorError> {
+        let tracker = self.get(id)?;
+        let _refreshing = self.refreshing.lock().await;
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ConnectorError::Auth(format!("not logged in to {}", tracker.name())))?;
+        let refresh_token = match (&session.refresh_token, session.expires_at) {
+            (Some(token), Some(expires_at)) if expires_at <= now_millis() + REFRESH_MARGIN => token,
+            _ => return Ok((tracker, session.access_token)),
+        };
+
+        match tracker.refresh(refresh_token).await {
+            Ok(session) => {
+                let token = session.access_token.clone();
+                self.store(id, Some(session))?;
+                Ok((tracker, token))
+            }
+            Err(e @ ConnectorError::Auth(_)) => {
+                self.store(id, None)?;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn login(&self, id: &str, code: &str) -> Result<(), ConnectorError> {
+        let session = self.get(id)?.login(code).await?;
+        self.store(id, Some(session))
+    }
+
+    pub fn logout(&self, id: &str) -> Result<(), ConnectorError> {
+        self.store(id, None)
+    }
+
+    /// Replaces the session of a tracker and saves them all.
+    fn store(&self, id: &str, session: Option<TrackerSession>) -> Result<(), ConnectorError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match session {
+            Some(session) => sessions.insert(id.to_string(), session),
+            None => sessions.remove(id),
+        };
+        Ok(write_private(&self.path, &*sessions)?)
+    }
+}
+
+#[cfg(test)]
+impl Trackers {
+    /// `list` in place of the real trackers, logged in with `sessions` and
+    /// saving them in `dir`.
+    pub fn test(
+        list: Vec<Box<dyn Tracker>>,
+        dir: &std::path::Path,
+        sessions: Vec<(&str, TrackerSession)>,
+    ) -> Self {
+        Trackers {
+            list,
+            path: dir.join("trackers.json"),
+            sessions: Mutex::new(
+                sessions
+                    .into_iter()
+                    .map(|(id, session)| (id.to_string(), session))
+                    .collect(),
+            ),
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use serde_json::json;
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    fn expiring(expires_at: u64) -> TrackerSession {
+        TrackerSession {
+            access_token: "access".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(expires_at),
+        }
+    }
+
+    async fn mal(
+        dir: &std::path::Path,
+        expires_at: u64,
+        token: impl Fn() -> Response + Send + Sync + 'static,
+    ) -> (TestServer, Trackers) {
+        let server = TestServer::start(move |req| match req.path.as_str() {
+            "/token" => token(),
+            _ => Response::status(404),
+        })
+        .await;
+        let trackers = Trackers::test(
+            vec![Box::new(MyAnimeList::test(&server.url))],
+            dir,
+            vec![("myanimelist", expiring(expires_at))],
+        );
+        (server, trackers)
+    }
+
+    #[tokio::test]
+    async fn refreshes_expiring_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, trackers) = mal(dir.path(), now_millis(), || {
+            Response::json(json!({
+                "access_token": "new-access",
+                "refresh_token": "new-refresh",
+                "expires_in": 3600,
+            }))
+        })
+        .await;
+
+        let (_, token) = trackers.session("myanimelist").await.unwrap();
+        assert_eq!(token, "new-access");
+        let req = &server.requests_to("/token")[0];
+        assert_eq!(req.form("grant_type").as_deref(), Some("refresh_token"));
+        assert_eq!(req.form("refresh_token").as_deref(), Some("refresh"));
+
+        let saved: HashMap<String, TrackerSession> =
+            serde_json::from_slice(&fs::read(dir.path().join("trackers.json")).unwrap()).unwrap();
+        let saved = &saved["myanimelist"];
+        assert_eq!(saved.refresh_token.as_deref(), Some("new-refresh"));
+        assert!(saved.expires_at.unwrap() > now_millis() + HOUR / 2);
+
+        // the new token is good for an hour
+        trackers.session("myanimelist").await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_valid_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, trackers) = mal(dir.path(), now_millis() + HOUR, || Response::status(500)).await;
+
+        let (_, token) = trackers.session("myanimelist").await.unwrap();
+        assert_eq!(token, "access");
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_refresh_ends_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_server, trackers) = mal(dir.path(), 0, || Response::status(400)).await;
+
+        assert!(matches!(
+            trackers.session("myanimelist").await,
+            Err(ConnectorError::Auth(_))
+        ));
+        assert!(!trackers.info()[0].logged_in);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_refresh_keeps_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_server, trackers) = mal(dir.path(), 0, || {
+            Response::status(429).header("Retry-After", "60")
+        })
+        .await;
+
+        assert!(matches!(
+            trackers.session("myanimelist").await,
+            Err(ConnectorError::RateLimited { .. })
+        ));
+        assert!(trackers.info()[0].logged_in);
+    }
+}
@@ -0,0 +1,394 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{random_string, TrackEntry, TrackStatus, Tracker, TrackerItem, TrackerSession};
+use crate::{
+    connectors::{check_status, env_url, ConnectorError, HttpClient},
+    updates::now_millis,
+};
+
+const API_URL: &str = "https://api.myanimelist.net/v2";
+const AUTH_URL: &str = "https://myanimelist.net/v1/oauth2";
+/// The MyAnimeList API client to log in with. Set when building.
+const CLIENT_ID: Option<&str> = option_env!("BUNNI_MAL_CLIENT_ID");
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    /// Seconds.
+    expires_in: u64,
+}
+
+impl From<TokenResponse> for TrackerSession {
+    fn from(token: TokenResponse) -> Self {
+        TrackerSession {
+            access_token: token.access_token,
+            refresh_token: Some(token.refresh_token),
+            expires_at: Some(now_millis() + token.expires_in * 1000),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    data: Vec<SearchNode>,
+}
+
+#[derive(Deserialize)]
+struct SearchNode {
+    node: MangaNode,
+}
+
+#[derive(Deserialize)]
+struct MangaNode {
+    id: u32,
+    title: String,
+    main_picture: Option<Picture>,
+    /// 0 while it's still running.
+    #[serde(default)]
+    num_chapters: u32,
+}
+
+#[derive(Deserialize)]
+struct Picture {
+    medium: String,
+}
+
+#[derive(Deserialize)]
+struct EntryResponse {
+    my_list_status: Option<ListStatus>,
+}
+
+#[derive(Deserialize)]
+struct ListStatus {
+    status: String,
+    /// 0 when unscored.
+    score: u32,
+    num_chapters_read: u32,
+    #[serde(default)]
+    is_rereading: bool,
+}
+
+/// MyAnimeList marks rereads with a flag rather than a status.
+fn status_name(status: TrackStatus) -> &'static str {
+    match status {
+        TrackStatus::Reading | TrackStatus::Rereading => "reading",
+        TrackStatus::Completed => "completed",
+        TrackStatus::OnHold => "on_hold",
+        TrackStatus::Dropped => "dropped",
+        TrackStatus::PlanToRead => "plan_to_read",
+    }
+}
+
+fn parse_status(name: &str, rereading: bool) -> Option<TrackStatus> {
+    if rereading {
+        return Some(TrackStatus::Rereading);
+    }
+    Some(match name {
+        "reading" => TrackStatus::Reading,
+        "completed" => TrackStatus::Completed,
+        "on_hold" => TrackStatus::OnHold,
+        "dropped" => TrackStatus::Dropped,
+        "plan_to_read" => TrackStatus::PlanToRead,
+        _ => return None,
+    })
+}
+
+pub struct MyAnimeList {
+    client: HttpClient,
+    api_url: String,
+    auth_url: String,
+    client_id: Option<String>,
+    /// The PKCE verifier of the last login page handed out.
+    verifier: Mutex<Option<String>>,
+}
+
+impl MyAnimeList {
+    pub fn new(client: HttpClient) -> Self {
+        MyAnimeList {
+            client,
+            api_url: env_url("BUNNI_MAL_API", API_URL),
+            auth_url: env_url("BUNNI_MAL_AUTH", AUTH_URL),
+            client_id: CLIENT_ID.map(str::to_string),
+            verifier: Mutex::new(None),
+        }
+    }
+
+    fn client_id(&self) -> Result<&str, ConnectorError> {
+        self.client_id
+            .as_deref()
+            .ok_or_else(|| ConnectorError::Unsupported("MyAnimeList login in this build".to_string()))
+    }
+
+    /// Only a 400 or 401 means the code or refresh token is bad. Anything
+    /// else, like being rate limited, is passed on as is so it doesn't end
+    /// the session.
+    async fn request_token(&self, form: &[(&str, &str)]) -> Result<TrackerSession, ConnectorError> {
+        let request = self.client.post(format!("{}/token", self.auth_url)).form(form);
+        let res = self.client.send(request).await?;
+        if matches!(res.status(), StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED) {
+            return Err(ConnectorError::Auth(format!(
+                "MyAnimeList rejected the login ({})",
+                res.status()
+            )));
+        }
+        let token: TokenResponse = check_status(res)?.json().await?;
+        Ok(token.into())
+    }
+
+    async fn send(&self, token: &str, request: RequestBuilder) -> Result<reqwest::Response, ConnectorError> {
+        let res = self.client.send(request.bearer_auth(token)).await?;
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(ConnectorError::Auth(
+                "MyAnimeList rejected the token, log in again".to_string(),
+            ));
+        }
+        check_status(res)
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T, ConnectorError> {
+        let request = self.client.get(format!("{}{path}", self.api_url)).query(query);
+        Ok(self.send(token, request).await?.json().await?)
+    }
+}
+
+#[async_trait]
+impl Tracker for MyAnimeList {
+    fn id(&self) -> &str {
+        "myanimelist"
+    }
+
+    fn name(&self) -> &str {
+        "MyAnimeList"
+    }
+
+    /// MyAnimeList only supports the plain PKCE method, so the challenge is
+    /// the verifier itself.
+    fn auth_url(&self) -> Result<String, ConnectorError> {
+        let verifier = random_string()?;
+        let url = reqwest::Url::parse_with_params(
+            &format!("{}/authorize", self.auth_url),
+            &[
+                ("response_type", "code"),
+                ("client_id", self.client_id()?),
+                ("code_challenge", &verifier),
+                ("code_challenge_method", "plain"),
+            ],
+        )
+        .map_err(ConnectorError::parse)?;
+        *self.verifier.lock().unwrap() = Some(verifier);
+        Ok(url.to_string())
+    }
+
+    async fn login(&self, code: &str) -> Result<TrackerSession, ConnectorError> {
+        let verifier = self
+            .verifier
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| ConnectorError::Auth("open the MyAnimeList login page first".to_string()))?;
+        self.request_token(&[
+            ("client_id", self.client_id()?),
+            ("grant_type", "authorization_code"),
+            ("code", code.trim()),
+            ("code_verifier", &verifier),
+        ])
+        .await
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<TrackerSession, ConnectorError> {
+        self.request_token(&[
+            ("client_id", self.client_id()?),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ])
+        .await
+    }
+
+    async fn search(&self, token: &str, query: &str) -> Result<Vec<TrackerItem>, ConnectorError> {
+        let res: SearchResponse = self
+            .get(
+                token,
+                "/manga",
+                &[("q", query), ("limit", "20"), ("fields", "num_chapters")],
+            )
+            .await?;
+        Ok(res
+            .data
+            .into_iter()
+            .map(|SearchNode { node }| TrackerItem {
+                id: node.id.to_string(),
+                title: node.title,
+                cover_url: node.main_picture.map(|p| p.medium).unwrap_or_default(),
+                chapters: (node.num_chapters > 0).then(|| node.num_chapters),
+            })
+            .collect())
+    }
+
+    async fn fetch_entry(
+        &self,
+        token: &str,
+        remote_id: &str,
+    ) -> Result<Option<TrackEntry>, ConnectorError> {
+        let res: EntryResponse = self
+            .get(token, &format!("/manga/{remote_id}"), &[("fields", "my_list_status")])
+            .await?;
+        res.my_list_status
+            .map(|entry| {
+                Ok(TrackEntry {
+                    status: parse_status(&entry.status, entry.is_rereading).ok_or_else(|| {
+                        ConnectorError::parse(format!("unknown MyAnimeList status {}", entry.status))
+                    })?,
+                    score: (entry.score > 0).then(|| entry.score as f32),
+                    progress: entry.num_chapters_read,
+                })
+            })
+            .transpose()
+    }
+
+    async fn update_entry(
+        &self,
+        token: &str,
+        remote_id: &str,
+        entry: &TrackEntry,
+    ) -> Result<(), ConnectorError> {
+        let score = entry.score.map_or(0, |s| s.round() as u32).to_string();
+        let progress = entry.progress.to_string();
+        let rereading = (entry.status == TrackStatus::Rereading).to_string();
+        let request = self
+            .client
+            .patch(format!("{}/manga/{remote_id}/my_list_status", self.api_url))
+            .form(&[
+                ("status", status_name(entry.status)),
+                ("score", &score),
+                ("num_chapters_read", &progress),
+                ("is_rereading", &rereading),
+            ]);
+        self.send(token, request).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl MyAnimeList {
+    /// MyAnimeList against a test server at `url`, for both the API and
+    /// logging in.
+    pub fn test(url: &str) -> Self {
+        MyAnimeList {
+            client: HttpClient::new(),
+            api_url: url.to_string(),
+            auth_url: url.to_string(),
+            client_id: Some("client".to_string()),
+            verifier: Mutex::new(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn login_sends_verifier() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/token" => Response::json(json!({
+                "access_token": "access",
+                "refresh_token": "refresh",
+                "expires_in": 3600,
+            })),
+            _ => Response::status(404),
+        })
+        .await;
+        let mal = MyAnimeList::test(&server.url);
+
+        let url = reqwest::Url::parse(&mal.auth_url().unwrap()).unwrap();
+        let challenge = url
+            .query_pairs()
+            .find(|(key, _)| key == "code_challenge")
+            .unwrap()
+            .1
+            .into_owned();
+        let session = mal.login(" code ").await.unwrap();
+        assert_eq!(session.access_token, "access");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh"));
+        assert!(session.expires_at.unwrap() > now_millis());
+
+        let req = &server.requests_to("/token")[0];
+        assert_eq!(req.form("grant_type").as_deref(), Some("authorization_code"));
+        assert_eq!(req.form("code").as_deref(), Some("code"));
+        assert_eq!(req.form("code_verifier"), Some(challenge));
+
+        // the verifier is only good once
+        assert!(matches!(mal.login("code").await, Err(ConnectorError::Auth(_))));
+    }
+
+    #[tokio::test]
+    async fn fetches_entry() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/manga/42" => Response::json(json!({ "id": 42, "my_list_status": {
+                "status": "completed", "score": 8, "num_chapters_read": 30, "is_rereading": true,
+            } })),
+            "/manga/7" => Response::json(json!({ "id": 7 })),
+            _ => Response::status(404),
+        })
+        .await;
+        let mal = MyAnimeList::test(&server.url);
+
+        let entry = mal.fetch_entry("token", "42").await.unwrap();
+        assert_eq!(
+            entry,
+            Some(TrackEntry {
+                status: TrackStatus::Rereading,
+                score: Some(8.0),
+                progress: 30,
+            })
+        );
+        assert_eq!(mal.fetch_entry("token", "7").await.unwrap(), None);
+
+        let req = &server.requests_to("/manga/42")[0];
+        assert_eq!(req.headers["authorization"], "Bearer token");
+        assert_eq!(req.param("fields"), Some("my_list_status"));
+    }
+
+    #[tokio::test]
+    async fn updates_entry() {
+        let server = TestServer::start(|_| Response::json(json!({}))).await;
+        let mal = MyAnimeList::test(&server.url);
+
+        let entry = TrackEntry {
+            status: TrackStatus::Rereading,
+            score: None,
+            progress: 3,
+        };
+        mal.update_entry("token", "42", &entry).await.unwrap();
+        let req = &server.requests_to("/manga/42/my_list_status")[0];
+        assert_eq!(req.method, "PATCH");
+        assert_eq!(req.headers["authorization"], "Bearer token");
+        assert_eq!(req.form("status").as_deref(), Some("reading"));
+        assert_eq!(req.form("is_rereading").as_deref(), Some("true"));
+        assert_eq!(req.form("score").as_deref(), Some("0"));
+        assert_eq!(req.form("num_chapters_read").as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_auth_error() {
+        let server = TestServer::start(|_| Response::status(401)).await;
+        let mal = MyAnimeList::test(&server.url);
+
+        assert!(matches!(
+            mal.fetch_entry("expired", "42").await,
+            Err(ConnectorError::Auth(_))
+        ));
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::{
+    connectors::{Chapter, Connectors, Manga},
+    db::Database,
+    metadata,
+    prefs::{StoredManga, UserPrefs},
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Oldest entries are dropped from the feed past this.
+const MAX_FEED_LEN: usize = 1000;
+/// Manga fetched at once, so a big library doesn't hit every site in one go.
+const CONCURRENT_CHECKS: usize = 4;
+
+/// Milliseconds since the unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Type, Clone)]
+pub struct ChapterUpdate {
+    pub connector_idx: u32,
+    pub manga_id: String,
+    pub manga_title: String,
+    pub cover_url: String,
+    pub chapter: Chapter,
+    /// When the chapter was first noticed, in milliseconds since the epoch.
+    pub found_at: u64,
+}
+
+#[derive(Clone, Serialize)]
+struct NewChaptersEvent {
+    updates: Vec<ChapterUpdate>,
+}
+
+/// Periodically re-fetches the liked manga and records chapters that weren't
+/// there on the previous check.
+pub struct Updater {
+    // only one check at a time, so the known chapters don't race
+    running: Mutex<()>,
+}
+
+impl Updater {
+    pub fn new() -> Self {
+        Updater {
+            running: Mutex::new(()),
+        }
+    }
+
+    pub fn spawn(app: AppHandle) {
+        tauri::async_runtime::spawn(async move {
+            loop {
+                if let Err(e) = app.state::<Updater>().check(&app).await {
+                    println!("update check failed: {e}");
+                }
+                sleep(CHECK_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Checks every liked manga once and returns the unread chapters that
+    /// are new since the last check. Manga seen for the first time only
+    /// record their chapters, otherwise liking something would flood the feed.
+    pub async fn check(&self, app: &AppHandle) -> rusqlite::Result<Vec<ChapterUpdate>> {
+        let _running = self.running.lock().await;
+        let connectors = app.state::<Connectors>();
+        let db = app.state::<Database>();
+
+        let liked = db.liked()?;
+        let languages = app.state::<UserPrefs>().inner.lock().unwrap().languages();
+        let fetches = liked
+            .iter()
+            .map(|saved| {
+                let fetch = connectors[saved.connector_idx].fetch_manga(&saved.manga_id, &languages);
+                async move { (saved, fetch.await) }
+            })
+            .collect::<Vec<_>>();
+        let fetched = stream::iter(fetches)
+            .buffer_unordered(CONCURRENT_CHECKS)
+            .collect::<Vec<_>>()
+            .await;
+
+        let found_at = now_millis();
+        let mut updates = Vec::new();
+        for (saved, manga) in fetched {
+            let manga = match manga {
+                Ok(manga) => manga,
+                Err(e) => {
+                    println!("update check for {} failed: {e}", saved.manga_id);
+                    continue;
+                }
+            };
+            metadata::store(app, saved.connector_idx, &manga);
+            updates.extend(new_chapters(&db, saved, manga, found_at)?);
+        }
+        db.add_updates(&updates, MAX_FEED_LEN)?;
+
+        if !updates.is_empty() {
+            app.emit_all(
+                "new_chapters",
+                NewChaptersEvent {
+                    updates: updates.clone(),
+                },
+            )
+            .unwrap();
+        }
+        Ok(updates)
+    }
+}
+
+/// The unread chapters of `manga` that weren't there on its last check,
+/// remembering the ones there now for the next. None on its first check.
+fn new_chapters(
+    db: &Database,
+    saved: &StoredManga,
+    manga: Manga,
+    found_at: u64,
+) -> rusqlite::Result<Vec<ChapterUpdate>> {
+    let ids = manga.chapters.iter().map(|c| c.id.clone()).collect::<Vec<_>>();
+    let known = match db.replace_known_chapters(saved.connector_idx, &saved.manga_id, &ids)? {
+        Some(known) => known,
+        None => return Ok(Vec::new()),
+    };
+    let read = db.read_chapters(saved.connector_idx, &ids)?;
+
+    Ok(manga
+        .chapters
+        .into_iter()
+        .filter(|c| !known.contains(&c.id) && !read.contains(&c.id))
+        .map(|chapter| ChapterUpdate {
+            connector_idx: saved.connector_idx,
+            manga_id: saved.manga_id.clone(),
+            manga_title: manga.desc.title.clone(),
+            cover_url: manga.desc.cover_url.clone(),
+            chapter,
+            found_at,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::SearchItem;
+
+    fn manga(chapters: &[&str]) -> Manga {
+        Manga {
+            desc: SearchItem {
+                id: "manga".to_string(),
+                title: "Manga".to_string(),
+                description: String::new(),
+                cover_url: String::new(),
+            },
+            chapters: chapters
+                .iter()
+                .map(|id| Chapter {
+                    id: id.to_string(),
+                    name: id.to_string(),
+                    number: 0.0,
+                    volume: None,
+                    language: None,
+                    scanlation_group: None,
+                    read: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn diffs_known_chapters() {
+        let mut db = Database::in_memory(&["test"]);
+        db.toggle_liked(0, "manga").unwrap();
+        let saved = StoredManga {
+            connector_idx: 0,
+            manga_id: "manga".to_string(),
+        };
+        let check = |db: &Database, chapters: &[&str]| {
+            new_chapters(db, &saved, manga(chapters), 0)
+                .unwrap()
+                .into_iter()
+                .map(|update| update.chapter.id)
+                .collect::<Vec<_>>()
+        };
+
+        // the first check only seeds
+        assert!(check(&db, &["1", "2"]).is_empty());
+        assert_eq!(check(&db, &["1", "2", "3"]), ["3"]);
+        assert!(check(&db, &["1", "2", "3"]).is_empty());
+        db.mark_read(0, "manga", "5").unwrap();
+        assert_eq!(check(&db, &["1", "2", "3", "4", "5"]), ["4"]);
+
+        // a new connector version may list the same chapters differently
+        db.register(&[("test", 2)]).unwrap();
+        assert!(check(&db, &["a", "b"]).is_empty());
+        assert_eq!(check(&db, &["a", "b", "c"]), ["c"]);
+        db.register(&[("test", 2)]).unwrap();
+        assert_eq!(check(&db, &["a", "b", "c", "d"]), ["d"]);
+    }
+}
@@ -14,16 +14,59 @@ export function getConnectors() {
     return invoke()<string[]>("get_connectors")
 }
 
-export function searchManga(idx: number, query: string) {
-    return invoke()<SearchItem[]>("search_manga", { idx,query })
+export function searchManga(idx: number, query: string, filters: SearchFilters, page: number) {
+    return invoke()<Page<SearchItem>>("search_manga", { idx,query,filters,page })
 }
 
-export function fetchManga(idx: number, id: string) {
-    return invoke()<Manga>("fetch_manga", { idx,id })
+export function getSearchFilters(idx: number) {
+    return invoke()<FilterCapabilities>("get_search_filters", { idx })
 }
 
-export function fetchChapter(idx: number, id: string) {
-    return invoke()<ChapterImages>("fetch_chapter", { idx,id })
+/**
+ * Shows the cached manga right away, refreshing it in the background when
+ * it's stale, unless `force` asks to wait for the site.
+ */
+export function fetchManga(idx: number, id: string, force: boolean) {
+    return invoke()<Manga>("fetch_manga", { idx,id,force })
+}
+
+export function fetchChapter(idx: number, mangaId: string, id: string) {
+    return invoke()<ChapterImages>("fetch_chapter", { idx,mangaId,id })
+}
+
+/**
+ * Looks the manga up once for all of its queued chapters.
+ */
+export function queueDownload(connectorIdx: number, mangaId: string, chapterIds: string[]) {
+    return invoke()<DownloadJob[]>("queue_download", { connectorIdx,mangaId,chapterIds })
+}
+
+export function listDownloadJobs() {
+    return invoke()<DownloadJob[]>("list_download_jobs")
+}
+
+export function pauseDownload(jobId: number | null) {
+    return invoke()<null>("pause_download", { jobId })
+}
+
+export function resumeDownload(jobId: number | null) {
+    return invoke()<null>("resume_download", { jobId })
+}
+
+export function cancelDownload(jobId: number | null) {
+    return invoke()<null>("cancel_download", { jobId })
+}
+
+export function listDownloads() {
+    return invoke()<DownloadedManga[]>("list_downloads")
+}
+
+export function deleteDownload(connectorIdx: number, mangaId: string, chapterId: string | null) {
+    return invoke()<null>("delete_download", { connectorIdx,mangaId,chapterId })
+}
+
+export function exportCbz(connectorIdx: number, mangaId: string, chapterIds: string[] | null, grouping: ExportGrouping, dest: string) {
+    return invoke()<string[]>("export_cbz", { connectorIdx,mangaId,chapterIds,grouping,dest })
 }
 
 export function toggleLiked(connectorIdx: number, id: string) {
@@ -34,8 +77,44 @@ export function isLiked(connectorIdx: number, mangaId: string) {
     return invoke()<boolean>("is_liked", { connectorIdx,mangaId })
 }
 
-export function fetchLiked() {
-    return invoke()<([number, Manga])[]>("fetch_liked")
+export function fetchLiked(categoryId: number | null, force: boolean) {
+    return invoke()<([number, Manga])[]>("fetch_liked", { categoryId,force })
+}
+
+export function getCategories() {
+    return invoke()<Category[]>("get_categories")
+}
+
+export function createCategory(name: string) {
+    return invoke()<Category>("create_category", { name })
+}
+
+export function renameCategory(id: number, name: string) {
+    return invoke()<boolean>("rename_category", { id,name })
+}
+
+export function deleteCategory(id: number) {
+    return invoke()<boolean>("delete_category", { id })
+}
+
+export function reorderCategories(ids: number[]) {
+    return invoke()<null>("reorder_categories", { ids })
+}
+
+export function getMangaCategories(connectorIdx: number, mangaId: string) {
+    return invoke()<number[]>("get_manga_categories", { connectorIdx,mangaId })
+}
+
+export function setMangaCategories(connectorIdx: number, mangaId: string, categoryIds: number[]) {
+    return invoke()<null>("set_manga_categories", { connectorIdx,mangaId,categoryIds })
+}
+
+/**
+ * Orders a category's manga, given as `(connector_idx, manga_id)` pairs
+ * like `fetch_liked` returns them.
+ */
+export function reorderCategory(categoryId: number, manga: ([number, string])[]) {
+    return invoke()<null>("reorder_category", { categoryId,manga })
 }
 
 export function setMangaView(connectorIdx: number, mangaId: string, long: boolean) {
@@ -46,12 +125,292 @@ export function getMangaView(connectorIdx: number, mangaId: string) {
     return invoke()<Format | null>("get_manga_view", { connectorIdx,mangaId })
 }
 
-export function markChapterRead(connectorIdx: number, chapterId: string) {
-    return invoke()<null>("mark_chapter_read", { connectorIdx,chapterId })
+export function markChapterRead(connectorIdx: number, mangaId: string, chapterId: string) {
+    return invoke()<null>("mark_chapter_read", { connectorIdx,mangaId,chapterId })
 }
 
-export type ChapterImages = { images: string[]; format: Format }
+/**
+ * Records the page the reader is on. Reaching the last page marks the
+ * chapter read.
+ */
+export function setChapterProgress(connectorIdx: number, mangaId: string, chapterId: string, page: number, total: number) {
+    return invoke()<null>("set_chapter_progress", { connectorIdx,mangaId,chapterId,page,total })
+}
+
+export function getChapterProgress(connectorIdx: number, chapterId: string) {
+    return invoke()<ChapterProgress | null>("get_chapter_progress", { connectorIdx,chapterId })
+}
+
+/**
+ * The chapter and page to open to pick the manga back up, `None` when
+ * every chapter has been read.
+ */
+export function continueReading(connectorIdx: number, mangaId: string) {
+    return invoke()<ResumePoint | null>("continue_reading", { connectorIdx,mangaId })
+}
+
+/**
+ * Reading history, newest first. Times are milliseconds since the epoch,
+ * `from` inclusive and `to` exclusive; leave any filter out to not filter
+ * on it.
+ */
+export function getHistory(from: number | null, to: number | null, connectorIdx: number | null, mangaId: string | null) {
+    return invoke()<HistoryEntry[]>("get_history", { from,to,connectorIdx,mangaId })
+}
+
+export function deleteHistoryEntry(id: number) {
+    return invoke()<boolean>("delete_history_entry", { id })
+}
+
+export function clearHistory() {
+    return invoke()<null>("clear_history")
+}
+
+export function getLocalLibrary() {
+    return invoke()<string>("get_local_library")
+}
+
+export function exportBackup(path: string) {
+    return invoke()<null>("export_backup", { path })
+}
+
+export function importBackup(path: string, mode: ImportMode) {
+    return invoke()<null>("import_backup", { path,mode })
+}
+
+/**
+ * Merges the library from a Tachiyomi/Mihon backup into ours.
+ */
+export function importTachiyomi(path: string) {
+    return invoke()<TachiyomiReport>("import_tachiyomi", { path })
+}
+
+export function setLocalLibrary(path: string) {
+    return invoke()<null>("set_local_library", { path })
+}
+
+export function getConnectorSettings(idx: number) {
+    return invoke()<ConnectorSettings>("get_connector_settings", { idx })
+}
+
+/**
+ * Changes one of a connector's own settings, taking effect right away.
+ * Its cached manga are refreshed next time, as their chapters may differ now.
+ */
+export function setConnectorSetting(idx: number, key: string, value: SettingValue) {
+    return invoke()<null>("set_connector_setting", { idx,key,value })
+}
+
+export function getImageCacheUsage() {
+    return invoke()<CacheUsage>("get_image_cache_usage")
+}
+
+export function clearImageCache() {
+    return invoke()<null>("clear_image_cache")
+}
+
+/**
+ * Limits the image cache to `bytes`, evicting the least recently used
+ * images right away if it's over.
+ */
+export function setImageCacheSize(bytes: number) {
+    return invoke()<null>("set_image_cache_size", { bytes })
+}
+
+export function getMangaCacheTtl() {
+    return invoke()<number>("get_manga_cache_ttl")
+}
+
+/**
+ * Sets how many seconds fetched manga are shown before being refreshed.
+ */
+export function setMangaCacheTtl(seconds: number) {
+    return invoke()<null>("set_manga_cache_ttl", { seconds })
+}
+
+export function getLanguages() {
+    return invoke()<string[]>("get_languages")
+}
+
+export function setLanguages(languages: string[]) {
+    return invoke()<null>("set_languages", { languages })
+}
+
+/**
+ * Logs in to MangaDex with a personal API client's credentials.
+ */
+export function mangadexLogin(username: string, password: string, clientId: string, clientSecret: string) {
+    return invoke()<null>("mangadex_login", { username,password,clientId,clientSecret })
+}
+
+export function mangadexLogout() {
+    return invoke()<null>("mangadex_logout")
+}
+
+/**
+ * The name of the logged in MangaDex user.
+ */
+export function mangadexUser() {
+    return invoke()<string | null>("mangadex_user")
+}
+
+export function getMangadexFollow() {
+    return invoke()<boolean>("get_mangadex_follow")
+}
+
+export function setMangadexFollow(follow: boolean) {
+    return invoke()<null>("set_mangadex_follow", { follow })
+}
+
+/**
+ * Likes everything followed on MangaDex and marks the chapters read there
+ * read here too.
+ */
+export function syncMangadex() {
+    return invoke()<SyncReport>("sync_mangadex")
+}
+
+export function getTrackers() {
+    return invoke()<TrackerInfo[]>("get_trackers")
+}
+
+/**
+ * The page to send the user to for logging in to a tracker. What it hands
+ * back goes to `tracker_login`.
+ */
+export function trackerAuthUrl(tracker: string) {
+    return invoke()<string>("tracker_auth_url", { tracker })
+}
+
+export function trackerLogin(tracker: string, code: string) {
+    return invoke()<null>("tracker_login", { tracker,code })
+}
+
+export function trackerLogout(tracker: string) {
+    return invoke()<null>("tracker_logout", { tracker })
+}
+
+export function searchTracker(tracker: string, query: string) {
+    return invoke()<TrackerItem[]>("search_tracker", { tracker,query })
+}
+
+export function getTrackerLinks(connectorIdx: number, mangaId: string) {
+    return invoke()<TrackerLink[]>("get_tracker_links", { connectorIdx,mangaId })
+}
+
+/**
+ * Links a manga to a tracker entry found with `search_tracker`, taking over
+ * the entry if it's on the user's list already.
+ */
+export function linkTracker(connectorIdx: number, mangaId: string, tracker: string, item: TrackerItem) {
+    return invoke()<TrackerLink>("link_tracker", { connectorIdx,mangaId,tracker,item })
+}
+
+export function unlinkTracker(connectorIdx: number, mangaId: string, tracker: string) {
+    return invoke()<boolean>("unlink_tracker", { connectorIdx,mangaId,tracker })
+}
+
+/**
+ * Changes the status, score or progress of a linked manga and pushes it to
+ * the tracker. If that fails the change is kept for the next sync.
+ */
+export function setTrackerEntry(connectorIdx: number, mangaId: string, tracker: string, entry: TrackEntry) {
+    return invoke()<TrackEntry>("set_tracker_entry", { connectorIdx,mangaId,tracker,entry })
+}
+
+/**
+ * Syncs every tracker the manga is linked to both ways.
+ */
+export function syncTrackers(connectorIdx: number, mangaId: string) {
+    return invoke()<TrackerLink[]>("sync_trackers", { connectorIdx,mangaId })
+}
+
+/**
+ * Checks the library for new chapters right away instead of waiting for
+ * the next scheduled check.
+ */
+export function checkUpdates() {
+    return invoke()<ChapterUpdate[]>("check_updates")
+}
+
+/**
+ * New chapters found in the library, newest first.
+ */
+export function getUpdates() {
+    return invoke()<ChapterUpdate[]>("get_updates")
+}
+
+/**
+ * Narrows down a search. Empty lists mean "don't filter on this". Filters a
+ * connector doesn't advertise in its `FilterCapabilities` are ignored.
+ */
+export type SearchFilters = { tags: string[]; status: PublicationStatus[]; demographic: Demographic[]; content_rating: ContentRating[]; languages: string[]; sort: SortOrder | null }
+export type PublicationStatus = "Ongoing" | "Completed" | "Hiatus" | "Cancelled"
+export type TrackerInfo = { id: string; name: string; logged_in: boolean }
+export type TachiyomiReport = { imported: number; categories: number; read_chapters: number; unmatched: UnmatchedManga[] }
+/**
+ * What a setting holds, which decides how the frontend shows it.
+ */
+export type SettingKind = "Bool" | { Enum: { options: SettingOption[] } } | "StringList" | "Url" | "UrlList"
+/**
+ * A user defined group of liked manga, like "Reading" or "Dropped". A manga
+ * can be in any number of them.
+ */
+export type Category = { id: number; name: string; manga_count: number }
+/**
+ * A manga linked to its entry on a tracker, with the entry as last seen or
+ * changed here.
+ */
+export type TrackerLink = { tracker: string; remote_id: string; title: string; entry: TrackEntry; updated_at: number; synced_at: number }
+export type ChapterManifest = { chapter: Chapter; format: Format; pages: string[] }
+export type ConnectorError = { Network: string } | { Status: { status: number; url: string } } | { Parse: string } | { NotFound: string } | { RateLimited: { retry_after: number | null } } | { Unsupported: string } | { Io: string } | { Database: string } | { Auth: string } | { Invalid: string }
+export type Format = "Normal" | "Long"
+export type UnmatchedManga = { title: string; source: string; url: string }
+/**
+ * How a backup is combined with the library that's already there.
+ */
+export type ImportMode = "Merge" | "Replace"
 export type Manga = { desc: SearchItem; chapters: Chapter[] }
 export type SearchItem = { id: string; title: string; description: string; cover_url: string }
-export type Chapter = { id: string; name: string; number: number; read: boolean | null }
-export type Format = "Normal" | "Long"
+export type Demographic = "Shounen" | "Shoujo" | "Seinen" | "Josei"
+export type SettingOption = { value: string; name: string }
+export type DownloadJob = { job_id: number; connector_idx: number; manga_id: string; chapter_id: string; status: JobStatus; page: number; total: number }
+/**
+ * Where a manga is on the user's list. Each tracker has its own names for
+ * these.
+ */
+export type TrackStatus = "Reading" | "Completed" | "OnHold" | "Dropped" | "PlanToRead" | "Rereading"
+export type SettingValue = { Bool: boolean } | { Enum: string } | { StringList: string[] } | { String: string }
+export type DownloadedManga = { manifest: MangaManifest; chapters: ChapterManifest[] }
+/**
+ * A connector's settings and their current values, for the settings page.
+ */
+export type ConnectorSettings = { fields: SettingField[]; values: { [key: string]: SettingValue } }
+export type SortOrder = "Relevance" | "LatestUpload" | "Popular" | "Rating" | "Title" | "NewlyAdded"
+/**
+ * One page of results from a paginated endpoint.
+ */
+export type Page<T> = { items: T[]; has_next: boolean; total: number | null }
+export type ChapterProgress = { page: number; total: number; updated_at: number; completed: boolean }
+export type HistoryEntry = { id: number; connector_idx: number; manga_id: string; chapter_id: string; page: number; opened_at: number; duration: number }
+export type CacheUsage = { bytes: number; images: number; max_bytes: number }
+export type ContentRating = "Safe" | "Suggestive" | "Erotica" | "Pornographic"
+export type Chapter = { id: string; name: string; number: number; volume: string | null; language: string | null; scanlation_group: string | null; read: boolean | null }
+export type ChapterImages = { images: string[]; format: Format }
+/**
+ * Where to pick a manga back up.
+ */
+export type ResumePoint = { chapter: Chapter; page: number }
+export type SyncReport = { followed: number; newly_liked: number; read_chapters: number }
+export type SettingField = { key: string; name: string; description: string; kind: SettingKind; default: SettingValue }
+export type ExportGrouping = "Chapter" | "Volume"
+export type ChapterUpdate = { connector_idx: number; manga_id: string; manga_title: string; cover_url: string; chapter: Chapter; found_at: number }
+export type TrackerItem = { id: string; title: string; cover_url: string; chapters: number | null }
+export type Tag = { id: string; name: string; group: string | null }
+export type TrackEntry = { status: TrackStatus; score: number | null; progress: number }
+/**
+ * The filters a connector understands, for building the filter UI.
+ */
+export type FilterCapabilities = { tags: Tag[]; max_tags: number | null; status: PublicationStatus[]; demographic: Demographic[]; content_rating: ContentRating[]; languages: string[]; sort: SortOrder[]; filters_with_query: boolean }
+export type JobStatus = "Queued" | "Downloading" | "Paused" | "Completed" | { Failed: string } | "Cancelled"
+export type MangaManifest = { connector_id: string; desc: SearchItem; cover: string | null }
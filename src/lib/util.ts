@@ -6,6 +6,33 @@ export function sleep(ms: number) {
     return new Promise(res => setTimeout(res, ms));
 }
 
+const IMAGE_RETRIES = 30;
+
+/**
+ * Images the backend hasn't cached yet fail while it loads them in the
+ * background, so ask for `bunni://` images again a little later. Returns a
+ * function that stops it.
+ */
+export function retryBackendImages() {
+    const tries = new WeakMap<HTMLImageElement, number>();
+    const onError = (e: Event) => {
+        const img = e.target;
+        if (!(img instanceof HTMLImageElement)) return;
+        const src = img.src;
+        if (!src.startsWith("bunni:") && !src.startsWith("https://bunni.localhost")) return;
+        const tried = tries.get(img) ?? 0;
+        if (tried >= IMAGE_RETRIES) return;
+        tries.set(img, tried + 1);
+        // setting the same src loads it again
+        setTimeout(() => {
+            if (img.src === src) img.src = src;
+        }, 1000);
+    };
+    // errors on images don't bubble, but can be caught on the way down
+    window.addEventListener("error", onError, true);
+    return () => window.removeEventListener("error", onError, true);
+}
+
 export function popIn(
     node: Element,
     { delay = 0, duration = 400, easing = linear }: TransitionConfig = {}